@@ -0,0 +1,47 @@
+use ast::*;
+
+/// Checks that every named allocation of the given function is freed in the same block and that
+/// frees match the most recent allocation. Returns a warning for every mismatch.
+///
+/// Unnamed allocations and frees only take part to keep track of the order.
+pub fn check(func: &Func) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    check_block(&func.expr, &mut warnings);
+
+    warnings
+        .into_iter()
+        .map(|warning| format!("in {}: {}", func.id, warning))
+        .collect()
+}
+
+fn check_block(exprs: &[Located], warnings: &mut Vec<String>) {
+    let mut live: Vec<&Option<String>> = Vec::new();
+
+    for Located { expr, .. } in exprs {
+        match expr {
+            Expr::Alloc(_, name) => live.push(name),
+            Expr::Free(name) => match (live.pop(), name) {
+                (None, Some(name)) => warnings.push(format!("{} freed but never allocated", name)),
+                (Some(Some(last)), Some(name)) if last != name => warnings.push(format!(
+                    "{} freed while {} is the most recent allocation",
+                    name, last
+                )),
+                _ => {}
+            },
+            Expr::While(whl) => check_block(&whl.exprs, warnings),
+            Expr::If(whether) => {
+                check_block(&whether.exprs, warnings);
+
+                if let Some(ref else_exprs) = whether.else_exprs {
+                    check_block(else_exprs, warnings);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for name in live.into_iter().flatten() {
+        warnings.push(format!("{} allocated but never freed", name));
+    }
+}
@@ -1,7 +1,10 @@
+use call_graph::{CallGraph, FuncRef, Reachability};
+use config::{FunctionOrder, MemoryLayout};
 use melon::{typedef::*, Instruction, IntegerType};
-use std::collections::BTreeMap;
+use std::{collections::{BTreeMap, BTreeSet, VecDeque},
+          path::PathBuf};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum Expr {
     ActualInstr(Instruction),
 
@@ -12,22 +15,93 @@ pub enum Expr {
 
     Load(IntegerType, Argument<Address>),
     Store(IntegerType, Argument<Address>),
+    Tee(IntegerType, Argument<Address>),
+    TeeIndirect(IntegerType),
 
     Sys(String),
     Call(String),
-    Alloc(Argument<u16>),
+    /// Allocates the given amount of bytes, optionally naming the allocation for diagnostics
+    Alloc(Argument<u16>, Option<String>),
+    Free(Option<String>),
 
     While(While),
     If(If),
+    Assert(Condition, IntegerType),
+    /// Aborts the program, like a failed assertion
+    Trap,
+    /// Leaves the innermost loop or the one with the given label
+    Break(Option<String>),
+    /// Jumps back to the condition of the innermost loop or the one with the given label
+    Continue(Option<String>),
+    CountBits(BitCount, IntegerType),
+    /// Arithmetic whose result is clamped to the bounds of the type instead of overflowing
+    Saturating(Saturating, IntegerType),
+    /// Arithmetic without a type, which is inferred from the types of the operands once the
+    /// function is parsed
+    Untyped(Arithmetic),
+    WideMul(IntegerType),
+    /// Pops the `u16` length, the `u16` destination and the `u16` source address and copies the
+    /// bytes from the source to the destination
+    MemCopy,
+    /// Pops the `u16` length, the `u8` value and the `u16` destination address and sets the bytes
+    /// at the destination to the value
+    MemSet,
+    /// Constrains the value to the inclusive range between the minimum and the maximum
+    Clamp(IntegerType, Argument<i32>, Argument<i32>),
 }
 
-#[derive(Debug, Clone)]
+/// The bits counted by `popcount`, `clz` and `ctz`
+#[derive(Debug, Clone, Serialize)]
+pub enum BitCount {
+    Ones,
+    LeadingZeros,
+    TrailingZeros,
+}
+
+/// The arithmetic instructions whose type may be left out
+#[derive(Debug, Clone, Serialize)]
+pub enum Arithmetic {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Arithmetic {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Arithmetic::Add => "add",
+            Arithmetic::Sub => "sub",
+            Arithmetic::Mul => "mul",
+            Arithmetic::Div => "div",
+        }
+    }
+
+    pub fn instruction(&self, type_t: IntegerType) -> Instruction {
+        match self {
+            Arithmetic::Add => Instruction::Add(type_t),
+            Arithmetic::Sub => Instruction::Sub(type_t),
+            Arithmetic::Mul => Instruction::Mul(type_t),
+            Arithmetic::Div => Instruction::Div(type_t),
+        }
+    }
+}
+
+/// The operations of `sadd`, `ssub` and `smul`
+#[derive(Debug, Clone, Serialize)]
+pub enum Saturating {
+    Add,
+    Sub,
+    Mul,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub enum Argument<T> {
     Literal(T),
     Constant(String),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum Condition {
     Less,
     Greater,
@@ -37,56 +111,1217 @@ pub enum Condition {
     NotEqual,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct If {
     pub cond: Condition,
     pub type_t: IntegerType,
-    pub exprs: Vec<Expr>,
-    pub else_exprs: Option<Vec<Expr>>,
+    /// The value the top value is compared with instead of the value below it. The top value is
+    /// taken off the stack before either branch runs.
+    pub immediate: Option<Argument<i32>>,
+    pub exprs: Vec<Located>,
+    pub else_exprs: Option<Vec<Located>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct While {
+    /// The name `break` and `continue` refer to the loop by, including the leading `'`
+    pub label: Option<String>,
     pub cond: Condition,
     pub type_t: IntegerType,
-    pub exprs: Vec<Expr>,
+    /// The value the top value is compared with instead of the value below it. The top value is
+    /// taken off the stack before the body runs and after the loop, so the body has to push the
+    /// next value to compare.
+    pub immediate: Option<Argument<i32>>,
+    /// The number of iterations after which the loop is left even if the condition still holds
+    pub max: Option<Argument<u16>>,
+    pub exprs: Vec<Located>,
+}
+
+/// An expression with the source line it was parsed from
+#[derive(Debug, Clone, Serialize)]
+pub struct Located {
+    pub expr: Expr,
+    /// `None` for expressions that were added after parsing, e.g. by a pass
+    pub line: Option<usize>,
+}
+
+impl From<Expr> for Located {
+    fn from(expr: Expr) -> Located {
+        Located { expr, line: None }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Func {
     pub id: String,
-    pub expr: Vec<Expr>,
+    pub expr: Vec<Located>,
+    /// Whether calls to this function should be replaced by its body when optimizing
+    pub is_inline: bool,
+    /// Whether the function promises to have no side effects, including those of the functions it
+    /// calls. This is verified once every module is known.
+    pub is_pure: bool,
+    /// The constant that has to be nonzero for the function to be compiled
+    pub cfg: Option<String>,
+    /// The values the function expects on the stack, the last one on top. Only the entry function
+    /// may declare them.
+    pub params: Vec<Param>,
+    /// The power of two the address of the first instruction of the function is a multiple of
+    pub align: Option<u16>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Param {
+    pub id: String,
+    pub type_t: IntegerType,
+}
+
+impl Func {
+    /// Returns the number of expressions including those of loops and conditionals
+    pub fn size(&self) -> usize {
+        let mut size = 0;
+        walk_exprs(&self.expr, &mut |_| size += 1);
+
+        size
+    }
+
+    /// Returns the first expression with a side effect, i.e. one that writes to the memory or the
+    /// registers, manages allocations or makes a system call
+    pub fn side_effect(&self) -> Option<&Expr> {
+        let mut side_effect = None;
+
+        walk_exprs(&self.expr, &mut |expr| {
+            let has_side_effect = matches!(
+                expr,
+                Expr::Sys(_)
+                    | Expr::Store(..)
+                    | Expr::Tee(..)
+                    | Expr::TeeIndirect(_)
+                    | Expr::Alloc(..)
+                    | Expr::Free(_)
+                    | Expr::MemCopy
+                    | Expr::MemSet
+                    | Expr::ActualInstr(Instruction::Store(..))
+                    | Expr::ActualInstr(Instruction::StoreIndirect(_))
+                    | Expr::ActualInstr(Instruction::Alloc(_))
+                    | Expr::ActualInstr(Instruction::Free)
+                    | Expr::ActualInstr(Instruction::SysCall(_))
+            );
+
+            if has_side_effect && side_effect.is_none() {
+                side_effect = Some(expr);
+            }
+        });
+
+        side_effect
+    }
+
+    /// Iterates over every expression including loops and conditionals and the expressions inside
+    /// of them in the order they appear, each with the number of loops and conditionals around it
+    pub fn walk(&self) -> impl Iterator<Item = (usize, &Expr)> {
+        let mut exprs = Vec::new();
+        walk_nested_exprs(&self.expr, 0, &mut |depth, expr| exprs.push((depth, expr)));
+
+        exprs.into_iter()
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Const {
     pub id: String,
     pub value: i32,
 }
 
-#[derive(Debug, Clone)]
+/// An expression over constants, evaluated at compile time
+#[derive(Debug, Clone, Serialize)]
+pub enum ConstExpr {
+    Literal(i32),
+    Constant(String),
+    /// The operator as written, like `+` or `<=`, and its operands
+    Op(String, Box<ConstExpr>, Box<ConstExpr>),
+}
+
+impl ConstExpr {
+    /// Evaluates the expression with the given constants. Comparisons are 1 if they hold and 0
+    /// otherwise.
+    pub fn eval(&self, constants: &[Const]) -> Result<i64> {
+        let (op, lhs, rhs) = match self {
+            ConstExpr::Literal(value) => return Ok(i64::from(*value)),
+            ConstExpr::Constant(id) => {
+                return match constants.iter().find(|constant| constant.id == *id) {
+                    Some(constant) => Ok(i64::from(constant.value)),
+                    None => bail!("unable to find constant {:?}", id),
+                };
+            }
+            ConstExpr::Op(op, lhs, rhs) => (op, lhs.eval(constants)?, rhs.eval(constants)?),
+        };
+
+        let value = match op.as_str() {
+            "==" => Some(i64::from(lhs == rhs)),
+            "!=" => Some(i64::from(lhs != rhs)),
+            "<" => Some(i64::from(lhs < rhs)),
+            ">" => Some(i64::from(lhs > rhs)),
+            "<=" => Some(i64::from(lhs <= rhs)),
+            ">=" => Some(i64::from(lhs >= rhs)),
+            "+" => lhs.checked_add(rhs),
+            "-" => lhs.checked_sub(rhs),
+            "*" => lhs.checked_mul(rhs),
+            "/" => lhs.checked_div(rhs),
+            "%" => lhs.checked_rem(rhs),
+            "&" => Some(lhs & rhs),
+            "|" => Some(lhs | rhs),
+            "^" => Some(lhs ^ rhs),
+            "<<" => lhs.checked_shl(rhs as u32).filter(|_| (0..32).contains(&rhs)),
+            ">>" => lhs.checked_shr(rhs as u32).filter(|_| (0..32).contains(&rhs)),
+            _ => bail!("unknown operator {:?}", op),
+        };
+
+        match value {
+            Some(value) => Ok(value),
+            None => bail!("unable to evaluate ({} {} {})", op, lhs, rhs),
+        }
+    }
+
+    /// Returns the ids of the constants used by the expression
+    pub fn constants(&self) -> BTreeSet<&str> {
+        match self {
+            ConstExpr::Literal(_) => BTreeSet::new(),
+            ConstExpr::Constant(id) => ::std::iter::once(id.as_str()).collect(),
+            ConstExpr::Op(_, lhs, rhs) => {
+                lhs.constants().union(&rhs.constants()).cloned().collect()
+            }
+        }
+    }
+}
+
+/// A condition on constants that has to hold for the module to compile
+#[derive(Debug, Clone, Serialize)]
+pub struct StaticAssert {
+    /// The condition as written
+    pub source: String,
+    pub cond: ConstExpr,
+}
+
+/// A range of memory a module requires for itself
+#[derive(Debug, Clone, Serialize)]
+pub struct Region {
+    pub id: String,
+    pub start: u16,
+    pub size: u16,
+}
+
+impl Region {
+    /// The address after the last byte of the region
+    pub fn end(&self) -> u32 {
+        u32::from(self.start) + u32::from(self.size)
+    }
+
+    pub fn overlaps(&self, other: &Region) -> bool {
+        u32::from(self.start) < other.end() && u32::from(other.start) < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Import {
     pub func_origin_id: String,
     pub func_alias_id: String,
     pub module_id: String,
 }
 
-#[derive(Debug, Clone)]
+/// What a module exports to the modules importing it
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicApi {
+    pub funcs: Vec<Export>,
+    /// The exported constants with their values
+    pub constants: Vec<Const>,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Export {
     pub func_origin_id: String,
     pub func_alias_id: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Module {
     pub id: String,
     pub imports: Vec<Import>,
     pub exports: Vec<Export>,
     pub constants: Vec<Const>,
+    pub regions: Vec<Region>,
     pub funcs: Vec<Func>,
+    /// The modules imported as a whole, by their alias
+    pub module_aliases: BTreeMap<String, String>,
+    /// The modules whose exported constants are all imported
+    pub const_imports: Vec<String>,
+    /// The ids of the constants other modules may import
+    pub const_exports: Vec<String>,
+    pub static_asserts: Vec<StaticAssert>,
+    /// Problems found while parsing the module that don't prevent it from being compiled
+    pub warnings: Vec<String>,
+    /// The file the module was read from, `None` for modules that were parsed from a string
+    pub path: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone)]
+impl Module {
+    /// Creates an empty module with the given id
+    pub fn new(id: String) -> Module {
+        Module {
+            id,
+            imports: Vec::new(),
+            exports: Vec::new(),
+            constants: Vec::new(),
+            regions: Vec::new(),
+            funcs: Vec::new(),
+            module_aliases: BTreeMap::new(),
+            const_imports: Vec::new(),
+            const_exports: Vec::new(),
+            static_asserts: Vec::new(),
+            warnings: Vec::new(),
+            path: None,
+        }
+    }
+
+    /// Finds the import a function id refers to. Ids qualified with a module alias like
+    /// `alias.$func` refer to the export `$func` of the aliased module.
+    pub fn import_of(&self, func_id: &str) -> Option<Import> {
+        if let Some(dot) = func_id.find('.') {
+            let module_id = self.module_aliases.get(&func_id[..dot])?;
+
+            return Some(Import {
+                func_origin_id: func_id[dot + 1..].into(),
+                func_alias_id: func_id.into(),
+                module_id: module_id.clone(),
+            });
+        }
+
+        self.imports
+            .iter()
+            .find(|import| import.func_alias_id == func_id)
+            .cloned()
+    }
+
+    /// Returns a hash of the semantic content of the module, i.e. its imports, exports,
+    /// constants, memory regions and functions. Formatting, comments and warnings don't affect
+    /// it, and it is the same across builds and platforms.
+    pub fn fingerprint(&self) -> u64 {
+        // Source lines only depend on the formatting
+        let funcs: Vec<_> = self.funcs
+            .iter()
+            .map(|func| {
+                let mut exprs = func.expr.clone();
+                clear_lines(&mut exprs);
+
+                (&func.id, &func.params, exprs, func.is_inline, func.is_pure, func.align)
+            })
+            .collect();
+
+        let canonical = format!(
+            "{:?}",
+            (
+                &self.id,
+                &self.imports,
+                &self.module_aliases,
+                &self.const_imports,
+                &self.exports,
+                &self.const_exports,
+                &self.constants,
+                &self.static_asserts,
+                &self.regions,
+                funcs,
+            )
+        );
+
+        // 64 bit FNV-1a
+        canonical.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    /// Returns the ids of all modules this module depends on
+    pub fn dependencies(&self) -> BTreeSet<String> {
+        self.imports
+            .iter()
+            .map(|import| import.module_id.clone())
+            .chain(self.module_aliases.values().cloned())
+            .chain(self.const_imports.iter().cloned())
+            .collect()
+    }
+
+    /// Renders every expression of every function with its source line and position in the
+    /// function, counting nested expressions in the order they appear
+    pub fn listing(&self) -> String {
+        let mut listing = String::new();
+
+        for func in &self.funcs {
+            let mut index = 0;
+
+            walk_located(&func.expr, 0, &mut |_, located| {
+                let line = match located.line {
+                    Some(line) => line.to_string(),
+                    None => "?".into(),
+                };
+
+                let instr = match &located.expr {
+                    Expr::While(whl) => format!("While({:?}, {:?})", whl.cond, whl.type_t),
+                    Expr::If(whether) => format!("If({:?}, {:?})", whether.cond, whether.type_t),
+                    expr => format!("{:?}", expr),
+                };
+
+                listing.push_str(&format!("{}:{}: {} {}\n", func.id, line, index, instr));
+                index += 1;
+            });
+        }
+
+        listing
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Ast {
     pub modules: BTreeMap<String, Module>,
+    /// The memory layout of the program the AST is generated for
+    pub memory: MemoryLayout,
+}
+
+impl Ast {
+    /// Adds the exported constants of the modules each module imports all constants from to the
+    /// constants of the module. Constants defined by the module itself take precedence, but the
+    /// same constant imported from two modules is an error.
+    pub fn resolve_const_imports(&mut self) -> Result<()> {
+        let mut imported = BTreeMap::new();
+
+        for module in self.modules.values() {
+            let mut constants: BTreeMap<&str, (&str, &Const)> = BTreeMap::new();
+
+            for module_id in &module.const_imports {
+                let exporter = match self.modules.get(module_id) {
+                    Some(exporter) => exporter,
+                    None => bail!("unable to find module {:?}", module_id),
+                };
+
+                for constant in &exporter.constants {
+                    if !exporter.const_exports.contains(&constant.id) {
+                        continue;
+                    }
+
+                    if let Some((other_id, _)) = constants.get(constant.id.as_str()) {
+                        bail!(
+                            "module {:?} imports the constant {} from both {:?} and {:?}",
+                            module.id,
+                            constant.id,
+                            other_id,
+                            module_id
+                        );
+                    }
+
+                    constants.insert(&constant.id, (module_id, constant));
+                }
+            }
+
+            let constants: Vec<_> = constants
+                .values()
+                .filter(|(_, constant)| {
+                    module.constants.iter().all(|local| local.id != constant.id)
+                })
+                .map(|(_, constant)| (*constant).clone())
+                .collect();
+
+            imported.insert(module.id.clone(), constants);
+        }
+
+        for (module_id, mut constants) in imported {
+            if let Some(module) = self.modules.get_mut(&module_id) {
+                module.constants.append(&mut constants);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reorders the functions of every module
+    pub fn order_funcs(&mut self, order: FunctionOrder) {
+        let call_graph = CallGraph::new(self);
+
+        for module in self.modules.values_mut() {
+            match order {
+                FunctionOrder::Source => {}
+                FunctionOrder::Name => module.funcs.sort_by(|a, b| a.id.cmp(&b.id)),
+                FunctionOrder::Callgraph => {
+                    let order = {
+                        let func_ids: Vec<_> =
+                            module.funcs.iter().map(|func| func.id.as_str()).collect();
+                        call_graph.order(&module.id, &func_ids)
+                    };
+
+                    module
+                        .funcs
+                        .sort_by_key(|func| order.iter().position(|id| *id == func.id));
+                }
+            }
+        }
+    }
+
+    /// Returns the warnings of every module, prefixed with the id of the module
+    pub fn warnings(&self) -> Vec<String> {
+        self.modules
+            .values()
+            .flat_map(|module| {
+                module
+                    .warnings
+                    .iter()
+                    .map(move |warning| format!("module {:?}: {}", module.id, warning))
+            })
+            .collect()
+    }
+
+    /// Returns the exported functions and constants of every module
+    pub fn public_api(&self) -> BTreeMap<String, PublicApi> {
+        self.modules
+            .iter()
+            .map(|(id, module)| {
+                let constants = module
+                    .constants
+                    .iter()
+                    .filter(|constant| module.const_exports.contains(&constant.id))
+                    .cloned()
+                    .collect();
+
+                let api = PublicApi {
+                    funcs: module.exports.clone(),
+                    constants,
+                };
+
+                (id.clone(), api)
+            })
+            .collect()
+    }
+
+    /// Returns the modules every module imports functions or constants from
+    pub fn dependency_graph(&self) -> BTreeMap<String, Vec<String>> {
+        self.modules
+            .iter()
+            .map(|(id, module)| (id.clone(), module.dependencies().into_iter().collect()))
+            .collect()
+    }
+
+    /// Evaluates the static assertions of every module, failing with the values involved in the
+    /// first one that doesn't hold
+    pub fn check_static_asserts(&self) -> Result<()> {
+        for module in self.modules.values() {
+            for assertion in &module.static_asserts {
+                let cond = &assertion.cond;
+                let value = cond.eval(&module.constants).map_err(|err| {
+                    format_err!(
+                        "static assertion {} of module {:?}: {}",
+                        assertion.source,
+                        module.id,
+                        err
+                    )
+                })?;
+
+                if value != 0 {
+                    continue;
+                }
+
+                // Shows the compared values, for comparisons
+                let outcome = match cond {
+                    ConstExpr::Op(op, lhs, rhs) => format!(
+                        "{} {} {} doesn't hold",
+                        lhs.eval(&module.constants)?,
+                        op,
+                        rhs.eval(&module.constants)?
+                    ),
+                    _ => "the value is 0".into(),
+                };
+
+                let values: Vec<_> = cond
+                    .constants()
+                    .into_iter()
+                    .filter_map(|id| module.constants.iter().find(|constant| constant.id == id))
+                    .map(|constant| format!("{} = {}", constant.id, constant.value))
+                    .collect();
+
+                let values = if values.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", values.join(", "))
+                };
+
+                bail!(
+                    "static assertion {} of module {:?} failed: {}{}",
+                    assertion.source,
+                    module.id,
+                    outcome,
+                    values
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the functions marked as pure and every function they call have no side effects
+    pub fn check_purity(&self) -> Result<()> {
+        let call_graph = CallGraph::new(self);
+
+        for module in self.modules.values() {
+            for func in module.funcs.iter().filter(|func| func.is_pure) {
+                let entry = (module.id.clone(), func.id.clone());
+
+                for (callee_module, callee_id) in call_graph.reachable_from(&entry) {
+                    let callee = self.modules[&callee_module]
+                        .funcs
+                        .iter()
+                        .find(|callee| callee.id == callee_id);
+
+                    if let Some(expr) = callee.and_then(Func::side_effect) {
+                        let culprit = if (&callee_module, &callee_id) == (&module.id, &func.id) {
+                            "it".to_string()
+                        } else {
+                            format!("{} of module {:?}, which it calls,", callee_id, callee_module)
+                        };
+
+                        bail!(
+                            "{} of module {:?} is marked as pure, but {} has the side effect {:?}",
+                            func.id,
+                            module.id,
+                            culprit,
+                            expr
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every imported function is exported by its module. Modules that weren't parsed
+    /// because none of their functions are reachable are skipped.
+    pub fn check_imports(&self) -> Result<()> {
+        for module in self.modules.values() {
+            for import in &module.imports {
+                let exporter = match self.modules.get(&import.module_id) {
+                    Some(exporter) => exporter,
+                    None => continue,
+                };
+
+                let exports: Vec<_> = exporter
+                    .exports
+                    .iter()
+                    .map(|export| export.func_alias_id.as_str())
+                    .collect();
+
+                ensure!(
+                    exports.contains(&import.func_origin_id.as_str()),
+                    "module {:?} imports {} from module {:?}, which only exports [{}]",
+                    module.id,
+                    import.func_origin_id,
+                    exporter.id,
+                    exports.join(", ")
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that no two memory regions declared anywhere in the project overlap
+    pub fn check_regions(&self) -> Result<()> {
+        let regions: Vec<_> = self.modules
+            .values()
+            .flat_map(|module| module.regions.iter().map(move |region| (&module.id, region)))
+            .collect();
+
+        for (index, (module, region)) in regions.iter().enumerate() {
+            for (other_module, other) in &regions[index + 1..] {
+                ensure!(
+                    !region.overlaps(other),
+                    "memory region {} of module {:?} ({:#06x}..{:#06x}) overlaps memory region {} \
+                     of module {:?} ({:#06x}..{:#06x})",
+                    region.id,
+                    module,
+                    region.start,
+                    region.end(),
+                    other.id,
+                    other_module,
+                    other.start,
+                    other.end()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Warns about constants whose value doesn't fit the narrowest type they're used as, e.g. a
+    /// constant of 1000 that is pushed as `u8`. Only the narrowest use of each constant is
+    /// reported.
+    pub fn check_constant_widths(&mut self) {
+        for module in self.modules.values_mut() {
+            let mut warnings = Vec::new();
+
+            for constant in &module.constants {
+                let mut narrowest: Option<(&str, ConstantUse)> = None;
+
+                for func in &module.funcs {
+                    for usage in constant_uses(&func.expr, &constant.id) {
+                        let is_narrower = match narrowest {
+                            Some((_, ref other)) => usage.range() < other.range(),
+                            None => true,
+                        };
+
+                        if is_narrower {
+                            narrowest = Some((&func.id, usage));
+                        }
+                    }
+                }
+
+                if let Some((func_id, usage)) = narrowest {
+                    let value = i64::from(constant.value);
+
+                    if value < usage.min || value > usage.max {
+                        warnings.push(format!(
+                            "in {}: the constant {} is {}, which doesn't fit the {} of its use as \
+                             {}",
+                            func_id, constant.id, constant.value, usage.type_name, usage.kind
+                        ));
+                    }
+                }
+            }
+
+            module.warnings.append(&mut warnings);
+        }
+    }
+
+    /// Warns about modules that import each other, directly or through other modules. Each group
+    /// of such modules is reported once, by the shortest cycle through its first module.
+    pub fn check_import_cycles(&mut self) {
+        let mut reported = BTreeSet::new();
+        let mut cycles = Vec::new();
+
+        for module_id in self.modules.keys() {
+            if reported.contains(module_id) {
+                continue;
+            }
+
+            if let Some(cycle) = self.import_cycle(module_id) {
+                // Every module reachable from this one and leading back to it shares the cycle
+                let reachable = self.imported_modules(module_id);
+                reported.extend(
+                    reachable
+                        .into_iter()
+                        .filter(|other| self.imported_modules(other).contains(module_id)),
+                );
+
+                cycles.push((module_id.clone(), cycle));
+            }
+        }
+
+        for (module_id, cycle) in cycles {
+            if let Some(module) = self.modules.get_mut(&module_id) {
+                module.warnings.push(format!(
+                    "the imports of the modules form a cycle: {}",
+                    cycle.join(" -> ")
+                ));
+            }
+        }
+    }
+
+    /// Returns the modules imported by the given module, directly or through other modules
+    fn imported_modules(&self, module_id: &str) -> BTreeSet<String> {
+        let mut imported = BTreeSet::new();
+        let mut stack = vec![module_id.to_string()];
+
+        while let Some(current) = stack.pop() {
+            if let Some(module) = self.modules.get(&current) {
+                for dependency in module.dependencies() {
+                    if imported.insert(dependency.clone()) {
+                        stack.push(dependency);
+                    }
+                }
+            }
+        }
+
+        imported
+    }
+
+    /// Finds the shortest chain of imports leading from the given module back to it
+    fn import_cycle(&self, module_id: &str) -> Option<Vec<String>> {
+        let mut previous: BTreeMap<String, String> = BTreeMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(module_id.to_string());
+
+        while let Some(current) = queue.pop_front() {
+            let dependencies = match self.modules.get(&current) {
+                Some(module) => module.dependencies(),
+                None => continue,
+            };
+
+            for dependency in dependencies {
+                if dependency == module_id {
+                    let mut cycle = vec![dependency, current.clone()];
+                    while let Some(before) = previous.get(cycle.last().unwrap()) {
+                        cycle.push(before.clone());
+                    }
+                    cycle.reverse();
+
+                    return Some(cycle);
+                }
+
+                if !previous.contains_key(&dependency) {
+                    previous.insert(dependency.clone(), current.clone());
+                    queue.push_back(dependency);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Determines which functions can be reached from the given entry function or from the
+    /// exported functions, which are part of the API of their modules
+    pub fn reachability(&self, entry: &FuncRef) -> Reachability {
+        let call_graph = CallGraph::new(self);
+
+        let exports = self.modules.values().flat_map(|module| {
+            module
+                .exports
+                .iter()
+                .map(move |exp| (module.id.clone(), exp.func_origin_id.clone()))
+        });
+
+        let mut reachable = BTreeSet::new();
+        for root in ::std::iter::once(entry.clone()).chain(exports) {
+            reachable.extend(call_graph.reachable_from(&root));
+        }
+
+        let mut unreachable = BTreeMap::new();
+
+        for module in self.modules.values() {
+            let funcs: Vec<_> = module
+                .funcs
+                .iter()
+                .filter(|func| !reachable.contains(&(module.id.clone(), func.id.clone())))
+                .map(|func| func.id.clone())
+                .collect();
+
+            if !funcs.is_empty() {
+                unreachable.insert(module.id.clone(), funcs);
+            }
+        }
+
+        Reachability {
+            reachable,
+            unreachable,
+        }
+    }
+
+    /// Renders the listing of every module
+    pub fn listing(&self) -> String {
+        self.modules
+            .values()
+            .map(|module| format!("module {}\n{}", module.id, module.listing()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Finds the expressions matching the given predicate in every function, including the bodies
+    /// of loops and conditionals. Each match comes with the module and function it's in and its
+    /// position in the function, counting nested expressions in the order they appear.
+    pub fn instructions_of<F: Fn(&Expr) -> bool>(
+        &self,
+        predicate: F,
+    ) -> Vec<(String, String, usize, &Expr)> {
+        let mut found = Vec::new();
+
+        for module in self.modules.values() {
+            for func in &module.funcs {
+                let mut index = 0;
+
+                walk_exprs(&func.expr, &mut |expr| {
+                    if predicate(expr) {
+                        found.push((module.id.clone(), func.id.clone(), index, expr));
+                    }
+
+                    index += 1;
+                });
+            }
+        }
+
+        found
+    }
+
+    /// Returns the modules that functions reachable from the given entry function import functions
+    /// from, but which aren't part of the AST yet, each with a module importing it
+    pub fn missing_imports(&self, entry: &FuncRef) -> BTreeMap<String, String> {
+        let mut missing = BTreeMap::new();
+
+        for (module_id, func_id) in CallGraph::new(self).reachable_from(entry) {
+            let module = match self.modules.get(&module_id) {
+                Some(module) => module,
+                None => continue,
+            };
+
+            for func in module.funcs.iter().filter(|func| func.id == func_id) {
+                walk_exprs(&func.expr, &mut |expr| {
+                    if let Expr::Call(ref callee) = expr {
+                        if let Some(import) = module.import_of(callee) {
+                            if !self.modules.contains_key(&import.module_id) {
+                                missing
+                                    .entry(import.module_id)
+                                    .or_insert_with(|| module_id.clone());
+                            }
+                        }
+                    }
+                });
+            }
+        }
+
+        missing
+    }
+
+    /// Resolves a call from inside the given module to the id of the module and the local id of
+    /// the function that is being called
+    pub fn resolve_call(&self, module: &Module, func_id: &str) -> Option<(String, String)> {
+        if let Some(import) = module.import_of(func_id) {
+            let origin_module = self.modules.get(&import.module_id)?;
+
+            let export = origin_module
+                .exports
+                .iter()
+                .find(|exp| exp.func_alias_id == import.func_origin_id)?;
+
+            return Some((import.module_id.clone(), export.func_origin_id.clone()));
+        }
+
+        if module.funcs.iter().any(|func| func.id == func_id) {
+            return Some((module.id.clone(), func_id.into()));
+        }
+
+        None
+    }
+}
+
+/// A place a constant is used at, with the values that fit there
+struct ConstantUse {
+    kind: &'static str,
+    type_name: &'static str,
+    min: i64,
+    max: i64,
+}
+
+impl ConstantUse {
+    fn new(kind: &'static str, type_t: &IntegerType) -> ConstantUse {
+        let (type_name, min, max) = match type_t {
+            IntegerType::U8 => ("u8", 0, i64::from(u8::MAX)),
+            IntegerType::U16 => ("u16", 0, i64::from(u16::MAX)),
+            IntegerType::I8 => ("i8", i64::from(i8::MIN), i64::from(i8::MAX)),
+            IntegerType::I16 => ("i16", i64::from(i16::MIN), i64::from(i16::MAX)),
+        };
+
+        ConstantUse {
+            kind,
+            type_name,
+            min,
+            max,
+        }
+    }
+
+    fn range(&self) -> i64 {
+        self.max - self.min
+    }
+}
+
+/// Finds the uses of the given constant in the expressions
+fn constant_uses(exprs: &[Located], const_id: &str) -> Vec<ConstantUse> {
+    fn refers_to<T>(arg: &Argument<T>, const_id: &str) -> bool {
+        match arg {
+            Argument::Constant(id) => id == const_id,
+            Argument::Literal(_) => false,
+        }
+    }
+
+    let mut uses = Vec::new();
+
+    walk_exprs(exprs, &mut |expr| {
+        let mut found = |kind, type_t, arg_refers| {
+            if arg_refers {
+                uses.push(ConstantUse::new(kind, type_t));
+            }
+        };
+
+        match expr {
+            Expr::PushConstU8(arg) => found("push", &IntegerType::U8, refers_to(arg, const_id)),
+            Expr::PushConstU16(arg) => found("push", &IntegerType::U16, refers_to(arg, const_id)),
+            Expr::PushConstI8(arg) => found("push", &IntegerType::I8, refers_to(arg, const_id)),
+            Expr::PushConstI16(arg) => found("push", &IntegerType::I16, refers_to(arg, const_id)),
+            Expr::Load(_, arg) | Expr::Store(_, arg) | Expr::Tee(_, arg) => {
+                found("address", &IntegerType::U16, refers_to(arg, const_id))
+            }
+            Expr::Alloc(arg, _) => {
+                found("allocation size", &IntegerType::U16, refers_to(arg, const_id))
+            }
+            Expr::Clamp(type_t, min, max) => found(
+                "clamp bound",
+                type_t,
+                refers_to(min, const_id) || refers_to(max, const_id),
+            ),
+            Expr::If(whether) => {
+                if let Some(ref immediate) = whether.immediate {
+                    found("comparison", &whether.type_t, refers_to(immediate, const_id));
+                }
+            }
+            Expr::While(whl) => {
+                if let Some(ref immediate) = whl.immediate {
+                    found("comparison", &whl.type_t, refers_to(immediate, const_id));
+                }
+                if let Some(ref max) = whl.max {
+                    found("loop maximum", &IntegerType::U16, refers_to(max, const_id));
+                }
+            }
+            _ => {}
+        }
+    });
+
+    uses
+}
+
+/// Visits the given expressions and the bodies of loops and conditionals in the order they appear
+fn walk_exprs<'a>(exprs: &'a [Located], visit: &mut dyn FnMut(&'a Expr)) {
+    walk_located(exprs, 0, &mut |_, located| visit(&located.expr));
+}
+
+/// Like `walk_exprs`, also passing the number of loops and conditionals around each expression
+fn walk_nested_exprs<'a>(
+    exprs: &'a [Located],
+    depth: usize,
+    visit: &mut dyn FnMut(usize, &'a Expr),
+) {
+    walk_located(exprs, depth, &mut |depth, located| visit(depth, &located.expr));
+}
+
+/// Like `walk_nested_exprs`, passing the expressions with their source lines
+fn walk_located<'a>(exprs: &'a [Located], depth: usize, visit: &mut dyn FnMut(usize, &'a Located)) {
+    for located in exprs {
+        visit(depth, located);
+
+        match located.expr {
+            Expr::While(ref whl) => walk_located(&whl.exprs, depth + 1, visit),
+            Expr::If(ref whether) => {
+                walk_located(&whether.exprs, depth + 1, visit);
+
+                if let Some(ref else_exprs) = whether.else_exprs {
+                    walk_located(else_exprs, depth + 1, visit);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Removes the source lines of the expressions and those nested in them
+fn clear_lines(exprs: &mut [Located]) {
+    for located in exprs {
+        located.line = None;
+
+        match located.expr {
+            Expr::While(ref mut whl) => clear_lines(&mut whl.exprs),
+            Expr::If(ref mut whether) => {
+                clear_lines(&mut whether.exprs);
+
+                if let Some(ref mut else_exprs) = whether.else_exprs {
+                    clear_lines(else_exprs);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast_gen::AstGen;
+    use test_utils::{fixture_config, test_config};
+
+    #[test]
+    fn instructions_of_finds_nested_system_calls() {
+        let ast = AstGen::gen("main".into(), fixture_config("audit")).unwrap();
+
+        let sys_calls: Vec<_> = ast.instructions_of(|expr| matches!(expr, Expr::Sys(_)))
+            .into_iter()
+            .map(|(module, func, index, _)| (module, func, index))
+            .collect();
+
+        assert_eq!(
+            sys_calls,
+            vec![
+                ("main".to_string(), "$fire".to_string(), 3),
+                ("main".to_string(), "$main".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_yields_nested_expressions_with_their_depth() {
+        let source = "
+            (func $count
+              (push u8 0 3)
+              (while (< u8)
+                (if (== u8)
+                  (call $report)
+                  (sys :gurgle))
+                (inc u8))
+              (drop u8))";
+
+        let module = AstGen::compile_reader(source.as_bytes(), "main", test_config()).unwrap();
+
+        let walked: Vec<_> = module.funcs[0]
+            .walk()
+            .map(|(depth, expr)| match expr {
+                Expr::While(_) => (depth, "While".to_string()),
+                Expr::If(_) => (depth, "If".to_string()),
+                expr => (depth, format!("{:?}", expr)),
+            })
+            .collect();
+
+        assert_eq!(
+            walked,
+            vec![
+                (0, "PushConstU8(Literal(0))".to_string()),
+                (0, "PushConstU8(Literal(3))".to_string()),
+                (0, "While".to_string()),
+                (1, "If".to_string()),
+                (2, "Call(\"$report\")".to_string()),
+                (2, "Sys(\":gurgle\")".to_string()),
+                (1, "ActualInstr(Inc(U8))".to_string()),
+                (0, "ActualInstr(Drop(U8))".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn public_api_lists_exports() {
+        let ast = AstGen::gen("main".into(), fixture_config("public_api")).unwrap();
+
+        let api = ast.public_api();
+        assert!(api["main"].funcs.is_empty());
+        assert!(api["main"].constants.is_empty());
+
+        let exports: Vec<_> = api["api"]
+            .funcs
+            .iter()
+            .map(|exp| (exp.func_origin_id.as_str(), exp.func_alias_id.as_str()))
+            .collect();
+        assert_eq!(exports, vec![("$sqrt", "$sqrt"), ("$cube", "$pow3")]);
+
+        let constants: Vec<_> = api["api"]
+            .constants
+            .iter()
+            .map(|constant| (constant.id.as_str(), constant.value))
+            .collect();
+        assert_eq!(constants, vec![("%LIMIT", 8)]);
+    }
+
+    #[test]
+    fn dependency_graph_lists_the_imported_modules() {
+        let ast = AstGen::gen("main".into(), fixture_config("bundle")).unwrap();
+
+        let graph = ast.dependency_graph();
+        assert_eq!(graph.keys().collect::<Vec<_>>(), vec!["main", "math", "util"]);
+        assert_eq!(graph["main"], vec!["math", "util"]);
+        assert!(graph["math"].is_empty());
+        assert_eq!(graph["util"], vec!["math"]);
+    }
+
+    #[test]
+    fn listing_refers_to_source_lines() {
+        let ast = AstGen::gen("main".into(), fixture_config("listing")).unwrap();
+
+        let expected = "module main
+$main:4: 0 PushConstU8(Literal(7))
+$main:4: 1 PushConstU8(Literal(5))
+$main:5: 2 If(Greater, U8)
+$main:6: 3 PushConstU8(Literal(1))
+$main:7: 4 Sys(\":gurgle\")
+$main:8: 5 If(Equal, U8)
+$main:9: 6 PushConstU8(Literal(2))
+$main:11: 7 PushConstU8(Literal(3))
+$main:12: 8 While(Less, U8)
+$main:13: 9 ActualInstr(Drop(U8))
+$main:14: 10 ActualInstr(Drop(U8))
+";
+
+        assert_eq!(ast.listing(), expected);
+    }
+
+    #[test]
+    fn listing_keeps_the_lines_of_shifted_expressions() {
+        let mut ast = AstGen::gen("main".into(), fixture_config("listing")).unwrap();
+
+        let func = &mut ast.modules.get_mut("main").unwrap().funcs[0];
+        func.expr.insert(0, Expr::Sys(":gurgle".into()).into());
+
+        let listing = ast.modules["main"].listing();
+        let lines: Vec<_> = listing.lines().take(3).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "$main:?: 0 Sys(\":gurgle\")",
+                "$main:4: 1 PushConstU8(Literal(7))",
+                "$main:4: 2 PushConstU8(Literal(5))",
+            ]
+        );
+    }
+
+    #[test]
+    fn reachability_lists_unreachable_helpers() {
+        let ast = AstGen::gen("main".into(), fixture_config("reachability")).unwrap();
+
+        let reachability = ast.reachability(&("main".into(), "$main".into()));
+
+        assert!(
+            reachability
+                .reachable
+                .contains(&("helpers".into(), "$used".into()))
+        );
+        // Only called by the exported $api
+        assert!(
+            reachability
+                .reachable
+                .contains(&("helpers".into(), "$private".into()))
+        );
+        assert_eq!(reachability.unreachable.len(), 1);
+        assert_eq!(reachability.unreachable["helpers"], vec!["$unused"]);
+    }
+
+    #[test]
+    fn fingerprints_ignore_formatting() {
+        let compact = "(const %X 1)(func $f (push u8 %X) (drop u8))";
+        let spaced = ";; the same module\n(const %X 0x1)\n\n(func $f\n  (push u8 %X)\n  (drop u8))\n";
+        let changed = "(const %X 2)(func $f (push u8 %X) (drop u8))";
+
+        let fingerprint = |source: &str| {
+            AstGen::compile_reader(source.as_bytes(), "main", test_config())
+                .unwrap()
+                .fingerprint()
+        };
+
+        assert_eq!(fingerprint(compact), fingerprint(spaced));
+        assert_ne!(fingerprint(compact), fingerprint(changed));
+    }
+
+    #[test]
+    fn fingerprints_include_parameter_types() {
+        let fingerprint = |type_t: &str| {
+            let source = format!("(func $main (param $argc {}) (push u8 1) (drop u8))", type_t);
+
+            AstGen::compile_reader(source.as_bytes(), "main", test_config())
+                .unwrap()
+                .fingerprint()
+        };
+
+        assert_ne!(fingerprint("u8"), fingerprint("u16"));
+    }
 }
@@ -1,24 +1,298 @@
+use alloc_balance;
 use ast::*;
-use config::Config;
+use call_graph::{CallGraph, FuncRef};
+use config::{Compilation, Config, Memory, MemoryLayout, Program, Signal};
+use cst::TokenStream;
 use defaults;
-use failure::ResultExt;
+use diagnostic::Diagnostic;
+use failure::{Error, Fail, ResultExt};
+use literal;
+use loop_labels;
+use lowering;
 use melon::{typedef::*, Instruction, IntegerType, Register};
 use parser::{BeastParser, Rule};
-use pest::{iterators::Pair, Parser};
-use std::{collections::{BTreeMap, BTreeSet},
+use pest::{self,
+           iterators::{Pair, Pairs},
+           Parser};
+use profile::{CompileReport, Profiler, Timer};
+use source_provider::SourceProvider;
+use std::{collections::{BTreeMap, BTreeSet, VecDeque},
+          fmt,
           fs::File,
-          io::Read,
-          path::PathBuf,
-          sync::mpsc::{self, TryRecvError},
-          thread};
+          io::{self, Read},
+          panic::{self, AssertUnwindSafe},
+          fs,
+          path::{Path, PathBuf},
+          sync::{mpsc, Arc, Mutex},
+          thread,
+          time::Instant};
+use type_check;
 
 const SOURCE_FILE_EXTENSIONS: [&str; 2] = ["beast", "bst"];
 
+/// The keywords of every instruction and block that can be used inside of functions
+#[rustfmt::skip]
+pub const INSTRUCTIONS: &[&str] = &[
+    "push",
+    "add", "sub", "mul", "widemul", "div",
+    "shr", "shl", "and", "or", "xor", "not", "neg",
+    "sadd", "ssub", "smul",
+    "popcount", "clz", "ctz",
+    "clamp", "inc", "dec",
+    "u8_promote", "u16_demote", "i8_promote", "i16_demote",
+    "reg",
+    "load", "store", "tee",
+    "dup", "drop",
+    "sys", "call", "ret",
+    "memcpy", "memset",
+    "alloc", "free",
+    "assert", "trap",
+    "break", "continue",
+    "while", "if", "else",
+];
+
 #[derive(Clone)]
 pub struct AstGen {
     config: Config,
     lib: Vec<String>,
     include: Vec<String>,
+    profiler: Option<Profiler>,
+    /// Provides the modules it knows instead of the search paths
+    sources: Option<Arc<dyn SourceProvider>>,
+    /// The number of loops and conditionals around the expression being generated
+    nesting: usize,
+    /// The number of lines in the source before the item being generated
+    first_line: usize,
+    /// The id of the module being generated
+    module_id: String,
+    /// The constants of the module being generated that alias another constant, by their id
+    const_aliases: BTreeMap<String, String>,
+    /// The module the AST is generated for, whose entry function starts with an empty stack
+    root_module: Option<String>,
+}
+
+/// Where a module found by `AstGen::discover` comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleKind {
+    /// A module of the include paths or of a source provider
+    Source,
+    /// A module of the library paths
+    Lib,
+}
+
+/// A module found by `AstGen::discover`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub id: String,
+    pub kind: ModuleKind,
+    /// The file of the module, `None` for modules loaded from a source provider
+    pub path: Option<PathBuf>,
+    /// The modules it imports functions or constants from
+    pub dependencies: BTreeSet<String>,
+}
+
+/// A syntax error in the source of a module
+#[derive(Debug)]
+pub struct ParseError {
+    /// The message of the parser, including an excerpt of the source
+    pub message: String,
+    /// The file of the module, `None` for modules that weren't read from a file
+    pub path: Option<PathBuf>,
+    /// The byte offset in the source
+    pub pos: usize,
+    pub line: usize,
+    pub col: usize,
+    /// The line of the source the error is in
+    pub snippet: String,
+    /// The names of the rules the parser would have accepted at the position
+    pub expected: Vec<String>,
+}
+
+impl ParseError {
+    fn new(err: &pest::Error<Rule>, source: &str) -> ParseError {
+        let (pos, expected) = match err {
+            pest::Error::ParsingError { positives, pos, .. } => (
+                pos,
+                positives.iter().map(|rule| format!("{:?}", rule)).collect(),
+            ),
+            pest::Error::CustomErrorPos { pos, .. } => (pos, Vec::new()),
+            pest::Error::CustomErrorSpan { span, .. } => (&span.start_pos(), Vec::new()),
+        };
+        let (line, col) = pos.line_col();
+
+        ParseError {
+            message: err.to_string(),
+            path: None,
+            pos: pos.pos(),
+            line,
+            col,
+            snippet: source.lines().nth(line - 1).unwrap_or_default().into(),
+            expected,
+        }
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        Diagnostic::error(self.message.clone())
+            .at(self.line, self.col)
+            .with_span(self.pos, self.pos)
+            .expecting(self.expected.clone())
+    }
+}
+
+impl fmt::Display for ParseError {
+    /// Writes the message with the file in front of the position it points to
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path {
+            Some(ref path) => write!(
+                f,
+                "{}",
+                self.message
+                    .replacen("--> ", &format!("--> {}:", path.display()), 1)
+            ),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl Fail for ParseError {}
+
+type ModuleResult = (String, Result<Module>);
+
+/// A requested module whose result hasn't been sent yet. If it is dropped before, e.g. because its
+/// worker panics, an error is sent in place of the result, so every request is answered.
+struct PendingModule {
+    module_name: String,
+    sender: Option<mpsc::Sender<ModuleResult>>,
+}
+
+impl PendingModule {
+    fn new(module_name: String, sender: &mpsc::Sender<ModuleResult>) -> PendingModule {
+        PendingModule {
+            module_name,
+            sender: Some(sender.clone()),
+        }
+    }
+
+    fn finish(mut self, module: Result<Module>) {
+        if let Some(sender) = self.sender.take() {
+            // The receiver is only gone if compilation was already aborted
+            let _ = sender.send((self.module_name.clone(), module));
+        }
+    }
+}
+
+impl Drop for PendingModule {
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            let err = format_err!("no result arrived for the module {:?}", self.module_name);
+            let _ = sender.send((self.module_name.clone(), Err(err)));
+        }
+    }
+}
+
+/// The modules of a program in the order they finish parsing. The dependencies of each module are
+/// requested as it arrives, the checks spanning several modules are left to the consumer.
+pub struct ModuleStream {
+    profiler: Option<Profiler>,
+    module_sender: mpsc::Sender<ModuleResult>,
+    module_receiver: mpsc::Receiver<ModuleResult>,
+    instructor_sender: mpsc::Sender<PendingModule>,
+    lazy_modules: bool,
+    entry: FuncRef,
+    /// The modules that arrived so far
+    ast: Ast,
+    /// The requested modules with the module that imported them first
+    requested_modules: BTreeMap<String, Option<String>>,
+    /// The number of requested modules that arrived or failed
+    answered: usize,
+    /// Whether the stream goes on after a module failed
+    collect_errors: bool,
+    finished: bool,
+}
+
+impl ModuleStream {
+    /// Waits for the next module, returning `None` once every requested module arrived
+    fn next_module(&mut self) -> Result<Option<(String, Module)>> {
+        // Every request is answered exactly once, either by its result or by an error
+        if self.answered == self.requested_modules.len() {
+            return Ok(None);
+        }
+
+        // The stream holds a sender itself, so receiving can't fail
+        let (module_name, module_res) = self.module_receiver.recv()?;
+        self.answered += 1;
+        let timer = Timer::start(&self.profiler);
+
+        let requested_modules = &self.requested_modules;
+        let module = module_res.with_context(|e| {
+            let importer = match requested_modules[&module_name] {
+                Some(ref importer) => format!(" imported by {:?}", importer),
+                None => String::new(),
+            };
+
+            format!("failed to compile module {:?}{}\n{}", module_name, importer, e)
+        })?;
+
+        self.ast.modules.insert(module_name.clone(), module.clone());
+
+        // Lazily, a module is only requested once a reachable function calls into it, which may
+        // only become known through the functions of a later module
+        // Constants are needed whether a function uses them or not
+        let dependencies = if self.lazy_modules {
+            let mut missing = self.ast.missing_imports(&self.entry);
+            for dependency in &module.const_imports {
+                missing.insert(dependency.clone(), module.id.clone());
+            }
+
+            missing
+        } else {
+            module
+                .dependencies()
+                .into_iter()
+                .map(|dependency| (dependency, module.id.clone()))
+                .collect()
+        };
+
+        for (dependency, importer) in dependencies {
+            if !self.requested_modules.contains_key(&dependency) {
+                self.requested_modules
+                    .insert(dependency.clone(), Some(importer));
+
+                self.instructor_sender
+                    .send(PendingModule::new(dependency, &self.module_sender))?;
+            }
+        }
+
+        timer.resolution();
+
+        Ok(Some((module_name, module)))
+    }
+}
+
+impl Iterator for ModuleStream {
+    type Item = Result<(String, Module)>;
+
+    /// Ends after the first error, unless errors are collected and the module could be read
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        match self.next_module() {
+            Ok(Some(module)) => Some(Ok(module)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(err) => {
+                let unreadable = err.causes()
+                    .any(|cause| cause.downcast_ref::<io::Error>().is_some());
+                self.finished = !self.collect_errors || unreadable;
+
+                Some(Err(err))
+            }
+        }
+    }
 }
 
 impl AstGen {
@@ -33,9 +307,35 @@ impl AstGen {
             config: config,
             lib: lib,
             include: include,
+            profiler: None,
+            sources: None,
+            nesting: 0,
+            first_line: 0,
+            module_id: String::new(),
+            const_aliases: BTreeMap::new(),
+            root_module: None,
         }
     }
 
+    /// Creates a generator loading the modules known to the given provider from it, any other
+    /// module is looked up in the search paths
+    pub fn with_sources<P: SourceProvider + 'static>(config: Config, sources: P) -> AstGen {
+        let mut compiler = AstGen::new(config);
+        compiler.sources = Some(Arc::new(sources));
+
+        compiler
+    }
+
+    /// Creates a generator with the given library and include paths instead of those of the
+    /// config. Modules are looked up in the include paths, followed by the default one, and then in
+    /// the library paths, again followed by the default one.
+    pub fn with_paths(mut config: Config, lib: Vec<String>, include: Vec<String>) -> AstGen {
+        config.compilation.lib_dirs = lib;
+        config.compilation.include_dirs = include;
+
+        AstGen::new(config)
+    }
+
     pub fn gen(root_module: String, config: Config) -> Result<Ast> {
         let mut compiler = AstGen::new(config);
         let ast = compiler.ast(root_module)?;
@@ -43,289 +343,1333 @@ impl AstGen {
         Ok(ast)
     }
 
-    fn ast(&mut self, root_module: String) -> Result<Ast> {
-        let (module_sender, module_receiver) = mpsc::channel();
-        let (instructor_sender, instructor_receiver) = mpsc::channel::<String>();
+    /// Yields the modules of the program as soon as each of them is parsed, without the checks
+    /// `gen` runs on the whole AST afterwards
+    pub fn gen_streaming(
+        root_module: String,
+        config: Config,
+    ) -> impl Iterator<Item = Result<(String, Module)>> {
+        AstGen::new(config).stream(root_module)
+    }
 
-        instructor_sender.send(root_module.clone())?;
+    /// Parses every module found in the given directory and its subdirectories on its own, whether
+    /// it is imported anywhere or not. Returns the warnings of all modules, or the errors of all
+    /// modules that failed to parse.
+    pub fn check_all<P: AsRef<Path>>(dir: P, config: Config) -> Result<Vec<String>> {
+        let dir = dir.as_ref();
+        let mut compiler = AstGen::new(config);
 
-        let compiler = self.clone();
-        let instructor_sender = instructor_sender.clone();
-        thread::spawn(move || {
-            while let Ok(module_name) = instructor_receiver.recv() {
-                let mut compiler = compiler.clone();
-                let module_sender = module_sender.clone();
-                thread::spawn(move || {
-                    let module = compiler.module(module_name.clone());
+        let strict_extensions = compiler
+            .config
+            .compilation
+            .strict_extensions
+            .unwrap_or(defaults::STRICT_EXTENSIONS);
+        let extensions = if strict_extensions {
+            &SOURCE_FILE_EXTENSIONS[..1]
+        } else {
+            &SOURCE_FILE_EXTENSIONS[..]
+        };
 
-                    module_sender.send((module_name, module)).unwrap();
-                });
+        let mut files = Vec::new();
+        AstGen::source_files(dir, extensions, &mut files)
+            .with_context(|_| format!("unable to read directory {:?}", dir))?;
+        files.sort();
+
+        let mut warnings = Vec::new();
+        let mut errors = Vec::new();
+
+        for file in files {
+            let module_id = file.strip_prefix(dir)?
+                .with_extension("")
+                .iter()
+                .map(|part| part.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(".");
+
+            match compiler.read_module(module_id.clone(), &file) {
+                Ok(module) => for warning in module.warnings {
+                    warnings.push(format!("module {:?}: {}", module_id, warning));
+                },
+                Err(err) => errors.push(format!("{}: {}", file.display(), err)),
             }
-        });
+        }
+
+        ensure!(errors.is_empty(), "{}", errors.join("\n"));
+
+        Ok(warnings)
+    }
 
-        let mut modules = BTreeMap::new();
-        let mut requested_modules = BTreeSet::new();
-        requested_modules.insert(root_module);
+    /// Collects the files with one of the given extensions in the directory and its
+    /// subdirectories
+    fn source_files(dir: &Path, extensions: &[&str], files: &mut Vec<PathBuf>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            if path.is_dir() {
+                AstGen::source_files(&path, extensions, files)?;
+            } else if extensions
+                .iter()
+                .any(|extension| path.extension() == Some(extension.as_ref()))
+            {
+                files.push(path);
+            }
+        }
 
-        loop {
-            match module_receiver.try_recv() {
-                Ok((module_name, module_res)) => {
-                    let module = module_res.with_context(|e| {
-                        format!("failed to compile module {:?}\n{}", module_name, e)
-                    })?;
+        Ok(())
+    }
 
-                    modules.insert(module_name, module.clone());
+    /// Generates the AST like `gen` and runs the given pass over it before returning it. This is
+    /// the place to rewrite the AST, e.g. to inject instrumentation or rename symbols.
+    pub fn gen_with_pass(
+        root_module: String,
+        config: Config,
+        pass: &mut dyn FnMut(&mut Ast) -> Result<()>,
+    ) -> Result<Ast> {
+        let mut ast = AstGen::gen(root_module, config)?;
+        pass(&mut ast)?;
 
-                    for import in module.imports {
-                        if !requested_modules.contains(&import.module_id) {
-                            requested_modules.insert(import.module_id.clone());
+        Ok(ast)
+    }
 
-                            instructor_sender.send(import.module_id)?;
-                        }
-                    }
-                }
-                Err(TryRecvError::Empty) => {
-                    if modules.len() == requested_modules.len() {
-                        break;
-                    }
+    /// Parses a single module read from the given reader, e.g. the standard input, as the root
+    /// module with the given name. Its imports are left unresolved.
+    pub fn compile_reader<R: Read>(reader: R, module_name: &str, config: Config) -> Result<Module> {
+        let mut compiler = AstGen::new(config);
+        compiler.root_module = Some(module_name.into());
+
+        compiler.read_source(module_name.into(), reader, None)
+    }
 
-                    thread::yield_now();
+    /// Finds the given module and every module it depends on without generating their ASTs. Only
+    /// the imports of each module are read, the modules are returned in the order they were found.
+    pub fn discover(&mut self, root_module: String) -> Result<Vec<ModuleInfo>> {
+        let mut found = Vec::new();
+        let mut requested = BTreeSet::new();
+        let mut queue = VecDeque::new();
+
+        requested.insert(root_module.clone());
+        queue.push_back((root_module, None));
+
+        while let Some((module_id, importer)) = queue.pop_front() {
+            let info = self.module_info(&module_id).with_context(|e| {
+                let importer = match importer {
+                    Some(ref importer) => format!(" imported by {:?}", importer),
+                    None => String::new(),
+                };
+
+                format!("failed to discover module {:?}{}\n{}", module_id, importer, e)
+            })?;
+
+            for dependency in &info.dependencies {
+                if requested.insert(dependency.clone()) {
+                    queue.push_back((dependency.clone(), Some(module_id.clone())));
                 }
-                _ => bail!("an unknown error occured"),
             }
+
+            found.push(info);
         }
 
-        Ok(Ast { modules: modules })
+        Ok(found)
     }
 
-    fn module(&mut self, module_id: String) -> Result<Module> {
-        let module_file = self.discover_module(module_id.clone())?;
+    /// Loads the module and reads the modules it imports from
+    fn module_info(&mut self, module_id: &str) -> Result<ModuleInfo> {
+        let provided = match self.sources.clone() {
+            Some(sources) => sources.load(module_id)?,
+            None => None,
+        };
+
+        let (source, path) = match provided {
+            Some(source) => (source, None),
+            None => {
+                let module_file = self.discover_module(module_id.into())?;
+                (fs::read_to_string(&module_file)?, Some(module_file))
+            }
+        };
+
+        let fields = BeastParser::parse(Rule::file, &source).map_err(|err| {
+            let mut err = ParseError::new(&err, &source);
+            err.path = path.clone();
+            err
+        })?;
+
+        // The items declaring imports only need the id of the module to be resolved
+        let mut module = Module::new(module_id.into());
+
+        for pair in fields {
+            match pair.as_rule() {
+                Rule::import | Rule::module_alias | Rule::const_glob_import => {
+                    self.item(pair, &mut module)?
+                }
+                _ => {}
+            }
+        }
+
+        // The include paths are searched first, so only files outside of them are libraries
+        let in_dirs = |dirs: &[String], path: &Path| dirs.iter().any(|dir| path.starts_with(dir));
+        let kind = match path {
+            Some(ref path) if !in_dirs(&self.include, path) && in_dirs(&self.lib, path) => {
+                ModuleKind::Lib
+            }
+            _ => ModuleKind::Source,
+        };
 
-        let mut file = File::open(module_file)?;
+        Ok(ModuleInfo {
+            id: module.id.clone(),
+            kind,
+            path,
+            dependencies: module.dependencies(),
+        })
+    }
+
+    /// Reads the given module as a lossless token stream instead of an AST
+    pub fn tokens(module_id: String, config: Config) -> Result<TokenStream> {
+        let mut compiler = AstGen::new(config);
+        let module_file = compiler.discover_module(module_id)?;
 
         let mut buf = String::new();
+        File::open(&module_file)?.read_to_string(&mut buf)?;
 
-        file.read_to_string(&mut buf)?;
+        let tokens = TokenStream::parse(&buf)
+            .with_context(|e| format!("failed to read {:?}\n{}", module_file, e))?;
 
-        let parsing_result = BeastParser::parse(Rule::file, &buf);
+        Ok(tokens)
+    }
 
-        let parsed_file = match parsing_result {
-            Err(err) => bail!("{}", err),
-            Ok(res) => res,
+    /// Generates the AST like `gen` and reports the time spent in each phase
+    pub fn gen_profiled(root_module: String, config: Config) -> Result<(Ast, CompileReport)> {
+        let profiler = Profiler::default();
+        let start = Instant::now();
+
+        let mut compiler = AstGen::new(config);
+        compiler.profiler = Some(profiler.clone());
+        let ast = compiler.ast(root_module)?;
+
+        Ok((ast, profiler.finish(start)))
+    }
+
+    /// Starts parsing the given module and every module it depends on in parallel, on as many
+    /// workers as the configured number of jobs
+    fn stream(&self, root_module: String) -> ModuleStream {
+        let (instructor_sender, instructor_receiver) = mpsc::channel();
+
+        self.spawn_workers(root_module.clone(), instructor_receiver);
+        self.module_stream(root_module, instructor_sender)
+    }
+
+    /// Starts the workers parsing the requested modules, which exit once the requests end
+    fn spawn_workers(
+        &self,
+        root_module: String,
+        instructor_receiver: mpsc::Receiver<PendingModule>,
+    ) {
+        let mut compiler = self.clone();
+        compiler.root_module = Some(root_module);
+
+        // The workers take turns waiting for the next request and exit once the stream is dropped
+        let instructor_receiver = Arc::new(Mutex::new(instructor_receiver));
+        for _ in 0..self.config.jobs() {
+            let mut compiler = compiler.clone();
+            let instructor_receiver = instructor_receiver.clone();
+            thread::spawn(move || loop {
+                let next = instructor_receiver
+                    .lock()
+                    .ok()
+                    .and_then(|receiver| receiver.recv().ok());
+                let pending = match next {
+                    Some(pending) => pending,
+                    None => return,
+                };
+
+                let module_name = pending.module_name.clone();
+                let module = AstGen::catch_panic(|| compiler.module(module_name));
+
+                pending.finish(module);
+            });
+        }
+    }
+
+    /// Requests the given module from the workers receiving the requests sent to the given sender
+    /// and collects their results
+    fn module_stream(
+        &self,
+        root_module: String,
+        instructor_sender: mpsc::Sender<PendingModule>,
+    ) -> ModuleStream {
+        let (module_sender, module_receiver) = mpsc::channel();
+
+        // The workers only stop receiving once the sender is dropped, so sending can't fail
+        let _ = instructor_sender.send(PendingModule::new(root_module.clone(), &module_sender));
+
+        let mut requested_modules = BTreeMap::new();
+        requested_modules.insert(root_module.clone(), None);
+
+        ModuleStream {
+            profiler: self.profiler.clone(),
+            module_sender,
+            module_receiver,
+            instructor_sender,
+            lazy_modules: self.config
+                .compilation
+                .lazy_modules
+                .unwrap_or(defaults::LAZY_MODULES),
+            entry: (root_module, self.config.entry_func()),
+            // Only the modules are needed to find the imports of lazily parsed modules
+            ast: Ast {
+                modules: BTreeMap::new(),
+                memory: MemoryLayout::default(),
+            },
+            requested_modules,
+            answered: 0,
+            collect_errors: self.config
+                .compilation
+                .collect_errors
+                .unwrap_or(defaults::COLLECT_ERRORS),
+            finished: false,
+        }
+    }
+
+    /// Generates the AST of the given module and every module it depends on
+    pub fn ast(&mut self, root_module: String) -> Result<Ast> {
+        let mut ast = Ast {
+            modules: BTreeMap::new(),
+            memory: self.config.memory_layout()?,
         };
 
-        let mut imports = Vec::new();
-        let mut exports = Vec::new();
-        let mut constants = Vec::new();
-        let mut funcs = Vec::new();
+        let collect_errors = self.config
+            .compilation
+            .collect_errors
+            .unwrap_or(defaults::COLLECT_ERRORS);
+        let mut errors = Vec::new();
 
-        for pair in parsed_file {
-            match pair.as_rule() {
-                Rule::import => {
-                    let import = self.import(pair)?;
-                    imports.push(import);
-                }
-                Rule::func => {
-                    let func = self.func(pair)?;
-                    funcs.push(func);
+        for module in self.stream(root_module.clone()) {
+            match module {
+                Ok((module_name, module)) => {
+                    ast.modules.insert(module_name, module);
                 }
-                Rule::export => {
-                    let export = self.export(pair)?;
-                    exports.push(export);
+                Err(err) => errors.push(err),
+            }
+        }
+
+        // The checks spanning several modules would only report the missing ones again
+        AstGen::fail_with(errors)?;
+
+        let lazy_modules = self.config
+            .compilation
+            .lazy_modules
+            .unwrap_or(defaults::LAZY_MODULES);
+        let entry_func = self.config.entry_func();
+
+        // Unreachable functions may call into modules that were never parsed
+        if lazy_modules {
+            let entry = (root_module.clone(), entry_func.clone());
+            let reachable = CallGraph::new(&ast).reachable_from(&entry);
+
+            for module in ast.modules.values_mut() {
+                let module_id = module.id.clone();
+                module
+                    .funcs
+                    .retain(|func| reachable.contains(&(module_id.clone(), func.id.clone())));
+            }
+        }
+
+        ast.resolve_const_imports()?;
+
+        let mut errors = Vec::new();
+        let checks: &[fn(&Ast) -> Result<()>] = &[
+            Ast::check_imports,
+            Ast::check_static_asserts,
+            Ast::check_purity,
+            Ast::check_regions,
+        ];
+        for check in checks {
+            if let Err(err) = check(&ast) {
+                errors.push(err);
+                if !collect_errors {
+                    break;
                 }
-                Rule::constant => {
-                    let constant = self.constant(pair)?;
-                    constants.push(constant);
+            }
+        }
+
+        ast.check_constant_widths();
+        ast.check_import_cycles();
+
+        if let Some(order) = self.config.compilation.function_order {
+            ast.order_funcs(order);
+        }
+
+        for module in ast.modules.values() {
+            for func in &module.funcs {
+                if !func.params.is_empty() && (module.id != root_module || func.id != entry_func) {
+                    errors.push(format_err!(
+                        "{} of module {:?} declares parameters, but only the entry function {} \
+                         of module {:?} may",
+                        func.id,
+                        module.id,
+                        entry_func,
+                        root_module
+                    ));
                 }
-                _ => unreachable!(),
             }
         }
 
-        Ok(Module {
-            id: module_id,
-            imports,
-            exports,
-            constants,
-            funcs,
-        })
-    }
+        if !collect_errors {
+            errors.truncate(1);
+        }
+        AstGen::fail_with(errors)?;
 
-    fn import(&mut self, pair: Pair<Rule>) -> Result<Import> {
-        let mut pairs = pair.into_inner();
+        Ok(ast)
+    }
 
-        let func_name = pairs.next().unwrap().as_str();
+    /// Fails with the given errors, a single one as it is and several ones joined by lines
+    fn fail_with(mut errors: Vec<Error>) -> Result<()> {
+        if errors.len() == 1 {
+            return Err(errors.remove(0));
+        } else if !errors.is_empty() {
+            let messages: Vec<_> = errors.iter().map(|err| err.to_string()).collect();
 
-        let after_func = pairs.next().unwrap();
+            bail!("{}", messages.join("\n"));
+        }
 
-        let (func_alias, module_id) = if after_func.as_rule() == Rule::func_alias {
-            (Some(after_func.as_str()), pairs.next().unwrap().as_str())
-        } else {
-            (None, after_func.as_str())
-        };
+        Ok(())
+    }
 
-        Ok(Import {
-            func_origin_id: func_name.into(),
-            func_alias_id: func_alias.unwrap_or(func_name).into(),
-            module_id: module_id.into(),
-        })
+    /// Runs the given closure and turns a panic inside of it into an error, so a failing worker
+    /// can't leave the main loop waiting for its module forever
+    fn catch_panic<T, F: FnOnce() -> Result<T>>(f: F) -> Result<T> {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(res) => res,
+            Err(payload) => {
+                let msg = payload
+                    .downcast_ref::<&str>()
+                    .map(|msg| msg.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown cause".into());
+
+                bail!("internal compiler error: {}", msg)
+            }
+        }
     }
 
-    fn func(&mut self, pair: Pair<Rule>) -> Result<Func> {
-        let mut pairs = pair.into_inner();
+    fn module(&mut self, module_id: String) -> Result<Module> {
+        if let Some(sources) = self.sources.clone() {
+            let source = sources
+                .load(&module_id)
+                .with_context(|e| format!("unable to load module {:?}\n{}", module_id, e))?;
 
-        let func_name = pairs.next().unwrap().as_str();
+            if let Some(source) = source {
+                return self.read_source(module_id, source.as_bytes(), None);
+            }
+        }
 
-        let mut instr_vec = Vec::new();
+        let timer = Timer::start(&self.profiler);
+        let module_file = self.discover_module(module_id.clone())?;
+        timer.discovery();
 
-        for expr in pairs {
-            let expr = self.expr(expr)?;
+        self.read_module(module_id, &module_file)
+    }
+
+    /// Reads and parses the module stored in the given file
+    fn read_module(&mut self, module_id: String, module_file: &Path) -> Result<Module> {
+        let file = File::open(module_file)?;
 
-            instr_vec.push(expr);
+        let mut module = self.read_source(module_id, file, Some(module_file))?;
+        module.path = Some(module_file.to_path_buf());
+
+        if module_file.extension() == Some(SOURCE_FILE_EXTENSIONS[1].as_ref()) {
+            module.warnings.push(format!(
+                "the extension of {:?} is deprecated, rename it to .{}",
+                module_file, SOURCE_FILE_EXTENSIONS[0]
+            ));
         }
 
-        Ok(Func {
-            id: func_name.into(),
-            expr: instr_vec,
-        })
+        Ok(module)
     }
 
-    fn constant(&mut self, pair: Pair<Rule>) -> Result<Const> {
-        let mut pairs = pair.into_inner();
+    /// Reads the source of a module from the reader and parses it, failing with every error found.
+    /// A single syntax error is returned as the `ParseError` itself, pointing into the given file.
+    fn read_source<R: Read>(
+        &mut self,
+        module_id: String,
+        mut reader: R,
+        path: Option<&Path>,
+    ) -> Result<Module> {
+        let mut buf = String::new();
 
-        let const_id = pairs.next().unwrap().as_str();
+        reader.read_to_string(&mut buf)?;
 
-        let raw_const_lit = pairs.next().unwrap().as_str();
+        let timer = Timer::start(&self.profiler);
+        let (module, mut errors) = self.parse_module(module_id.clone(), &buf);
+        timer.parse(&module_id);
 
-        Ok(Const {
-            id: const_id.into(),
-            value: raw_const_lit
-                .parse()
-                .or_else(|_| i32::from_str_radix(&raw_const_lit[2..], 16))?,
-        })
+        for err in &mut errors {
+            if let Some(parse_error) = err.downcast_mut::<ParseError>() {
+                parse_error.path = path.map(Path::to_path_buf);
+            }
+        }
+
+        AstGen::fail_with(errors)?;
+
+        Ok(module)
     }
 
-    fn export(&mut self, pair: Pair<Rule>) -> Result<Export> {
-        let mut pairs = pair.into_inner();
+    /// Parses the top-level items of the given source one by one. Items that fail to parse are
+    /// reported and skipped, so the returned module contains every item that could be parsed.
+    fn parse_module(&mut self, module_id: String, source: &str) -> (Module, Vec<Error>) {
+        self.module_id = module_id.clone();
 
-        let exported_func = pairs.next().unwrap().as_str();
+        let mut module = Module::new(module_id);
 
-        let alias = pairs.next().and_then(|e| Some(e.as_str())).or_else(|| None);
+        let mut errors = Vec::new();
+        let mut pos = 0;
 
-        Ok(Export {
-            func_origin_id: exported_func.into(),
-            func_alias_id: alias.unwrap_or(exported_func).into(),
-        })
-    }
+        while BeastParser::parse(Rule::file_end, &source[pos..]).is_err() {
+            let next_pos = match BeastParser::parse(Rule::file_item, &source[pos..]) {
+                Ok(mut pairs) => {
+                    let pair = pairs.next().unwrap();
+                    let item_end = pos + pair.clone().into_span().end();
+                    self.first_line = source[..pos].matches('\n').count();
 
-    fn expr(&mut self, pair: Pair<Rule>) -> Result<Expr> {
-        let mut pairs = pair.into_inner();
+                    if let Err(err) = self.item(pair, &mut module) {
+                        errors.push(err);
+                    }
 
-        let plain_instr = pairs.next().unwrap();
-        let mut inner = plain_instr.clone().into_inner();
+                    Some(item_end)
+                }
+                Err(_) => {
+                    // Skip leading whitespace and comments to start searching after the item
+                    let skipped = BeastParser::parse(Rule::file_item_start, &source[pos..]);
+                    let item_start = match skipped {
+                        Ok(mut pairs) => pos + pairs.next().unwrap().into_span().end(),
+                        Err(_) => pos,
+                    };
 
-        match plain_instr.as_rule() {
-            Rule::push_instr => {
-                let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
-
-                let raw_arg = inner.next().unwrap();
-                match raw_arg.as_rule() {
-                    Rule::constant_id => {
-                        let arg = raw_arg.as_str();
-                        let inst = match real_type {
-                            IntegerType::U8 => Expr::PushConstU8(Argument::Constant(arg.into())),
-                            IntegerType::U16 => Expr::PushConstU16(Argument::Constant(arg.into())),
-                            IntegerType::I8 => Expr::PushConstI8(Argument::Constant(arg.into())),
-                            IntegerType::I16 => Expr::PushConstI16(Argument::Constant(arg.into())),
-                        };
-
-                        Ok(inst)
+                    errors.push(AstGen::parse_error_at(source, item_start));
+
+                    AstGen::next_item_start(source, item_start)
+                }
+            };
+
+            match next_pos {
+                Some(next_pos) => pos = next_pos,
+                None => break,
+            }
+        }
+
+        errors.append(&mut self.resolve_const_aliases(&mut module));
+
+        for const_id in &module.const_exports {
+            if module.constants.iter().all(|constant| constant.id != *const_id) {
+                errors.push(format_err!(
+                    "unable to export the constant {}, it isn't defined",
+                    const_id
+                ));
+            }
+        }
+
+        // Constants can be declared after the functions they configure, so the cfg attributes are
+        // evaluated once every item is known
+        let funcs = ::std::mem::take(&mut module.funcs);
+        for mut func in funcs {
+            match AstGen::is_configured(&func, &module.constants) {
+                Ok(true) => {
+                    if let Err(err) = type_check::infer(&mut func) {
+                        errors.push(err);
+                        continue;
+                    }
+
+                    if let Err(err) = loop_labels::check(&func) {
+                        errors.push(err);
+                        continue;
                     }
-                    Rule::literal => {
-                        let arg = raw_arg.as_str();
-                        let inst = match real_type {
-                            IntegerType::U8 => Expr::PushConstU8(
-                                Argument::Literal(arg.parse().or_else(|_| u8::from_str_radix(&arg[2..], 16))?),
-                            ),
-                            IntegerType::U16 => Expr::PushConstU16(
-                                Argument::Literal(arg.parse().or_else(|_| u16::from_str_radix(&arg[2..], 16))?),
-                            ),
-                            IntegerType::I8 => Expr::PushConstI8(
-                                Argument::Literal(arg.parse().or_else(|_| i8::from_str_radix(&arg[2..], 16))?),
-                            ),
-                            IntegerType::I16 => Expr::PushConstI16(
-                                Argument::Literal(arg.parse().or_else(|_| i16::from_str_radix(&arg[2..], 16))?),
-                            ),
-                        };
-
-                        Ok(inst)
+
+                    module.warnings.append(&mut alloc_balance::check(&func));
+                    let is_entry = self.root_module.as_ref() == Some(&module.id)
+                        && func.id == self.config.entry_func();
+                    module.warnings.append(&mut type_check::check(&func, is_entry));
+
+                    if let Some(max) = self.config.compilation.max_func_instructions {
+                        let size = func.size();
+
+                        if size > max {
+                            module.warnings.push(format!(
+                                "in {}: the function has {} instructions, more than the maximum \
+                                 of {}",
+                                func.id, size, max
+                            ));
+                        }
                     }
-                    _ => unreachable!(),
+
+                    module.funcs.push(func);
                 }
+                Ok(false) => {}
+                Err(err) => errors.push(err),
             }
-            Rule::add => {
-                let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
-                Ok(Expr::ActualInstr(Instruction::Add(real_type)))
+        }
+
+        for (index, func) in module.funcs.iter().enumerate() {
+            if module.funcs[..index].iter().any(|other| other.id == func.id) {
+                errors.push(format_err!(
+                    "the function {} is defined more than once in module {:?}",
+                    func.id,
+                    module.id
+                ));
             }
-            Rule::sub => {
-                let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
-                Ok(Expr::ActualInstr(Instruction::Sub(real_type)))
+        }
+
+        for export in &module.exports {
+            if module.funcs.iter().all(|func| func.id != export.func_origin_id) {
+                errors.push(format_err!(
+                    "unable to export the function {}, module {:?} doesn't define it",
+                    export.func_origin_id,
+                    module.id
+                ));
             }
-            Rule::mul => {
-                let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
-                Ok(Expr::ActualInstr(Instruction::Mul(real_type)))
+        }
+
+        // Exported functions are compiled under their alias
+        for (index, export) in module.exports.iter().enumerate() {
+            let collision = module.exports[..index]
+                .iter()
+                .find(|other| other.func_alias_id == export.func_alias_id);
+
+            if let Some(other) = collision {
+                errors.push(format_err!(
+                    "the exports {} and {} of module {:?} are both named {}",
+                    other.func_origin_id,
+                    export.func_origin_id,
+                    module.id,
+                    export.func_alias_id
+                ));
             }
-            Rule::div => {
-                let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
-                Ok(Expr::ActualInstr(Instruction::Div(real_type)))
+        }
+
+        // Calls resolve imports before local functions, which makes shadowed functions
+        // unreachable by name
+        for func in &module.funcs {
+            if let Some(import) = module.imports.iter().find(|imp| imp.func_alias_id == func.id) {
+                module.warnings.push(format!(
+                    "{} is both defined locally and imported from module {:?}, calls resolve to \
+                     the import",
+                    func.id, import.module_id
+                ));
             }
-            Rule::shr => {
-                let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
-                Ok(Expr::ActualInstr(Instruction::Shr(real_type)))
+        }
+
+        (module, errors)
+    }
+
+    /// Returns whether the constant of the cfg attribute of the function, if any, is nonzero
+    fn is_configured(func: &Func, constants: &[Const]) -> Result<bool> {
+        let constant = match func.cfg {
+            Some(ref constant) => constant,
+            None => return Ok(true),
+        };
+
+        let value = constants
+            .iter()
+            .find(|cons| cons.id == *constant)
+            .ok_or_else(|| {
+                format_err!(
+                    "unable to find constant {:?} in the cfg attribute of {}",
+                    constant,
+                    func.id
+                )
+            })?
+            .value;
+
+        Ok(value != 0)
+    }
+
+    /// Adds the given item to the module
+    fn item(&mut self, pair: Pair<Rule>, module: &mut Module) -> Result<()> {
+        match pair.as_rule() {
+            Rule::import => {
+                let import = self.import(pair, &module.id, &mut module.warnings)?;
+                module.imports.push(import);
             }
-            Rule::shl => {
-                let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+            Rule::module_alias => {
+                let mut pairs = pair.into_inner();
+
+                let module_pair = pairs.next().unwrap();
+                let module_id = self.module_path(module_pair, &module.id, &mut module.warnings)?;
+                let alias = pairs.next().unwrap().as_str();
+
+                ensure!(
+                    !module.module_aliases.contains_key(alias),
+                    "module alias {:?} is already used",
+                    alias
+                );
+
+                module.module_aliases.insert(alias.into(), module_id);
+            }
+            Rule::func => {
+                let func = self.func(pair)?;
+                module.funcs.push(func);
+            }
+            Rule::export => {
+                let export = self.export(pair)?;
+                module.exports.push(export);
+            }
+            Rule::const_glob_import => {
+                let module_pair = pair.into_inner().next().unwrap();
+                let module_id = self.module_path(module_pair, &module.id, &mut module.warnings)?;
+
+                module.const_imports.push(module_id);
+            }
+            Rule::static_assert => {
+                let cond = pair.into_inner().next().unwrap();
+
+                module.static_asserts.push(StaticAssert {
+                    source: cond.as_str().into(),
+                    cond: AstGen::const_expr(cond)?,
+                });
+            }
+            Rule::export_const => {
+                let const_id = pair.into_inner().next().unwrap().as_str();
+                module.const_exports.push(const_id.into());
+            }
+            Rule::constant => {
+                if let Some(constant) = self.constant(pair, module)? {
+                    module.constants.push(constant);
+                }
+            }
+            Rule::region => {
+                let mut pairs = pair.into_inner();
+
+                let region_id = pairs.next().unwrap().as_str();
+                let start = literal::parse_u16(pairs.next().unwrap().as_str())?;
+                let size = literal::parse_u16(pairs.next().unwrap().as_str())?;
+
+                let region = Region {
+                    id: region_id.into(),
+                    start,
+                    size,
+                };
+
+                ensure!(
+                    region.end() <= 0x1_0000,
+                    "memory region {} ends beyond the address space",
+                    region_id
+                );
+
+                module.regions.push(region);
+            }
+            Rule::constant_group => {
+                for constant in self.constant_group(pair, &mut module.warnings)? {
+                    let value = Argument::Literal(constant.value);
+
+                    if self.is_new_constant(module, &constant.id, &value)? {
+                        module.constants.push(constant);
+                    }
+                }
+            }
+            rule => bail!(
+                "internal compiler error: unexpected rule {:?} at the top level of module {:?}",
+                rule,
+                module.id
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Creates the parse error of the item at the given position. Everything before the item is
+    /// blanked out instead of cut off, so the error points at the right line and column.
+    fn parse_error_at(source: &str, pos: usize) -> Error {
+        let masked: String = source[..pos]
+            .chars()
+            .map(|c| if c == '\n' { c } else { ' ' })
+            .chain(source[pos..].chars())
+            .collect();
+
+        match BeastParser::parse(Rule::file_item, &masked) {
+            Err(err) => ParseError::new(&err, source).into(),
+            Ok(_) => format_err!("unable to parse item at byte {}", pos),
+        }
+    }
+
+    /// Finds the start of the next top-level item after the given position, i.e. the next line
+    /// starting with an opening brace or an attribute
+    fn next_item_start(source: &str, pos: usize) -> Option<usize> {
+        source[pos..]
+            .match_indices('\n')
+            .map(|(index, _)| pos + index + 1)
+            .find(|&line_start| {
+                let line = &source[line_start..];
+
+                line.starts_with('(') || line.starts_with("#[")
+            })
+    }
+
+    fn import(
+        &mut self,
+        pair: Pair<Rule>,
+        importer: &str,
+        warnings: &mut Vec<String>,
+    ) -> Result<Import> {
+        let mut pairs = pair.into_inner();
+
+        let func_name = pairs.next().unwrap().as_str();
+
+        let after_func = pairs.next().unwrap();
+
+        let (func_alias, module_pair) = if after_func.as_rule() == Rule::func_alias {
+            (Some(after_func.as_str()), pairs.next().unwrap())
+        } else {
+            (None, after_func)
+        };
+
+        Ok(Import {
+            func_origin_id: func_name.into(),
+            func_alias_id: func_alias.unwrap_or(func_name).into(),
+            module_id: self.module_path(module_pair, importer, warnings)?,
+        })
+    }
+
+    fn const_expr(pair: Pair<Rule>) -> Result<ConstExpr> {
+        let mut pairs = pair.into_inner();
+        let first = pairs.next().unwrap();
+
+        Ok(match first.as_rule() {
+            Rule::integer_literal => ConstExpr::Literal(literal::parse_i32(first.as_str())?),
+            Rule::constant_id => ConstExpr::Constant(first.as_str().into()),
+            _ => {
+                let lhs = AstGen::const_expr(pairs.next().unwrap())?;
+                let rhs = AstGen::const_expr(pairs.next().unwrap())?;
+
+                ConstExpr::Op(first.as_str().into(), Box::new(lhs), Box::new(rhs))
+            }
+        })
+    }
+
+    /// Reads the path of a module imported by the given module, given as a module id or a string
+    fn module_path(
+        &self,
+        pair: Pair<Rule>,
+        importer: &str,
+        warnings: &mut Vec<String>,
+    ) -> Result<String> {
+        let module_id = if pair.as_rule() == Rule::string {
+            literal::parse_string(pair.as_str())?
+        } else {
+            pair.as_str().to_string()
+        };
+        let module_id = module_id.as_str();
+
+        let module_id = match AstGen::strip_source_extension(module_id) {
+            Some(stripped) => {
+                warnings.push(format!(
+                    "the extension of {:?} is unnecessary, import it as {:?}",
+                    module_id, stripped
+                ));
+
+                stripped
+            }
+            None => module_id,
+        };
+
+        Ok(self.resolve_module_path(importer, module_id))
+    }
+
+    /// Returns the id of the module the given module refers to by the path. Unless module paths
+    /// are absolute, the path is relative to the directory of the referring module first and to
+    /// the search paths only if there is no such module next to it.
+    fn resolve_module_path(&self, importer: &str, path: &str) -> String {
+        let absolute_module_paths = self.config
+            .compilation
+            .absolute_module_paths
+            .unwrap_or(defaults::ABSOLUTE_MODULE_PATHS);
+
+        if !absolute_module_paths {
+            if let Some(dot) = importer.rfind('.') {
+                let relative = format!("{}.{}", &importer[..dot], path);
+
+                if self.is_provided(&relative) || !self.module_files(&relative).is_empty() {
+                    return relative;
+                }
+            }
+        }
+
+        path.into()
+    }
+
+    /// Whether the source provider knows the module. A provider failing to load it counts as
+    /// knowing it, so the failure is reported once the module is loaded.
+    fn is_provided(&self, module: &str) -> bool {
+        match self.sources {
+            Some(ref sources) => !matches!(sources.load(module), Ok(None)),
+            None => false,
+        }
+    }
+
+    /// Returns the module path without its trailing source file extension, if it has one
+    fn strip_source_extension(module_id: &str) -> Option<&str> {
+        let (path, extension) = module_id.split_at(module_id.rfind('.')?);
+
+        if SOURCE_FILE_EXTENSIONS.contains(&&extension[1..]) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    fn func(&mut self, pair: Pair<Rule>) -> Result<Func> {
+        let mut pairs = pair.into_inner().peekable();
+
+        let mut is_inline = false;
+        let mut is_pure = false;
+        let mut cfg = None;
+        let mut align = None;
+
+        while pairs.peek().map(|pair| pair.as_rule()) == Some(Rule::attribute) {
+            let mut attribute = pairs.next().unwrap().into_inner();
+            let attribute_id = attribute.next().unwrap().as_str();
+            let argument = attribute.next().map(|arg| arg.as_str().to_string());
+
+            match (attribute_id, argument) {
+                ("inline", None) => is_inline = true,
+                ("pure", None) => is_pure = true,
+                ("cfg", Some(ref constant)) if constant.starts_with('%') => {
+                    cfg = Some(constant.clone())
+                }
+                ("cfg", _) => bail!("the cfg attribute needs a constant, like #[cfg(%DEBUG)]"),
+                ("align", Some(ref raw_align)) if !raw_align.starts_with('%') => {
+                    let value = literal::parse_u16(raw_align)?;
+                    ensure!(
+                        value.is_power_of_two(),
+                        "the alignment {} isn't a power of two",
+                        value
+                    );
+
+                    align = Some(value);
+                }
+                ("align", _) => bail!("the align attribute needs a power of two, like #[align(4)]"),
+                (attr, _) => bail!("unknown function attribute: {:?}", attr),
+            }
+        }
+
+        let func_name = pairs.next().unwrap().as_str();
+
+        let mut params = Vec::new();
+
+        while pairs.peek().map(|pair| pair.as_rule()) == Some(Rule::param) {
+            let mut param = pairs.next().unwrap().into_inner();
+
+            let param_id = param.next().unwrap().as_str();
+            let type_t = self.type_(param.next().unwrap().as_str())?;
+
+            ensure!(
+                params.iter().all(|other: &Param| other.id != param_id),
+                "parameter {} of {} is declared more than once",
+                param_id,
+                func_name
+            );
+
+            params.push(Param {
+                id: param_id.into(),
+                type_t,
+            });
+        }
+
+        let mut instr_vec = Vec::new();
+
+        // A failed function leaves the nesting of its innermost block behind
+        self.nesting = 0;
+
+        for expr in pairs {
+            instr_vec.append(&mut self.exprs(expr)?);
+        }
+
+        Ok(Func {
+            id: func_name.into(),
+            expr: instr_vec,
+            is_inline,
+            is_pure,
+            cfg,
+            params,
+            align,
+        })
+    }
+
+    /// Returns the constant or `None` if it aliases another constant, which is resolved once every
+    /// constant of the module is known
+    fn constant(&mut self, pair: Pair<Rule>, module: &Module) -> Result<Option<Const>> {
+        let mut pairs = pair.into_inner();
+
+        let const_id = pairs.next().unwrap().as_str();
+
+        let value = pairs.next().unwrap();
+        let value = if value.as_rule() == Rule::constant_id {
+            Argument::Constant(value.as_str().to_string())
+        } else {
+            Argument::Literal(literal::parse_i32(value.as_str())?)
+        };
+
+        if !self.is_new_constant(module, const_id, &value)? {
+            return Ok(None);
+        }
+
+        match value {
+            Argument::Constant(target) => {
+                self.const_aliases.insert(const_id.into(), target);
+
+                Ok(None)
+            }
+            Argument::Literal(value) => Ok(Some(Const {
+                id: const_id.into(),
+                value,
+            })),
+        }
+    }
+
+    /// Checks that the module doesn't define the constant yet, either by a value or as the alias
+    /// of another constant. Unless identical constants are allowed, a definition with the same
+    /// value is an error as well. Returns whether the constant is new.
+    fn is_new_constant(
+        &self,
+        module: &Module,
+        const_id: &str,
+        value: &Argument<i32>,
+    ) -> Result<bool> {
+        let previous = match module.constants.iter().find(|cons| cons.id == const_id) {
+            Some(cons) => Argument::Literal(cons.value),
+            None => match self.const_aliases.get(const_id) {
+                Some(target) => Argument::Constant(target.clone()),
+                None => return Ok(true),
+            },
+        };
+
+        let identical = match (&previous, value) {
+            (Argument::Literal(previous), Argument::Literal(value)) => previous == value,
+            (Argument::Constant(previous), Argument::Constant(value)) => previous == value,
+            _ => false,
+        };
+        let allow_identical = self.config
+            .compilation
+            .allow_identical_constants
+            .unwrap_or(defaults::ALLOW_IDENTICAL_CONSTANTS);
+
+        ensure!(
+            identical && allow_identical,
+            "the constant {} is defined more than once in module {:?}{}",
+            const_id,
+            module.id,
+            if identical {
+                ", set allow_identical_constants to allow repeating the same value"
+            } else {
+                ""
+            }
+        );
+
+        Ok(false)
+    }
+
+    /// Adds the aliased constants to the module, following chains of aliases
+    fn resolve_const_aliases(&mut self, module: &mut Module) -> Vec<Error> {
+        let aliases = ::std::mem::take(&mut self.const_aliases);
+        let mut errors = Vec::new();
+
+        for (alias, target) in &aliases {
+            let mut chain = vec![alias];
+            let mut current = target;
+
+            while let Some(next) = aliases.get(current) {
+                if chain.contains(&current) {
+                    break;
+                }
+
+                chain.push(current);
+                current = next;
+            }
+
+            if chain.contains(&current) {
+                chain.push(current);
+                let cycle: Vec<_> = chain.iter().map(|id| id.as_str()).collect();
+
+                errors.push(format_err!(
+                    "constant {:?} aliases itself: {}",
+                    alias,
+                    cycle.join(" -> ")
+                ));
+                continue;
+            }
+
+            match module.constants.iter().find(|cons| cons.id == *current) {
+                Some(cons) => {
+                    let value = cons.value;
+                    module.constants.push(Const {
+                        id: alias.clone(),
+                        value,
+                    });
+                }
+                None => errors.push(format_err!(
+                    "unable to find constant {:?} aliased by {:?}",
+                    current,
+                    alias
+                )),
+            }
+        }
+
+        errors
+    }
+
+    /// Expands a group of constants into constants named `%Group.Member`
+    fn constant_group(
+        &mut self,
+        pair: Pair<Rule>,
+        warnings: &mut Vec<String>,
+    ) -> Result<Vec<Const>> {
+        let mut pairs = pair.into_inner();
+
+        let group_id = pairs.next().unwrap().as_str();
+
+        let mut constants: Vec<Const> = Vec::new();
+
+        for member in pairs {
+            let mut member_pairs = member.into_inner();
+
+            let member_id = member_pairs.next().unwrap().as_str();
+            let raw_lit = member_pairs.next().unwrap().as_str();
+
+            let constant = Const {
+                id: format!("{}.{}", group_id, member_id),
+                value: literal::parse_i32(raw_lit)?,
+            };
+
+            ensure!(
+                constants.iter().all(|c| c.id != constant.id),
+                "duplicate member {:?} in constant group {:?}",
+                member_id,
+                group_id
+            );
+
+            if let Some(other) = constants.iter().find(|c| c.value == constant.value) {
+                warnings.push(format!("{:?} has the same value as {:?}", constant.id, other.id));
+            }
+
+            constants.push(constant);
+        }
+
+        Ok(constants)
+    }
+
+    fn export(&mut self, pair: Pair<Rule>) -> Result<Export> {
+        let mut pairs = pair.into_inner();
+
+        let exported_func = pairs.next().unwrap().as_str();
+
+        let alias = pairs.next().and_then(|e| Some(e.as_str())).or_else(|| None);
+
+        Ok(Export {
+            func_origin_id: exported_func.into(),
+            func_alias_id: alias.unwrap_or(exported_func).into(),
+        })
+    }
+
+    /// Parses an instruction. A push of several values expands into one push per value.
+    fn exprs(&mut self, pair: Pair<Rule>) -> Result<Vec<Located>> {
+        let line = Some(self.line_of(&pair));
+        let located = move |expr| Located { expr, line };
+
+        let plain_instr = pair.clone().into_inner().next().unwrap();
+
+        match plain_instr.as_rule() {
+            Rule::push_instr => {}
+            // Blocks are generated recursively, so their depth is limited to not overflow the stack
+            Rule::while_loop | Rule::if_cond => {
+                let max_nesting = self.config
+                    .compilation
+                    .max_nesting
+                    .unwrap_or(defaults::MAX_NESTING);
+
+                self.nesting += 1;
+                ensure!(
+                    self.nesting <= max_nesting,
+                    "block nesting exceeds {}",
+                    max_nesting
+                );
+
+                let expr = self.expr(pair);
+                self.nesting -= 1;
+
+                return Ok(vec![located(expr?)]);
+            }
+            _ => return Ok(vec![located(self.expr(pair)?)]),
+        }
+
+        let mut inner = plain_instr.into_inner();
+
+        let raw_type = inner.next().unwrap().as_str();
+        let real_type = self.type_(raw_type)?;
+
+        inner
+            .map(|raw_arg| self.push_value(&real_type, raw_arg).map(located))
+            .collect()
+    }
+
+    /// Returns the source line the pair starts at
+    fn line_of(&self, pair: &Pair<Rule>) -> usize {
+        self.first_line + pair.clone().into_span().start_pos().line_col().0
+    }
+
+    fn push_value(&mut self, real_type: &IntegerType, raw_arg: Pair<Rule>) -> Result<Expr> {
+        match raw_arg.as_rule() {
+            Rule::constant_id => {
+                let arg = raw_arg.as_str();
+                let inst = match *real_type {
+                    IntegerType::U8 => Expr::PushConstU8(Argument::Constant(arg.into())),
+                    IntegerType::U16 => Expr::PushConstU16(Argument::Constant(arg.into())),
+                    IntegerType::I8 => Expr::PushConstI8(Argument::Constant(arg.into())),
+                    IntegerType::I16 => Expr::PushConstI16(Argument::Constant(arg.into())),
+                };
+
+                Ok(inst)
+            }
+            Rule::literal => {
+                let arg = raw_arg.as_str();
+                let inst = match *real_type {
+                    IntegerType::U8 => {
+                        Expr::PushConstU8(Argument::Literal(literal::parse_u8(arg)?))
+                    }
+                    IntegerType::U16 => {
+                        Expr::PushConstU16(Argument::Literal(literal::parse_u16(arg)?))
+                    }
+                    IntegerType::I8 => {
+                        Expr::PushConstI8(Argument::Literal(literal::parse_i8(arg)?))
+                    }
+                    IntegerType::I16 => {
+                        Expr::PushConstI16(Argument::Literal(literal::parse_i16(arg)?))
+                    }
+                };
+
+                Ok(inst)
+            }
+            rule => Err(self.unexpected(rule, "a pushed value")),
+        }
+    }
+
+    fn expr(&mut self, pair: Pair<Rule>) -> Result<Expr> {
+        let mut pairs = pair.into_inner();
+
+        let plain_instr = pairs.next().unwrap();
+        let mut inner = plain_instr.clone().into_inner();
+
+        match plain_instr.as_rule() {
+            Rule::add | Rule::sub | Rule::mul | Rule::div => {
+                let op = match plain_instr.as_rule() {
+                    Rule::add => Arithmetic::Add,
+                    Rule::sub => Arithmetic::Sub,
+                    Rule::mul => Arithmetic::Mul,
+                    _ => Arithmetic::Div,
+                };
+
+                match inner.next() {
+                    Some(raw_type) => {
+                        let real_type = self.type_(raw_type.as_str())?;
+                        Ok(Expr::ActualInstr(op.instruction(real_type)))
+                    }
+                    None => Ok(Expr::Untyped(op)),
+                }
+            }
+            Rule::widemul => {
+                let raw_type = inner.next().unwrap().as_str();
+                let real_type = self.type_(raw_type)?;
+
+                ensure!(
+                    lowering::wider(&real_type).is_some(),
+                    "widemul needs a type twice as wide as {}, but there is none",
+                    raw_type
+                );
+
+                Ok(Expr::WideMul(real_type))
+            }
+            Rule::shr => {
+                let raw_type = inner.next().unwrap().as_str();
+                let real_type = self.type_(raw_type)?;
+                Ok(Expr::ActualInstr(Instruction::Shr(real_type)))
+            }
+            Rule::shl => {
+                let raw_type = inner.next().unwrap().as_str();
+                let real_type = self.type_(raw_type)?;
                 Ok(Expr::ActualInstr(Instruction::Shl(real_type)))
             }
             Rule::and => {
                 let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+                let real_type = self.type_(raw_type)?;
                 Ok(Expr::ActualInstr(Instruction::And(real_type)))
             }
             Rule::or => {
                 let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+                let real_type = self.type_(raw_type)?;
                 Ok(Expr::ActualInstr(Instruction::Or(real_type)))
             }
             Rule::xor => {
                 let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+                let real_type = self.type_(raw_type)?;
                 Ok(Expr::ActualInstr(Instruction::Xor(real_type)))
             }
             Rule::not => {
                 let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+                let real_type = self.type_(raw_type)?;
                 Ok(Expr::ActualInstr(Instruction::Not(real_type)))
             }
             Rule::neg => {
                 let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+                let real_type = self.type_(raw_type)?;
                 Ok(Expr::ActualInstr(Instruction::Neg(real_type)))
             }
+            Rule::sadd | Rule::ssub | Rule::smul => {
+                let op = match plain_instr.as_rule() {
+                    Rule::sadd => Saturating::Add,
+                    Rule::ssub => Saturating::Sub,
+                    _ => Saturating::Mul,
+                };
+
+                let raw_type = inner.next().unwrap().as_str();
+                let real_type = self.type_(raw_type)?;
+                Ok(Expr::Saturating(op, real_type))
+            }
+            Rule::popcount => {
+                let raw_type = inner.next().unwrap().as_str();
+                let real_type = self.type_(raw_type)?;
+                Ok(Expr::CountBits(BitCount::Ones, real_type))
+            }
+            Rule::clz => {
+                let raw_type = inner.next().unwrap().as_str();
+                let real_type = self.type_(raw_type)?;
+                Ok(Expr::CountBits(BitCount::LeadingZeros, real_type))
+            }
+            Rule::ctz => {
+                let raw_type = inner.next().unwrap().as_str();
+                let real_type = self.type_(raw_type)?;
+                Ok(Expr::CountBits(BitCount::TrailingZeros, real_type))
+            }
+            Rule::clamp => {
+                let raw_type = inner.next().unwrap().as_str();
+                let real_type = self.type_(raw_type)?;
+
+                let mut bound = || -> Result<Argument<i32>> {
+                    let raw_arg = inner.next().unwrap();
+                    Ok(if raw_arg.as_rule() == Rule::constant_id {
+                        Argument::Constant(raw_arg.as_str().into())
+                    } else {
+                        Argument::Literal(literal::parse_i32(raw_arg.as_str())?)
+                    })
+                };
+                let min = bound()?;
+                let max = bound()?;
+
+                Ok(Expr::Clamp(real_type, min, max))
+            }
             Rule::inc => {
                 let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+                let real_type = self.type_(raw_type)?;
                 Ok(Expr::ActualInstr(Instruction::Inc(real_type)))
             }
             Rule::dec => {
                 let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+                let real_type = self.type_(raw_type)?;
                 Ok(Expr::ActualInstr(Instruction::Dec(real_type)))
             }
             Rule::u8_promote => Ok(Expr::ActualInstr(Instruction::U8Promote)),
@@ -339,18 +1683,25 @@ impl AstGen {
                     self.register(raw_register)?,
                 )))
             }
+            Rule::store_reg => {
+                let raw_register = inner.next().unwrap().as_str();
+                let register = self.register(raw_register)?;
+
+                bail!(
+                    "unable to use store_reg {:?}, melon {} has no instruction to set registers",
+                    register,
+                    ::melon::VERSION
+                )
+            }
             Rule::load => {
                 let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+                let real_type = self.type_(raw_type)?;
 
                 if let Some(raw_arg) = inner.next() {
                     let arg = if raw_arg.as_rule() == Rule::constant_id {
                         Argument::Constant(raw_arg.as_str().into())
                     } else {
-                        let raw_arg = raw_arg.as_str();
-                        Argument::Literal(raw_arg
-                            .parse()
-                            .or_else(|_| u16::from_str_radix(&raw_arg[2..], 16))?)
+                        Argument::Literal(literal::parse_u16(raw_arg.as_str())?)
                     };
 
                     Ok(Expr::Load(real_type, arg))
@@ -360,16 +1711,13 @@ impl AstGen {
             }
             Rule::store => {
                 let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+                let real_type = self.type_(raw_type)?;
 
                 if let Some(raw_arg) = inner.next() {
                     let arg = if raw_arg.as_rule() == Rule::constant_id {
                         Argument::Constant(raw_arg.as_str().into())
                     } else {
-                        let raw_arg = raw_arg.as_str();
-                        Argument::Literal(raw_arg
-                            .parse()
-                            .or_else(|_| u16::from_str_radix(&raw_arg[2..], 16))?)
+                        Argument::Literal(literal::parse_u16(raw_arg.as_str())?)
                     };
 
                     Ok(Expr::Store(real_type, arg))
@@ -377,129 +1725,276 @@ impl AstGen {
                     Ok(Expr::ActualInstr(Instruction::StoreIndirect(real_type)))
                 }
             }
+            Rule::tee => {
+                let raw_type = inner.next().unwrap().as_str();
+                let real_type = self.type_(raw_type)?;
+
+                if let Some(raw_arg) = inner.next() {
+                    let arg = if raw_arg.as_rule() == Rule::constant_id {
+                        Argument::Constant(raw_arg.as_str().into())
+                    } else {
+                        Argument::Literal(literal::parse_u16(raw_arg.as_str())?)
+                    };
+
+                    Ok(Expr::Tee(real_type, arg))
+                } else {
+                    Ok(Expr::TeeIndirect(real_type))
+                }
+            }
             Rule::dup => {
                 let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+                let real_type = self.type_(raw_type)?;
                 Ok(Expr::ActualInstr(Instruction::Dup(real_type)))
             }
             Rule::drop => {
                 let raw_type = inner.next().unwrap().as_str();
-                let real_type = self.type_(raw_type);
+                let real_type = self.type_(raw_type)?;
                 Ok(Expr::ActualInstr(Instruction::Drop(real_type)))
             }
             Rule::sys => {
-                let signal = inner.next().unwrap().as_str();
-                Ok(Expr::Sys(signal.into()))
+                let signal = inner.next().unwrap();
+
+                if signal.as_rule() == Rule::integer_literal {
+                    let id = literal::parse_u16(signal.as_str())?;
+                    return Ok(Expr::ActualInstr(Instruction::SysCall(id)));
+                }
+
+                let name = &signal.as_str()[1..];
+                ensure!(
+                    name == "halt" || self.config.signals.contains_key(name),
+                    "undefined signal {:?}. Available signals are {:?}",
+                    name,
+                    self.config.signals.keys().collect::<Vec<_>>()
+                );
+
+                Ok(Expr::Sys(signal.as_str().into()))
             }
             Rule::call => {
                 let func_id = inner.next().unwrap().as_str();
                 Ok(Expr::Call(func_id.into()))
             }
             Rule::ret => Ok(Expr::ActualInstr(Instruction::Ret)),
+            Rule::trap => Ok(Expr::Trap),
+            Rule::break_loop => Ok(Expr::Break(inner.next().map(|label| label.as_str().into()))),
+            Rule::continue_loop => {
+                Ok(Expr::Continue(inner.next().map(|label| label.as_str().into())))
+            }
             Rule::alloc => {
-                let raw_num_const = inner.next().unwrap();
+                let mut raw_num_const = inner.next().unwrap();
+
+                let name = if raw_num_const.as_rule() == Rule::alloc_name {
+                    let name = raw_num_const.as_str().to_string();
+                    raw_num_const = inner.next().unwrap();
+
+                    Some(name)
+                } else {
+                    None
+                };
 
                 let arg = if raw_num_const.as_rule() == Rule::constant_id {
                     Argument::Constant(raw_num_const.as_str().into())
                 } else {
-                    let raw_arg = raw_num_const.as_str();
-
-                    Argument::Literal(raw_arg
-                        .parse()
-                        .or_else(|_| u16::from_str_radix(&raw_arg[2..], 16))?)
+                    Argument::Literal(literal::parse_u16(raw_num_const.as_str())?)
                 };
 
-                Ok(Expr::Alloc(arg))
+                Ok(Expr::Alloc(arg, name))
             }
-            Rule::free => Ok(Expr::ActualInstr(Instruction::Free)),
+            Rule::memcpy => Ok(Expr::MemCopy),
+            Rule::memset => Ok(Expr::MemSet),
+            Rule::free => Ok(Expr::Free(inner.next().map(|name| name.as_str().into()))),
             Rule::while_loop => {
-                let cond = inner.next().unwrap();
-
-                let condition = match cond.as_rule() {
-                    Rule::greater => Condition::Greater,
-                    Rule::less => Condition::Less,
-                    Rule::greater_equal => Condition::GreaterOrEqual,
-                    Rule::less_equal => Condition::LessOrEqual,
-                    Rule::equal => Condition::Equal,
-                    Rule::unequal => Condition::NotEqual,
-                    _ => unreachable!(),
+                let label = match inner.clone().next() {
+                    Some(ref pair) if pair.as_rule() == Rule::loop_label => {
+                        inner.next();
+                        Some(pair.as_str().into())
+                    }
+                    _ => None,
                 };
 
+                let cond = inner.next().unwrap();
+                let condition = self.condition(cond)?;
+
                 let type_t = inner.next().unwrap().as_str();
-                let real_type = self.type_(type_t);
+                let real_type = self.type_(type_t)?;
+
+                let immediate = AstGen::immediate(&mut inner)?;
+
+                let max = match inner.clone().next() {
+                    Some(ref pair) if pair.as_rule() == Rule::loop_max => {
+                        inner.next();
+
+                        let raw_max = pair.clone().into_inner().next().unwrap();
+                        Some(if raw_max.as_rule() == Rule::constant_id {
+                            Argument::Constant(raw_max.as_str().into())
+                        } else {
+                            Argument::Literal(literal::parse_u16(raw_max.as_str())?)
+                        })
+                    }
+                    _ => None,
+                };
 
                 let mut instr_vec = Vec::new();
 
                 for expr in inner {
-                    let expr = self.expr(expr)?;
-
-                    instr_vec.push(expr);
+                    instr_vec.append(&mut self.exprs(expr)?);
                 }
 
                 Ok(Expr::While(While {
+                    label,
                     cond: condition,
                     type_t: real_type,
+                    immediate,
+                    max,
                     exprs: instr_vec,
                 }))
             }
             Rule::if_cond => {
                 let cond = inner.next().unwrap();
-
-                let condition = match cond.as_rule() {
-                    Rule::greater => Condition::Greater,
-                    Rule::less => Condition::Less,
-                    Rule::greater_equal => Condition::GreaterOrEqual,
-                    Rule::less_equal => Condition::LessOrEqual,
-                    Rule::equal => Condition::Equal,
-                    Rule::unequal => Condition::NotEqual,
-                    _ => unreachable!(),
-                };
+                let condition = self.condition(cond)?;
 
                 let type_t = inner.next().unwrap().as_str();
-                let real_type = self.type_(type_t);
+                let real_type = self.type_(type_t)?;
 
-                let mut instr_vec = Vec::new();
+                let immediate = AstGen::immediate(&mut inner)?;
 
+                let mut instr_vec = Vec::new();
+                let mut else_ifs = Vec::new();
                 let mut else_branch = None;
 
                 for expr in inner {
-                    if expr.as_rule() == Rule::else_cond {
-                        let mut else_instr_vec = Vec::new();
+                    match expr.as_rule() {
+                        Rule::else_if_cond => {
+                            let line = self.line_of(&expr);
+                            let mut else_if = expr.into_inner();
 
-                        for expr in expr.into_inner() {
-                            let expr = self.expr(expr)?;
+                            let cond = else_if.next().unwrap();
+                            let condition = self.condition(cond)?;
 
-                            else_instr_vec.push(expr);
+                            let type_t = else_if.next().unwrap().as_str();
+                            let real_type = self.type_(type_t)?;
+
+                            let mut else_if_instr_vec = Vec::new();
+
+                            for expr in else_if {
+                                else_if_instr_vec.append(&mut self.exprs(expr)?);
+                            }
+
+                            ensure!(
+                                immediate.is_none(),
+                                "an else if can't follow a condition with an immediate, because \
+                                 the compared value is taken off the stack"
+                            );
+
+                            else_ifs.push((condition, real_type, else_if_instr_vec, line));
                         }
+                        Rule::else_cond => {
+                            let mut else_instr_vec = Vec::new();
 
-                        else_branch = Some(else_instr_vec);
-                        break;
-                    }
+                            for expr in expr.into_inner() {
+                                else_instr_vec.append(&mut self.exprs(expr)?);
+                            }
 
-                    let expr = self.expr(expr)?;
+                            else_branch = Some(else_instr_vec);
+                        }
+                        _ => {
+                            instr_vec.append(&mut self.exprs(expr)?);
+                        }
+                    }
+                }
 
-                    instr_vec.push(expr);
+                // Every `else if` becomes an `if` nested in the else branch of the previous one.
+                // The compared values stay on the stack, so the nested comparison sees the same
+                // values.
+                for (cond, type_t, exprs, line) in else_ifs.into_iter().rev() {
+                    let expr = Expr::If(If {
+                        cond,
+                        type_t,
+                        immediate: None,
+                        exprs,
+                        else_exprs: else_branch,
+                    });
+
+                    else_branch = Some(vec![Located {
+                        expr,
+                        line: Some(line),
+                    }]);
                 }
 
                 Ok(Expr::If(If {
                     cond: condition,
                     type_t: real_type,
+                    immediate,
                     exprs: instr_vec,
                     else_exprs: else_branch,
                 }))
             }
-            _ => unreachable!(),
+            Rule::assert => {
+                let cond = inner.next().unwrap();
+                let condition = self.condition(cond)?;
+
+                let type_t = inner.next().unwrap().as_str();
+                let real_type = self.type_(type_t)?;
+
+                Ok(Expr::Assert(condition, real_type))
+            }
+            rule => Err(self.unexpected(rule, "an expression")),
         }
     }
 
-    fn type_(&mut self, raw: &str) -> IntegerType {
-        match raw {
+    /// Takes the immediate of a condition from the given pairs if there is one
+    fn immediate(pairs: &mut Pairs<Rule>) -> Result<Option<Argument<i32>>> {
+        let immediate = match pairs.clone().next() {
+            Some(ref pair) if pair.as_rule() == Rule::constant_id => {
+                Argument::Constant(pair.as_str().into())
+            }
+            Some(ref pair) if pair.as_rule() == Rule::integer_literal => {
+                Argument::Literal(literal::parse_i32(pair.as_str())?)
+            }
+            _ => return Ok(None),
+        };
+
+        pairs.next();
+        Ok(Some(immediate))
+    }
+
+    fn condition(&mut self, pair: Pair<Rule>) -> Result<Condition> {
+        let condition = match pair.as_rule() {
+            Rule::greater => Condition::Greater,
+            Rule::less => Condition::Less,
+            Rule::greater_equal => Condition::GreaterOrEqual,
+            Rule::less_equal => Condition::LessOrEqual,
+            Rule::equal => Condition::Equal,
+            Rule::unequal => Condition::NotEqual,
+            rule => return Err(self.unexpected(rule, "a condition")),
+        };
+
+        Ok(condition)
+    }
+
+    fn type_(&mut self, raw: &str) -> Result<IntegerType> {
+        let type_t = match raw {
             "u8" => IntegerType::U8,
             "u16" => IntegerType::U16,
             "i8" => IntegerType::I8,
             "i16" => IntegerType::I16,
-            _ => unreachable!(),
-        }
+            _ => bail!(
+                "internal compiler error: unexpected type {:?} in module {:?}",
+                raw,
+                self.module_id
+            ),
+        };
+
+        Ok(type_t)
+    }
+
+    /// The error for a rule the grammar produced where the code generation doesn't expect it
+    fn unexpected(&self, rule: Rule, place: &str) -> Error {
+        format_err!(
+            "internal compiler error: unexpected rule {:?} in {} of module {:?}",
+            rule,
+            place,
+            self.module_id
+        )
     }
 
     fn register(&mut self, raw: &str) -> Result<Register> {
@@ -517,28 +2012,1442 @@ impl AstGen {
     }
 
     fn discover_module(&mut self, module: String) -> Result<PathBuf> {
-        let orig_module = module.clone();
+        let found_modules = self.module_files(&module);
+
+        let unique_modules = self.config
+            .compilation
+            .unique_modules
+            .unwrap_or(defaults::UNIQUE_MODULES);
+
+        ensure!(
+            !unique_modules || found_modules.len() <= 1,
+            "module {:?} is defined by more than one file: {:?}",
+            module,
+            found_modules
+        );
+
+        let module_file = match found_modules.into_iter().next() {
+            Some(module_file) => module_file,
+            None => bail!("unable to find module: {:?}", module),
+        };
 
-        let base_path: PathBuf = orig_module.split('.').collect();
+        if module_file.extension() == Some(SOURCE_FILE_EXTENSIONS[1].as_ref()) {
+            let strict_extensions = self.config
+                .compilation
+                .strict_extensions
+                .unwrap_or(defaults::STRICT_EXTENSIONS);
+
+            ensure!(
+                !strict_extensions,
+                "unable to find module: {:?} ({:?} is ignored because only .{} files are allowed)",
+                module,
+                module_file,
+                SOURCE_FILE_EXTENSIONS[0]
+            );
+        }
 
-        let beast_module_name = base_path.with_extension(SOURCE_FILE_EXTENSIONS[0]);
+        Ok(module_file)
+    }
 
-        let bst_module_name = base_path.with_extension(SOURCE_FILE_EXTENSIONS[1]);
+    /// Returns every file the module could be loaded from, long extensions first. The include paths
+    /// are searched before the library paths.
+    fn module_files(&self, module: &str) -> Vec<PathBuf> {
+        let base_path: PathBuf = module.split('.').collect();
 
-        let found_module = self.include
+        SOURCE_FILE_EXTENSIONS
             .iter()
-            .map(|include| PathBuf::from(include).join(beast_module_name.clone()))
-            .chain(
+            .flat_map(|extension| {
+                let module_name = base_path.with_extension(extension);
+
                 self.include
                     .iter()
-                    .map(|include| PathBuf::from(include).join(bst_module_name.clone())),
-            )
-            .find(|include| include.exists());
+                    .chain(&self.lib)
+                    .map(move |dir| PathBuf::from(dir).join(&module_name))
+            })
+            .filter(|path| path.exists())
+            .collect()
+    }
+}
+
+/// Assembles the generation of an AST without a manifest. Settings left unset keep their
+/// defaults, e.g. the module `main` as the root and `lib` and `src` as the search paths.
+#[derive(Default)]
+pub struct AstGenBuilder {
+    config: Option<Config>,
+    root_module: Option<String>,
+    lib_paths: Option<Vec<String>>,
+    include_paths: Option<Vec<String>>,
+    signals: Option<BTreeMap<String, Signal>>,
+    jobs: Option<usize>,
+}
+
+impl AstGenBuilder {
+    pub fn new() -> AstGenBuilder {
+        AstGenBuilder::default()
+    }
+
+    /// Starts with the settings of the given config instead of the defaults
+    pub fn config(mut self, config: Config) -> AstGenBuilder {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn root_module<S: Into<String>>(mut self, root_module: S) -> AstGenBuilder {
+        self.root_module = Some(root_module.into());
+        self
+    }
+
+    /// The paths to look for libraries, searched after the include paths
+    pub fn lib_paths(mut self, lib_paths: Vec<String>) -> AstGenBuilder {
+        self.lib_paths = Some(lib_paths);
+        self
+    }
+
+    /// The paths to look for modules, searched before the default one
+    pub fn include_paths(mut self, include_paths: Vec<String>) -> AstGenBuilder {
+        self.include_paths = Some(include_paths);
+        self
+    }
+
+    /// The signals the modules may send, replacing those of the config
+    pub fn signals(mut self, signals: BTreeMap<String, Signal>) -> AstGenBuilder {
+        self.signals = Some(signals);
+        self
+    }
+
+    /// The number of modules parsed at the same time
+    pub fn jobs(mut self, jobs: usize) -> AstGenBuilder {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    /// Generates the AST of the root module and every module it depends on
+    pub fn build(self) -> Result<Ast> {
+        let mut config = self.config.unwrap_or_else(|| Config {
+            program: Program {
+                name: defaults::BIN_ENTRY_POINT_MODULE.into(),
+                target_version: ::melon::VERSION.into(),
+                system_id: defaults::SYSTEM_ID.into(),
+                mem_pages: None,
+                memory: Memory::default(),
+            },
+            compilation: Compilation::default(),
+            signals: BTreeMap::new(),
+            targets: BTreeMap::new(),
+        });
+
+        if let Some(lib_paths) = self.lib_paths {
+            config.compilation.lib_dirs = lib_paths;
+        }
+        if let Some(include_paths) = self.include_paths {
+            config.compilation.include_dirs = include_paths;
+        }
+        if let Some(signals) = self.signals {
+            config.signals = signals;
+        }
+        if self.jobs.is_some() {
+            config.compilation.jobs = self.jobs;
+        }
+
+        config.validate()?;
+
+        let root_module = self.root_module
+            .or_else(|| config.compilation.entry_point.clone())
+            .unwrap_or_else(|| defaults::BIN_ENTRY_POINT_MODULE.into());
+
+        AstGen::new(config).ast(root_module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FunctionOrder;
+    use std::time::Duration;
+    use test_utils::{fixture_config, test_config};
+
+    #[test]
+    fn constants_accept_radix_literals() {
+        let ast = AstGen::gen("main".into(), fixture_config("constants")).unwrap();
+
+        let constants = &ast.modules["main"].constants;
+        assert_eq!(constants[0].id, "%MASK");
+        assert_eq!(constants[0].value, 0b1111_0000);
+        assert_eq!(constants[1].value, 0x10);
+    }
+
+    #[test]
+    fn alloc_accepts_radix_literals() {
+        let ast = AstGen::gen("main".into(), fixture_config("alloc")).unwrap();
 
-        if let Some(module_id) = found_module {
-            return Ok(module_id);
+        match ast.modules["main"].funcs[0].expr[0].expr {
+            Expr::Alloc(Argument::Literal(amount), None) => assert_eq!(amount, 256),
+            ref expr => panic!("unexpected expression {:?}", expr),
         }
+    }
+
+    #[test]
+    fn parser_recovers_at_item_boundaries() {
+        let source = include_str!("../test/fixtures/recovery/main.beast");
+
+        let mut ast_gen = AstGen::new(fixture_config("recovery"));
+        let (module, errors) = ast_gen.parse_module("main".into(), source);
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("3:"));
+
+        let func_ids: Vec<_> = module.funcs.iter().map(|func| func.id.as_str()).collect();
+        assert_eq!(func_ids, vec!["$good", "$main"]);
+    }
+
+    #[test]
+    fn imports_strip_source_extensions() {
+        let ast = AstGen::gen("main".into(), fixture_config("import_extension")).unwrap();
+
+        let imports = &ast.modules["main"].imports;
+        assert_eq!(imports[0].module_id, "util");
+        assert_eq!(imports[1].module_id, "util");
+        assert!(ast.modules.contains_key("util"));
+
+        let warnings = ast.warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("\"util.beast\""));
+        assert!(warnings[1].contains("\"util.bst\""));
+    }
+
+    #[test]
+    fn profiling_reports_every_module() {
+        let (ast, report) =
+            AstGen::gen_profiled("main".into(), fixture_config("public_api")).unwrap();
+
+        let profiled: Vec<_> = report.modules.keys().collect();
+        let parsed: Vec<_> = ast.modules.keys().collect();
+        assert_eq!(profiled, parsed);
+        assert_eq!(report.modules.values().sum::<Duration>(), report.parse);
+    }
+
+    #[test]
+    fn constant_groups_warn_about_duplicate_values() {
+        let ast = AstGen::gen("main".into(), fixture_config("constant_group")).unwrap();
+
+        let ids: Vec<_> = ast.modules["main"]
+            .constants
+            .iter()
+            .map(|c| c.id.as_str())
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                "%Color.Red",
+                "%Color.Green",
+                "%Color.Blue",
+                "%Level.Low",
+                "%Level.Default",
+            ]
+        );
+
+        let warnings = ast.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("\"%Level.Default\" has the same value as \"%Level.Low\""));
+    }
+
+    #[test]
+    fn worker_panics_become_errors() {
+        struct Exploding;
+
+        impl SourceProvider for Exploding {
+            fn load(&self, module: &str) -> Result<Option<String>> {
+                match module {
+                    "main" => Ok(Some("(import $scale from util)\n(func $main)".into())),
+                    _ => panic!("worker exploded"),
+                }
+            }
+        }
+
+        // The panic takes down the worker parsing `util`, not the generation waiting for it
+        let err = AstGen::with_sources(test_config(), Exploding)
+            .ast("main".into())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to compile module \"util\" imported by \"main\"\n\
+             internal compiler error: worker exploded"
+        );
+    }
+
+    #[test]
+    fn lost_worker_results_are_reported() {
+        let mut compiler = AstGen::new(fixture_config("public_api"));
+        let (instructor_sender, instructor_receiver) = mpsc::channel();
+        let mut stream = compiler.module_stream("main".into(), instructor_sender);
+
+        // A worker finishing the root module, but giving up on the module it imports
+        let pending = instructor_receiver.recv().unwrap();
+        pending.finish(compiler.module("main".into()));
+        assert_eq!(stream.next().unwrap().unwrap().0, "main");
+
+        drop(instructor_receiver.recv().unwrap());
+
+        let err = stream.next().unwrap().unwrap_err().to_string();
+        assert!(stream.next().is_none());
+        assert_eq!(
+            err,
+            "failed to compile module \"api\" imported by \"main\"\n\
+             no result arrived for the module \"api\""
+        );
+    }
+
+    #[test]
+    fn malformed_constant_literals_are_errors() {
+        let mut ast_gen = AstGen::new(fixture_config("constants"));
+
+        for source in &["(const %X 0x)", "(const %X 0b)", "(const %X -0o)", "(const %X 0xG)"] {
+            let (module, errors) = ast_gen.parse_module("main".into(), source);
+
+            assert!(module.constants.is_empty());
+            assert_eq!(errors.len(), 1, "{}", source);
+        }
+
+        let (_, errors) = ast_gen.parse_module("main".into(), "(const %X 0x)");
+        assert_eq!(errors[0].to_string(), "missing digits in literal \"0x\"");
+    }
+
+    #[test]
+    fn failed_modules_report_their_importer() {
+        let err = AstGen::gen("main".into(), fixture_config("broken_leaf")).unwrap_err();
+
+        let message = err.to_string();
+        assert!(message.starts_with("failed to compile module \"leaf\" imported by \"middle\""));
+    }
+
+    #[test]
+    fn short_extensions_warn_or_fail_when_strict() {
+        let ast = AstGen::gen("main".into(), fixture_config("short_extension")).unwrap();
+
+        let warnings = ast.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("util.bst"));
+
+        let mut config = fixture_config("short_extension");
+        config.compilation.strict_extensions = Some(true);
+
+        let message = AstGen::gen("main".into(), config).unwrap_err().to_string();
+        assert!(message.contains("unable to find module: \"util\""));
+    }
+
+    #[test]
+    fn passes_run_over_the_generated_ast() {
+        // A jump to the next instruction does nothing
+        let mut append_nop = |ast: &mut Ast| {
+            for module in ast.modules.values_mut() {
+                for func in &mut module.funcs {
+                    func.expr.push(Expr::ActualInstr(Instruction::Jmp(true, 1)).into());
+                }
+            }
+
+            Ok(())
+        };
+
+        let ast =
+            AstGen::gen_with_pass("main".into(), fixture_config("public_api"), &mut append_nop)
+                .unwrap();
+
+        let funcs: Vec<_> = ast.modules.values().flat_map(|module| &module.funcs).collect();
+        assert_eq!(funcs.len(), 3);
+        assert!(funcs.iter().all(|func| matches!(
+            func.expr.last().map(|located| &located.expr),
+            Some(Expr::ActualInstr(Instruction::Jmp(true, 1)))
+        )));
+
+        let mut failing = |_: &mut Ast| bail!("pass failed");
+        let res = AstGen::gen_with_pass("main".into(), fixture_config("public_api"), &mut failing);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn widemul_needs_a_wider_type() {
+        let mut ast_gen = AstGen::new(fixture_config("constants"));
+
+        let (module, errors) = ast_gen.parse_module("main".into(), "(func $f (widemul i8))");
+        assert!(errors.is_empty());
+        assert!(matches!(module.funcs[0].expr[0].expr, Expr::WideMul(IntegerType::I8)));
+
+        let (_, errors) = ast_gen.parse_module("main".into(), "(func $f (widemul u16))");
+        assert_eq!(
+            errors[0].to_string(),
+            "widemul needs a type twice as wide as u16, but there is none"
+        );
+    }
+
+    #[test]
+    fn else_if_chains_nest_into_else_branches() {
+        let source = include_str!("../test/fixtures/else_if/main.beast");
+
+        let mut ast_gen = AstGen::new(fixture_config("else_if"));
+        let (module, errors) = ast_gen.parse_module("main".into(), source);
+        assert!(errors.is_empty());
+
+        let first = match module.funcs[0].expr[0].expr {
+            Expr::If(ref first) => first.clone(),
+            ref expr => panic!("unexpected expression {:?}", expr),
+        };
+        assert!(matches!(first.cond, Condition::Greater));
+
+        let second = match first.else_exprs.as_ref().map(|exprs| &exprs[..]) {
+            Some([Located {
+                expr: Expr::If(second),
+                ..
+            }]) => second.clone(),
+            exprs => panic!("unexpected else branch {:?}", exprs),
+        };
+        assert!(matches!(second.cond, Condition::Equal));
+        assert_eq!(second.exprs.len(), 2);
+        assert_eq!(second.else_exprs.map(|exprs| exprs.len()), Some(2));
+    }
+
+    #[test]
+    fn unbalanced_named_allocations_are_reported() {
+        let ast = AstGen::gen("main".into(), fixture_config("alloc_names")).unwrap();
+
+        assert_eq!(
+            ast.warnings(),
+            vec![
+                "module \"main\": in $leak: buf allocated but never freed",
+                "module \"main\": in $mixed: a freed while b is the most recent allocation",
+                "module \"main\": in $mixed: b freed while a is the most recent allocation",
+                "module \"main\": in $mixed: c freed but never allocated",
+            ]
+        );
+    }
+
+    #[test]
+    fn modules_can_be_read_as_tokens() {
+        let tokens = AstGen::tokens("main".into(), fixture_config("else_if")).unwrap();
+
+        let source = include_str!("../test/fixtures/else_if/main.beast");
+        assert_eq!(tokens.to_string(), source);
+    }
+
+    #[test]
+    fn pushes_expand_into_one_push_per_value() {
+        let mut ast_gen = AstGen::new(fixture_config("constants"));
+
+        let source = "(const %X 3)(func $f (push u8 1 0x2 %X) (push i16 -4))";
+        let (module, errors) = ast_gen.parse_module("main".into(), source);
+        assert!(errors.is_empty());
+
+        let exprs = &module.funcs[0].expr;
+        assert_eq!(exprs.len(), 4);
+        assert!(matches!(exprs[0].expr, Expr::PushConstU8(Argument::Literal(1))));
+        assert!(matches!(exprs[1].expr, Expr::PushConstU8(Argument::Literal(2))));
+        assert!(matches!(
+            exprs[2].expr,
+            Expr::PushConstU8(Argument::Constant(ref id)) if id == "%X"
+        ));
+        assert!(matches!(exprs[3].expr, Expr::PushConstI16(Argument::Literal(-4))));
+    }
+
+    #[test]
+    fn condition_type_mismatches_warn() {
+        let ast = AstGen::gen("main".into(), fixture_config("condition_types")).unwrap();
+
+        assert_eq!(
+            ast.warnings(),
+            vec![
+                "module \"main\": in $mismatch: condition compares the values as i16 but a u8 \
+                 value is on the stack",
+                "module \"main\": in $promoted: condition compares the values as u8 but a u16 \
+                 value is on the stack",
+            ]
+        );
+    }
+
+    #[test]
+    fn alignments_are_powers_of_two() {
+        let source = "#[align(16)]\n(func $fast (push u8 1) (drop u8))\n#[align(12)]\n(func $odd)";
+        let (module, errors) =
+            AstGen::new(fixture_config("align")).parse_module("main".into(), source);
+
+        assert_eq!(module.funcs[0].align, Some(16));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "the alignment 12 isn't a power of two");
+    }
+
+    #[test]
+    fn trailing_comments_are_ignored() {
+        let mut ast_gen = AstGen::new(fixture_config("trailing_comments"));
+
+        let commented = ast_gen.module("main".into()).unwrap();
+        let plain = ast_gen.module("plain".into()).unwrap();
 
-        bail!("unable to find module: {:?}", module)
+        assert_eq!(commented.funcs[0].size(), 6);
+        assert_eq!(commented.fingerprint(), Module { id: "main".into(), ..plain }.fingerprint());
+    }
+
+    #[test]
+    fn streamed_modules_match_the_ast() {
+        let config = fixture_config("reachability");
+        let ast = AstGen::gen("main".into(), config.clone()).unwrap();
+
+        let mut streamed: Vec<_> = AstGen::gen_streaming("main".into(), config)
+            .map(|module| module.unwrap().0)
+            .collect();
+        streamed.sort();
+
+        let ids: Vec<_> = ast.modules.keys().cloned().collect();
+        assert_eq!(streamed, ids);
+
+        let mut failing = AstGen::gen_streaming("main".into(), fixture_config("broken_leaf"));
+        assert!(failing.any(|module| module.is_err()));
+        assert!(failing.next().is_none());
+    }
+
+    #[test]
+    fn instruction_keywords_match_the_grammar() {
+        let grammar = fs::read_to_string("src/beast.pest").unwrap();
+
+        let keyword_of = |rule: &str| {
+            let prefix = format!("{}_keyword = _{{ \"", rule);
+            let line = grammar
+                .lines()
+                .find(|line| line.starts_with(&prefix))
+                .unwrap_or_else(|| panic!("no keyword for {}", rule));
+
+            line[prefix.len()..line.len() - 3].to_string()
+        };
+
+        let plain_instr = grammar.split("plain_instr = _{").nth(1).unwrap();
+        let alternatives = plain_instr[..plain_instr.find("closing_brace").unwrap()]
+            .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .filter(|rule| !rule.is_empty() && *rule != "opening_brace")
+            // Only parsed to report that it can't be used
+            .filter(|rule| *rule != "store_reg")
+            .map(&keyword_of);
+
+        let mut keywords: Vec<_> = alternatives
+            .chain(["while_loop", "if_cond", "else_cond"].iter().map(|rule| keyword_of(rule)))
+            .collect();
+        keywords.sort();
+
+        let mut listed: Vec<_> = INSTRUCTIONS.iter().map(|kw| kw.to_string()).collect();
+        listed.sort();
+
+        assert_eq!(keywords, listed);
+    }
+
+    #[test]
+    fn lazy_modules_skip_modules_that_are_never_called() {
+        let mut config = fixture_config("lazy_modules");
+        assert!(AstGen::gen("main".into(), config.clone()).is_err());
+
+        config.compilation.lazy_modules = Some(true);
+        let ast = AstGen::gen("main".into(), config).unwrap();
+
+        let modules: Vec<_> = ast.modules.keys().map(|id| id.as_str()).collect();
+        assert_eq!(modules, vec!["big", "helpers", "main"]);
+
+        let big_funcs: Vec<_> = ast.modules["big"]
+            .funcs
+            .iter()
+            .map(|func| func.id.as_str())
+            .collect();
+        assert_eq!(big_funcs, vec!["$answer"]);
+    }
+
+    #[test]
+    fn constants_can_alias_other_constants() {
+        let ast = AstGen::gen("main".into(), fixture_config("const_alias")).unwrap();
+
+        let value_of = |id: &str| {
+            ast.modules["main"]
+                .constants
+                .iter()
+                .find(|cons| cons.id == id)
+                .map(|cons| cons.value)
+        };
+        assert_eq!(value_of("%SCREEN_BASE"), Some(0x0200));
+        assert_eq!(value_of("%ORIGIN"), Some(0x0200));
+
+        let source = "(const %A %B)\n(const %B %A)\n(const %C %MISSING)";
+        let (_, errors) =
+            AstGen::new(fixture_config("const_alias")).parse_module("main".into(), source);
+        let messages: Vec<_> = errors.iter().map(|err| err.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "constant \"%A\" aliases itself: %A -> %B -> %A",
+                "constant \"%B\" aliases itself: %B -> %A -> %B",
+                "unable to find constant \"%MISSING\" aliased by \"%C\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors_list_the_expected_rules() {
+        let source = fs::read_to_string("test/fixtures/truncated/main.beast").unwrap();
+        let (_, errors) =
+            AstGen::new(fixture_config("truncated")).parse_module("main".into(), &source);
+        assert_eq!(errors.len(), 1);
+
+        let err = errors[0].downcast_ref::<ParseError>().unwrap();
+        assert_eq!((err.line, err.col), (2, 11));
+        assert_eq!(err.expected, vec!["constant_id", "literal"]);
+
+        let diagnostic = err.to_diagnostic();
+        assert_eq!(diagnostic.location, Some((2, 11)));
+        assert_eq!(diagnostic.expected, err.expected);
+    }
+
+    #[test]
+    fn instructions_accept_binary_and_octal_literals() {
+        let source = "(func $main (push u8 0b1010 0o17) (push i16 -0b11) (load u8 0o20) \
+                      (store u16 0b1_0000) (alloc 0o10))";
+        let (module, errors) =
+            AstGen::new(fixture_config("recovery")).parse_module("main".into(), source);
+        assert!(errors.is_empty());
+
+        let exprs: Vec<_> = module.funcs[0]
+            .expr
+            .iter()
+            .map(|located| format!("{:?}", located.expr))
+            .collect();
+        assert_eq!(
+            exprs,
+            vec![
+                "PushConstU8(Literal(10))",
+                "PushConstU8(Literal(15))",
+                "PushConstI16(Literal(-3))",
+                "Load(U8, Literal(16))",
+                "Store(U16, Literal(16))",
+                "Alloc(Literal(8), None)",
+            ]
+        );
+
+        let (_, errors) = AstGen::new(fixture_config("recovery"))
+            .parse_module("main".into(), "(func $main (push u8 0b1_0000_0000))");
+        assert_eq!(
+            errors[0].to_string(),
+            "literal \"0b1_0000_0000\" does not fit into u8"
+        );
+    }
+
+    #[test]
+    fn tee_addresses_are_integer_literals() {
+        let source = "(func $main (tee u8 16) (tee u16 0x1_000) (tee i8 %ADDR) (tee u8))";
+        let (module, errors) =
+            AstGen::new(fixture_config("recovery")).parse_module("main".into(), source);
+        assert!(errors.is_empty());
+
+        let exprs: Vec<_> = module.funcs[0]
+            .expr
+            .iter()
+            .map(|located| format!("{:?}", located.expr))
+            .collect();
+        assert_eq!(
+            exprs,
+            vec![
+                "Tee(U8, Literal(16))",
+                "Tee(U16, Literal(4096))",
+                "Tee(I8, Constant(\"%ADDR\"))",
+                "TeeIndirect(U8)",
+            ]
+        );
+
+        let (_, errors) = AstGen::new(fixture_config("recovery"))
+            .parse_module("main".into(), "(func $main (tee u8 0x1_0000))");
+        assert_eq!(
+            errors[0].to_string(),
+            "literal \"0x1_0000\" does not fit into u16"
+        );
+    }
+
+    #[test]
+    fn instructions_accept_digit_separators() {
+        let source = "(func $main (push u16 65_535 0xFF_FF) (load u16 0x01_00) (store u8 1_024) \
+                      (alloc 0b1000_0000))";
+        let (module, errors) =
+            AstGen::new(fixture_config("recovery")).parse_module("main".into(), source);
+        assert!(errors.is_empty());
+
+        let exprs: Vec<_> = module.funcs[0]
+            .expr
+            .iter()
+            .map(|located| format!("{:?}", located.expr))
+            .collect();
+        assert_eq!(
+            exprs,
+            vec![
+                "PushConstU16(Literal(65535))",
+                "PushConstU16(Literal(65535))",
+                "Load(U16, Literal(256))",
+                "Store(U8, Literal(1024))",
+                "Alloc(Literal(128), None)",
+            ]
+        );
+
+        for source in &["(func $main (push u8 1_))", "(func $main (load u8 0x_10))"] {
+            let (_, errors) =
+                AstGen::new(fixture_config("recovery")).parse_module("main".into(), source);
+            assert!(errors[0].to_string().starts_with("misplaced digit separator"));
+        }
+    }
+
+    #[test]
+    fn signed_pushes_accept_negative_hex_literals() {
+        let source = "(func $main (push i8 -0x7F -0x80) (push i16 -0x8000 +0x7FFF))";
+        let (module, errors) =
+            AstGen::new(fixture_config("recovery")).parse_module("main".into(), source);
+        assert!(errors.is_empty());
+
+        let exprs: Vec<_> = module.funcs[0]
+            .expr
+            .iter()
+            .map(|located| format!("{:?}", located.expr))
+            .collect();
+        assert_eq!(
+            exprs,
+            vec![
+                "PushConstI8(Literal(-127))",
+                "PushConstI8(Literal(-128))",
+                "PushConstI16(Literal(-32768))",
+                "PushConstI16(Literal(32767))",
+            ]
+        );
+
+        let (_, errors) = AstGen::new(fixture_config("recovery"))
+            .parse_module("main".into(), "(func $main (push i8 -0x81))");
+        assert_eq!(errors[0].to_string(), "literal \"-0x81\" does not fit into i8");
+    }
+
+    #[test]
+    fn parse_errors_point_into_the_file() {
+        let err = AstGen::gen("main".into(), fixture_config("truncated")).unwrap_err();
+        let err = err.causes()
+            .filter_map(|cause| cause.downcast_ref::<ParseError>())
+            .next()
+            .unwrap();
+
+        let path = PathBuf::from("test/fixtures/truncated/main.beast");
+        assert_eq!(err.path, Some(path));
+        assert_eq!((err.line, err.col), (2, 11));
+        assert_eq!(err.snippet, "  (push u8");
+        assert!(err.to_string()
+            .contains("--> test/fixtures/truncated/main.beast:2:11\n"));
+    }
+
+    #[test]
+    fn while_loops_record_their_iteration_cap() {
+        let source = "(func $main (push u8 0) (while (== u8 0) max:1000 (push u8 0)))";
+        let (module, errors) =
+            AstGen::new(fixture_config("loop_max")).parse_module("main".into(), source);
+        assert!(errors.is_empty());
+
+        match module.funcs[0].expr[1].expr {
+            Expr::While(ref whl) => assert!(matches!(whl.max, Some(Argument::Literal(1000)))),
+            ref expr => panic!("expected a while loop, found {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn untyped_arithmetic_is_inferred_from_the_operands() {
+        let source = "(func $main (push u8 1) (push u8 2) (add) (push u8 3) (mul))";
+        let (module, errors) =
+            AstGen::new(fixture_config("untyped_arithmetic")).parse_module("main".into(), source);
+        assert!(errors.is_empty());
+
+        assert!(matches!(
+            module.funcs[0].expr[2].expr,
+            Expr::ActualInstr(Instruction::Add(IntegerType::U8))
+        ));
+    }
+
+    #[test]
+    fn untyped_arithmetic_on_mixed_operands_fails() {
+        let source = "(func $main (push u8 1) (push u16 2) (sub))";
+        let (_, errors) =
+            AstGen::new(fixture_config("untyped_arithmetic")).parse_module("main".into(), source);
+
+        let errors: Vec<_> = errors.iter().map(|err| err.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec!["in $main: cannot infer type for sub, the operands are u8 and u16"]
+        );
+    }
+
+    #[test]
+    fn loop_labels_resolve_to_loops_around_them() {
+        let ast = AstGen::gen("main".into(), fixture_config("loop_labels")).unwrap();
+
+        match ast.modules["main"].funcs[0].expr[2].expr {
+            Expr::While(ref whl) => assert_eq!(whl.label, Some("'outer".into())),
+            ref expr => panic!("expected a while loop, found {:?}", expr),
+        }
+
+        let invalid = vec![
+            ("(func $main (break))", "in $main: break outside of a loop"),
+            (
+                "(func $main (push u8 0) (while (== u8 0) (continue 'outer)))",
+                "in $main: continue 'outer doesn't refer to a loop around it",
+            ),
+            (
+                "(func $main (push u8 0) ('a: while (== u8 0) (push u8 0) ('a: while (== u8 0))))",
+                "in $main: the label 'a is already used by a loop around it",
+            ),
+        ];
+
+        for (source, message) in invalid {
+            let (_, errors) =
+                AstGen::new(fixture_config("loop_labels")).parse_module("main".into(), source);
+
+            let errors: Vec<_> = errors.iter().map(|err| err.to_string()).collect();
+            assert_eq!(errors, vec![message]);
+        }
+    }
+
+    #[test]
+    fn indirect_accesses_without_an_address_warn() {
+        let ast = AstGen::gen("main".into(), fixture_config("indirect_address")).unwrap();
+
+        assert_eq!(
+            ast.warnings(),
+            vec![
+                "module \"main\": in $narrow: indirect store expects a u16 address but finds a u8 \
+                 value",
+                "module \"main\": in $main: indirect load expects a u16 address but there is none \
+                 on the stack",
+            ]
+        );
+    }
+
+    #[test]
+    fn the_memory_layout_is_part_of_the_ast() {
+        let mut config = Config::from_file("test/fixtures/memory_layout/Beast.toml").unwrap();
+        config.compilation.include_dirs = vec!["test/fixtures/memory_layout".into()];
+
+        let ast = AstGen::gen("main".into(), config).unwrap();
+        assert_eq!(ast.memory.heap_start, 0x300);
+        assert_eq!(ast.memory.stack_start, 1536);
+    }
+
+    #[test]
+    fn glob_imports_bring_exported_constants_into_scope() {
+        let ast = AstGen::gen("main".into(), fixture_config("const_glob")).unwrap();
+
+        let constants: Vec<_> = ast.modules["main"]
+            .constants
+            .iter()
+            .map(|constant| (constant.id.as_str(), constant.value))
+            .collect();
+        assert_eq!(constants, vec![("%HEIGHT", 9), ("%RED", 0xe0), ("%WIDTH", 16)]);
+
+        let err = AstGen::gen("main".into(), fixture_config("const_glob_collision")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "module \"main\" imports the constant %SIZE from both \"first\" and \"second\""
+        );
+
+        let (_, errors) = AstGen::new(fixture_config("const_glob"))
+            .parse_module("main".into(), "(export %MISSING)");
+        let errors: Vec<_> = errors.iter().map(|err| err.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec!["unable to export the constant %MISSING, it isn't defined"]
+        );
+    }
+
+    #[test]
+    fn functions_are_ordered_as_configured() {
+        let order_of = |order| {
+            let mut config = fixture_config("function_order");
+            config.compilation.function_order = Some(order);
+
+            let ast = AstGen::gen("main".into(), config).unwrap();
+            ast.modules["main"]
+                .funcs
+                .iter()
+                .map(|func| func.id.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            order_of(FunctionOrder::Source),
+            vec!["$zeta", "$main", "$alpha", "$beta", "$pong", "$ping"]
+        );
+        assert_eq!(
+            order_of(FunctionOrder::Name),
+            vec!["$alpha", "$beta", "$main", "$ping", "$pong", "$zeta"]
+        );
+        assert_eq!(
+            order_of(FunctionOrder::Callgraph),
+            vec!["$main", "$alpha", "$zeta", "$beta", "$ping", "$pong"]
+        );
+    }
+
+    #[test]
+    fn failing_static_assertions_show_their_values() {
+        let err = AstGen::gen("main".into(), fixture_config("static_assert")).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "static assertion (== (% %BUF_LEN 16) 0) of module \"main\" failed: 4 == 0 doesn't \
+             hold (%BUF_LEN = 20)"
+        );
+    }
+
+    #[test]
+    fn constant_expressions_are_evaluated() {
+        let source = "(const %A 6) (static_assert (+ (<< %A 2) (/ -9 2))) (static_assert (/ 1 0))";
+        let (module, errors) =
+            AstGen::new(fixture_config("static_assert")).parse_module("main".into(), source);
+        assert!(errors.is_empty());
+
+        let assertions = &module.static_asserts;
+        assert_eq!(assertions[0].cond.eval(&module.constants).unwrap(), 20);
+        assert_eq!(
+            assertions[1].cond.eval(&module.constants).unwrap_err().to_string(),
+            "unable to evaluate (/ 1 0)"
+        );
+    }
+
+    #[test]
+    fn pure_functions_may_not_have_side_effects() {
+        let err = AstGen::gen("main".into(), fixture_config("purity")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "$report of module \"main\" is marked as pure, but it has the side effect \
+             Sys(\":gurgle\")"
+        );
+
+        let source = "#[pure] (func $outer (call $inner)) (func $inner (push u8 0) (store u8 0x10))";
+        let (module, errors) =
+            AstGen::new(fixture_config("purity")).parse_module("main".into(), source);
+        assert!(errors.is_empty());
+        assert!(module.funcs[0].is_pure);
+
+        let mut ast = Ast {
+            modules: BTreeMap::new(),
+            memory: MemoryLayout::default(),
+        };
+        ast.modules.insert("main".into(), module);
+
+        assert_eq!(
+            ast.check_purity().unwrap_err().to_string(),
+            "$outer of module \"main\" is marked as pure, but $inner of module \"main\", which \
+             it calls, has the side effect Store(U8, Literal(16))"
+        );
+    }
+
+    #[test]
+    fn modules_are_read_from_any_reader() {
+        let source = "(import $sqrt from math)\n(func $main (push u8 4) (call $sqrt))";
+        let config = fixture_config("recovery");
+        let module = AstGen::compile_reader(source.as_bytes(), "piped", config.clone()).unwrap();
+
+        assert_eq!(module.id, "piped");
+        assert_eq!(module.path, None);
+        assert_eq!(module.imports[0].module_id, "math");
+        assert_eq!(module.funcs[0].id, "$main");
+
+        let err = AstGen::compile_reader("(func $main".as_bytes(), "piped", config).unwrap_err();
+        assert!(err.to_string().contains("1:12"));
+    }
+
+    #[test]
+    fn explicit_paths_replace_those_of_the_config() {
+        let mut compiler = AstGen::with_paths(
+            fixture_config("constants"),
+            Vec::new(),
+            vec!["test/fixtures/bundle".into()],
+        );
+        let ast = compiler.ast("main".into()).unwrap();
+
+        assert_eq!(ast.modules.keys().collect::<Vec<_>>(), vec!["main", "math", "util"]);
+        assert_eq!(compiler.include, vec!["test/fixtures/bundle", defaults::INCLUDE_PATH]);
+        assert_eq!(compiler.lib, vec![defaults::LIB_PATH]);
+    }
+
+    #[test]
+    fn modules_are_looked_up_in_the_library_paths() {
+        let mut compiler = AstGen::with_paths(
+            test_config(),
+            vec!["test/fixtures/lib_paths/lib".into()],
+            vec!["test/fixtures/lib_paths/src".into()],
+        );
+        let ast = compiler.ast("main".into()).unwrap();
+
+        assert_eq!(
+            ast.modules["shapes"].path,
+            Some(PathBuf::from("test/fixtures/lib_paths/lib/shapes.beast"))
+        );
+    }
+
+    #[test]
+    fn builders_generate_asts_without_a_manifest() {
+        let mut signals = BTreeMap::new();
+        signals.insert("gurgle".to_string(), Signal::Id(1));
+
+        let ast = AstGenBuilder::new()
+            .root_module("main")
+            .include_paths(vec!["test/fixtures/bundle".into()])
+            .signals(signals)
+            .jobs(2)
+            .build()
+            .unwrap();
+        assert_eq!(ast.modules.keys().collect::<Vec<_>>(), vec!["main", "math", "util"]);
+
+        let ast = AstGenBuilder::new()
+            .config(test_config())
+            .lib_paths(vec!["test/fixtures/lib_paths/lib".into()])
+            .include_paths(vec!["test/fixtures/lib_paths/src".into()])
+            .build()
+            .unwrap();
+        assert_eq!(ast.modules.keys().collect::<Vec<_>>(), vec!["main", "shapes"]);
+
+        let err = AstGenBuilder::new()
+            .include_paths(vec!["test/fixtures/bundle".into()])
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("undefined signal \"gurgle\""));
+
+        let err = AstGenBuilder::new().jobs(0).build().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "compilation.jobs is 0, but at least one module has to be parsed at a time"
+        );
+    }
+
+    #[test]
+    fn discovery_finds_every_imported_module() {
+        let mut compiler = AstGen::new(fixture_config("bundle"));
+        let modules = compiler.discover("main".into()).unwrap();
+
+        let summary: Vec<_> = modules
+            .iter()
+            .map(|info| {
+                let dependencies: Vec<_> = info.dependencies.iter().cloned().collect();
+                assert_eq!(info.kind, ModuleKind::Source);
+                (info.id.as_str(), info.path.clone().unwrap(), dependencies)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "main",
+                    PathBuf::from("test/fixtures/bundle/main.beast"),
+                    vec!["math".to_string(), "util".to_string()],
+                ),
+                ("math", PathBuf::from("test/fixtures/bundle/math.beast"), vec![]),
+                (
+                    "util",
+                    PathBuf::from("test/fixtures/bundle/util.beast"),
+                    vec!["math".to_string()],
+                ),
+            ]
+        );
+
+        let mut compiler = AstGen::with_paths(
+            test_config(),
+            vec!["test/fixtures/lib_paths/lib".into()],
+            vec!["test/fixtures/lib_paths/src".into()],
+        );
+        let kinds: Vec<_> = compiler
+            .discover("main".into())
+            .unwrap()
+            .into_iter()
+            .map(|info| (info.id, info.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("main".to_string(), ModuleKind::Source),
+                ("shapes".to_string(), ModuleKind::Lib),
+            ]
+        );
+
+        let err = AstGen::new(fixture_config("relative_imports"))
+            .discover("missing".into())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to discover module \"missing\"\nunable to find module: \"missing\""
+        );
+    }
+
+    #[test]
+    fn modules_are_loaded_from_source_providers() {
+        use std::collections::HashMap;
+
+        let mut sources = HashMap::new();
+        sources.insert(
+            "main".to_string(),
+            "(import $scale from util)\n(func $main (push u8 2) (call $scale))".to_string(),
+        );
+        sources.insert(
+            "util".to_string(),
+            "(import $square from math)\n(export $scale)\n(func $scale (call $square))"
+                .to_string(),
+        );
+
+        // Modules missing from the provider are looked up in the search paths
+        let ast = AstGen::with_sources(fixture_config("bundle"), sources.clone())
+            .ast("main".into())
+            .unwrap();
+
+        assert_eq!(ast.modules.keys().collect::<Vec<_>>(), vec!["main", "math", "util"]);
+        assert_eq!(ast.modules["main"].path, None);
+        assert_eq!(ast.modules["util"].funcs[0].id, "$scale");
+        assert_eq!(
+            ast.modules["math"].path,
+            Some(PathBuf::from("test/fixtures/bundle/math.beast"))
+        );
+
+        sources.remove("util");
+        let err = AstGen::with_sources(fixture_config("constants"), sources)
+            .ast("main".into())
+            .unwrap_err();
+        assert!(err.to_string().contains("unable to find module: \"util\""));
+    }
+
+    #[test]
+    fn provided_modules_import_relative_to_themselves() {
+        use std::collections::HashMap;
+
+        let mut sources = HashMap::new();
+        sources.insert(
+            "main".to_string(),
+            "(import $area from shapes.square)\n(func $main (push u8 3) (call $area))".to_string(),
+        );
+        sources.insert(
+            "shapes.square".to_string(),
+            "(import $mul from ops)\n(export $area)\n(func $area (dup u8) (call $mul))"
+                .to_string(),
+        );
+        sources.insert(
+            "shapes.ops".to_string(),
+            "(export $mul)\n(func $mul (mul u8))".to_string(),
+        );
+        sources.insert("ops".to_string(), "(export $add)\n(func $add (add u8))".to_string());
+
+        let ast = AstGen::with_sources(test_config(), sources.clone())
+            .ast("main".into())
+            .unwrap();
+        assert_eq!(
+            ast.modules.keys().collect::<Vec<_>>(),
+            vec!["main", "shapes.ops", "shapes.square"]
+        );
+
+        // Without a module next to the importer the path is looked up from the root
+        sources.remove("shapes.ops");
+        sources.insert("ops".to_string(), "(export $mul)\n(func $mul (mul u8))".to_string());
+
+        let ast = AstGen::with_sources(test_config(), sources)
+            .ast("main".into())
+            .unwrap();
+        assert_eq!(
+            ast.modules.keys().collect::<Vec<_>>(),
+            vec!["main", "ops", "shapes.square"]
+        );
+    }
+
+    #[test]
+    fn errors_of_several_modules_are_collected() {
+        let err = AstGen::gen("main".into(), fixture_config("collect_errors")).unwrap_err();
+        assert_eq!(err.to_string().lines().count(), 2);
+
+        let mut config = fixture_config("collect_errors");
+        config.compilation.collect_errors = Some(true);
+
+        let err = AstGen::gen("main".into(), config).unwrap_err().to_string();
+        let lines: Vec<_> = err.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines.contains(&"failed to compile module \"first\" imported by \"main\""));
+        assert!(lines.contains(
+            &"the function $first is defined more than once in module \"first\""
+        ));
+        assert!(lines.contains(&"failed to compile module \"missing\" imported by \"second\""));
+        assert!(lines.contains(&"unable to find module: \"missing\""));
+    }
+
+    #[test]
+    fn unexpected_rules_are_errors() {
+        let mut compiler = AstGen::new(fixture_config("constants"));
+        compiler.parse_module("main".into(), "");
+
+        let pair = BeastParser::parse(Rule::func_id, "$main").unwrap().next().unwrap();
+        assert_eq!(
+            compiler.push_value(&IntegerType::U8, pair).unwrap_err().to_string(),
+            "internal compiler error: unexpected rule func_id in a pushed value of module \"main\""
+        );
+
+        let pair = BeastParser::parse(Rule::func_id, "$main").unwrap().next().unwrap();
+        assert!(compiler.condition(pair).is_err());
+        assert_eq!(
+            compiler.type_("u32").unwrap_err().to_string(),
+            "internal compiler error: unexpected type \"u32\" in module \"main\""
+        );
+    }
+
+    #[test]
+    fn functions_are_defined_once() {
+        let err = AstGen::gen("main".into(), fixture_config("duplicate_func")).unwrap_err();
+        assert!(err.to_string()
+            .ends_with("the function $helper is defined more than once in module \"main\""));
+
+        let source = "(export $a as $shared) (export $b as $shared) (func $a) (func $b)";
+        let (_, errors) =
+            AstGen::new(fixture_config("duplicate_func")).parse_module("util".into(), source);
+        assert_eq!(
+            errors[0].to_string(),
+            "the exports $a and $b of module \"util\" are both named $shared"
+        );
+    }
+
+    #[test]
+    fn exported_functions_are_defined() {
+        let err = AstGen::gen("main".into(), fixture_config("missing_export")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to compile module \"util\" imported by \"main\"\nunable to export the \
+             function $sqrt, module \"util\" doesn't define it"
+        );
+    }
+
+    #[test]
+    fn imported_functions_are_exported() {
+        let err = AstGen::gen("main".into(), fixture_config("unexported_import")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "module \"main\" imports $cube from module \"util\", which only exports [$square, \
+             $double]"
+        );
+    }
+
+    #[test]
+    fn constants_are_defined_once() {
+        let err = AstGen::gen("main".into(), fixture_config("duplicate_const")).unwrap_err();
+        assert!(err.to_string()
+            .ends_with("the constant %LIMIT is defined more than once in module \"main\""));
+
+        let source = "(const %A 1) (const %A 0x01) (const %B %A) (const %B %A) \
+                      (consts %C (x 1)) (consts %C (x 1))";
+        let (_, errors) =
+            AstGen::new(fixture_config("duplicate_const")).parse_module("main".into(), source);
+        let errors: Vec<_> = errors.iter().map(|err| err.to_string()).collect();
+        assert_eq!(
+            errors[0],
+            "the constant %A is defined more than once in module \"main\", set \
+             allow_identical_constants to allow repeating the same value"
+        );
+        assert_eq!(errors.len(), 3);
+
+        let mut config = fixture_config("duplicate_const");
+        config.compilation.allow_identical_constants = Some(true);
+        let (module, errors) = AstGen::new(config).parse_module("main".into(), source);
+        assert!(errors.is_empty());
+        assert_eq!(module.constants.len(), 3);
+    }
+
+    #[test]
+    fn circular_imports_warn() {
+        let ast = AstGen::gen("main".into(), fixture_config("import_cycle")).unwrap();
+
+        assert_eq!(
+            ast.warnings(),
+            vec!["module \"ping\": the imports of the modules form a cycle: ping -> pong -> ping"]
+        );
+    }
+
+    #[test]
+    fn constants_that_dont_fit_their_uses_warn() {
+        let ast = AstGen::gen("main".into(), fixture_config("constant_widths")).unwrap();
+
+        assert_eq!(
+            ast.warnings(),
+            vec![
+                "module \"main\": in $main: the constant %LIMIT is 1000, which doesn't fit the u8 \
+                 of its use as push",
+                "module \"main\": in $main: the constant %OFFSET is -200, which doesn't fit the \
+                 i8 of its use as comparison",
+            ]
+        );
+    }
+
+    #[test]
+    fn local_functions_shadowing_imports_warn() {
+        let ast = AstGen::gen("main".into(), fixture_config("shadowed_import")).unwrap();
+
+        assert_eq!(
+            ast.warnings(),
+            vec![
+                "module \"main\": $sqrt is both defined locally and imported from module \
+                 \"math\", calls resolve to the import",
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_regions_are_errors() {
+        let err = AstGen::gen("main".into(), fixture_config("regions")).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "memory region %FRAME of module \"gfx\" (0x0100..0x0140) overlaps memory region \
+             %BUFFER of module \"main\" (0x0120..0x0160)"
+        );
+    }
+
+    #[test]
+    fn check_all_includes_unreferenced_modules() {
+        let err = AstGen::check_all("test/fixtures/check_all", fixture_config("check_all"))
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.starts_with("test/fixtures/check_all/util/unused.beast: "));
+        assert_eq!(
+            err.lines()
+                .filter(|line| line.starts_with("test/fixtures/check_all/"))
+                .count(),
+            1
+        );
+        assert!(err.contains("--> test/fixtures/check_all/util/unused.beast:"));
+
+        let warnings =
+            AstGen::check_all("test/fixtures/short_extension", fixture_config("short_extension"))
+                .unwrap();
+
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("module \"util\": the extension of"));
+    }
+
+    #[test]
+    fn cfg_attributes_depend_on_constants() {
+        let source = include_str!("../test/fixtures/cfg/main.beast");
+        let mut ast_gen = AstGen::new(fixture_config("cfg"));
+
+        let (module, errors) = ast_gen.parse_module("main".into(), source);
+        assert!(errors.is_empty());
+        let func_ids: Vec<_> = module.funcs.iter().map(|func| func.id.as_str()).collect();
+        assert_eq!(func_ids, vec!["$trace", "$main"]);
+
+        let disabled = source.replace("(const %DEBUG 1)", "(const %DEBUG 0)");
+        let (module, errors) = ast_gen.parse_module("main".into(), &disabled);
+        assert!(errors.is_empty());
+        let func_ids: Vec<_> = module.funcs.iter().map(|func| func.id.as_str()).collect();
+        assert_eq!(func_ids, vec!["$main"]);
+
+        let undefined = source.replace("(const %DEBUG 1)", "");
+        let (_, errors) = ast_gen.parse_module("main".into(), &undefined);
+        assert_eq!(
+            errors[0].to_string(),
+            "unable to find constant \"%DEBUG\" in the cfg attribute of $trace"
+        );
+    }
+
+    #[test]
+    fn modules_in_several_search_paths_are_errors_when_unique() {
+        let mut config = fixture_config("duplicate_module");
+        config.compilation.include_dirs = vec![
+            "test/fixtures/duplicate_module/first".into(),
+            "test/fixtures/duplicate_module/second".into(),
+        ];
+
+        let ast = AstGen::gen("main".into(), config.clone()).unwrap();
+        assert!(ast.modules.contains_key("util"));
+
+        config.compilation.unique_modules = Some(true);
+        let err = AstGen::gen("main".into(), config).unwrap_err().to_string();
+
+        assert!(err.contains("module \"util\" is defined by more than one file"));
+        assert!(err.contains("duplicate_module/first/util.beast"));
+        assert!(err.contains("duplicate_module/second/util.beast"));
+    }
+
+    #[test]
+    fn large_functions_are_reported() {
+        let mut config = fixture_config("max_func_size");
+        config.compilation.max_func_instructions = Some(5);
+
+        let ast = AstGen::gen("main".into(), config).unwrap();
+
+        assert_eq!(
+            ast.warnings(),
+            vec![
+                "module \"main\": in $main: the function has 7 instructions, more than the \
+                 maximum of 5",
+            ]
+        );
+    }
+
+    #[test]
+    fn store_reg_is_rejected() {
+        let mut ast_gen = AstGen::new(test_config());
+
+        let source = "(func $frame (reg :sp) (store_reg :bp))";
+        let (_, errors) = ast_gen.parse_module("main".into(), source);
+        assert_eq!(
+            errors[0].to_string(),
+            format!(
+                "unable to use store_reg BasePtr, melon {} has no instruction to set registers",
+                ::melon::VERSION
+            )
+        );
+
+        let (_, errors) = ast_gen.parse_module("main".into(), "(func $frame (store_reg :ip))");
+        assert!(errors[0].to_string().starts_with("unrecognized register identifier: \":ip\""));
+    }
+
+    #[test]
+    fn only_the_entry_function_declares_parameters() {
+        let ast = AstGen::gen("main".into(), fixture_config("entry_params")).unwrap();
+
+        let params: Vec<_> = ast.modules["main"].funcs[0]
+            .params
+            .iter()
+            .map(|param| (param.id.as_str(), format!("{:?}", param.type_t)))
+            .collect();
+        assert_eq!(params, vec![("$argv", "U16".into()), ("$argc", "U8".into())]);
+
+        let err = AstGen::gen("main".into(), fixture_config("misplaced_params")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "$helper of module \"main\" declares parameters, but only the entry function $main \
+             of module \"main\" may"
+        );
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let mut config = fixture_config("nesting");
+        config.compilation.max_nesting = Some(3);
+        assert!(AstGen::gen("main".into(), config.clone()).is_ok());
+
+        config.compilation.max_nesting = Some(2);
+        let err = AstGen::gen("main".into(), config).unwrap_err();
+        assert!(err.to_string().contains("block nesting exceeds 2"));
+    }
+
+    #[test]
+    fn conditions_take_immediates() {
+        let ast = AstGen::gen("main".into(), fixture_config("immediate_conditions")).unwrap();
+        let main = &ast.modules["main"].funcs[0];
+
+        let immediates: Vec<_> = main.expr
+            .iter()
+            .filter_map(|located| match &located.expr {
+                Expr::If(whether) => Some(format!("{:?}", whether.immediate)),
+                Expr::While(whl) => Some(format!("{:?}", whl.immediate)),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            immediates,
+            vec![
+                "Some(Constant(\"%ANSWER\"))",
+                "Some(Literal(0))",
+                "Some(Literal(0))",
+            ]
+        );
     }
 }
@@ -0,0 +1,217 @@
+//! Flattens the modules of an AST into a single module that needs no imports.
+//!
+//! The functions and constants of the root module keep their ids. Those of every other module are
+//! prefixed with the id of their module, e.g. `$sqrt` of the module `math.float` becomes
+//! `$math_float__sqrt`, and every call is rewired to the renamed function.
+
+use ast::*;
+use melon::typedef::*;
+use std::{collections::BTreeMap, mem};
+
+/// Bundles the AST into one module with the id of the root module, which keeps its exports.
+/// Static assertions are left out, they were checked when the AST was generated.
+pub fn bundle(ast: &Ast, root_module: &str) -> Result<Ast> {
+    let root = match ast.modules.get(root_module) {
+        Some(root) => root,
+        None => bail!("unable to find the root module {:?}", root_module),
+    };
+
+    let mut bundled = Module {
+        exports: root.exports.clone(),
+        const_exports: root.const_exports.clone(),
+        path: root.path.clone(),
+        ..Module::new(root.id.clone())
+    };
+
+    for module in ast.modules.values() {
+        let rename = |id: &str| bundled_id(module, root_module, id);
+
+        for constant in &module.constants {
+            bundled.constants.push(Const {
+                id: rename(&constant.id),
+                value: constant.value,
+            });
+        }
+
+        for region in &module.regions {
+            bundled.regions.push(Region {
+                id: rename(&region.id),
+                ..region.clone()
+            });
+        }
+
+        for func in &module.funcs {
+            let mut func = func.clone();
+            func.id = rename(&func.id);
+            func.cfg = func.cfg.map(|cfg| rename(&cfg));
+            func.expr = rewire(ast, module, root_module, func.expr)?;
+
+            ensure!(
+                bundled.funcs.iter().all(|other| other.id != func.id),
+                "unable to bundle module {:?}, the function {} is already defined",
+                module.id,
+                func.id
+            );
+
+            bundled.funcs.push(func);
+        }
+
+        for warning in &module.warnings {
+            bundled
+                .warnings
+                .push(format!("module {:?}: {}", module.id, warning));
+        }
+    }
+
+    let mut modules = BTreeMap::new();
+    modules.insert(bundled.id.clone(), bundled);
+
+    Ok(Ast {
+        modules,
+        memory: ast.memory,
+    })
+}
+
+/// The id of a function or constant of the given module in the bundle
+fn bundled_id(module: &Module, root_module: &str, id: &str) -> String {
+    if module.id == root_module {
+        id.into()
+    } else {
+        let (sigil, name) = id.split_at(1);
+        format!("{}{}__{}", sigil, module.id.replace('.', "_"), name)
+    }
+}
+
+/// Renames the constants the expressions of the module refer to and points their calls to the
+/// bundled functions
+fn rewire(
+    ast: &Ast,
+    module: &Module,
+    root_module: &str,
+    exprs: Vec<Located>,
+) -> Result<Vec<Located>> {
+    fn rename<T>(arg: &mut Argument<T>, module: &Module, root_module: &str) {
+        if let Argument::Constant(id) = arg {
+            *id = bundled_id(module, root_module, id);
+        }
+    }
+
+    let mut rewired = Vec::new();
+
+    for Located { mut expr, line } in exprs {
+        match expr {
+            Expr::Call(ref mut func_id) => {
+                let (callee_module, callee_id) = match ast.resolve_call(module, func_id) {
+                    Some(callee) => callee,
+                    None => bail!(
+                        "unable to bundle module {:?}, the function {} can't be found",
+                        module.id,
+                        func_id
+                    ),
+                };
+
+                *func_id = bundled_id(&ast.modules[&callee_module], root_module, &callee_id);
+            }
+            Expr::PushConstU8(ref mut arg) => rename(arg, module, root_module),
+            Expr::PushConstU16(ref mut arg) => rename(arg, module, root_module),
+            Expr::PushConstI8(ref mut arg) => rename(arg, module, root_module),
+            Expr::PushConstI16(ref mut arg) => rename(arg, module, root_module),
+            Expr::Load(_, ref mut arg)
+            | Expr::Store(_, ref mut arg)
+            | Expr::Tee(_, ref mut arg) => rename(arg, module, root_module),
+            Expr::Alloc(ref mut arg, _) => rename(arg, module, root_module),
+            Expr::Clamp(_, ref mut min, ref mut max) => {
+                rename(min, module, root_module);
+                rename(max, module, root_module);
+            }
+            Expr::While(ref mut whl) => {
+                if let Some(ref mut immediate) = whl.immediate {
+                    rename(immediate, module, root_module);
+                }
+                if let Some(ref mut max) = whl.max {
+                    rename(max, module, root_module);
+                }
+
+                whl.exprs = rewire(ast, module, root_module, mem::take(&mut whl.exprs))?;
+            }
+            Expr::If(ref mut whether) => {
+                if let Some(ref mut immediate) = whether.immediate {
+                    rename(immediate, module, root_module);
+                }
+
+                whether.exprs = rewire(ast, module, root_module, mem::take(&mut whether.exprs))?;
+                if let Some(else_exprs) = whether.else_exprs.take() {
+                    whether.else_exprs = Some(rewire(ast, module, root_module, else_exprs)?);
+                }
+            }
+            _ => {}
+        }
+
+        rewired.push(Located { expr, line });
+    }
+
+    Ok(rewired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast_gen::AstGen;
+    use compiler::{Compiler, Emit};
+    use test_utils::*;
+
+    #[test]
+    fn bundles_run_like_their_modules() {
+        let ast = AstGen::gen("main".into(), fixture_config("bundle")).unwrap();
+        let bundled = bundle(&ast, "main").unwrap();
+
+        assert_eq!(bundled.modules.keys().collect::<Vec<_>>(), vec!["main"]);
+
+        let module = &bundled.modules["main"];
+        assert!(module.imports.is_empty());
+
+        let func_ids: Vec<_> = module.funcs.iter().map(|func| func.id.as_str()).collect();
+        assert_eq!(
+            func_ids,
+            vec!["$main", "$double", "$math__square", "$util__double", "$util__scale"]
+        );
+
+        let constant_ids: Vec<_> = module
+            .constants
+            .iter()
+            .map(|constant| constant.id.as_str())
+            .collect();
+        assert_eq!(constant_ids, vec!["%BASE", "%util__BASE"]);
+
+        let calls = bundled.instructions_of(|expr| matches!(expr, Expr::Call(_)));
+        let calls: Vec<_> = calls
+            .into_iter()
+            .map(|(_, func_id, _, expr)| format!("{} {:?}", func_id, expr))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                "$main Call(\"$math__square\")",
+                "$main Call(\"$util__scale\")",
+                "$main Call(\"$double\")",
+                "$util__scale Call(\"$math__square\")",
+                "$util__scale Call(\"$util__double\")",
+            ]
+        );
+
+        let original = Compiler::compile("main".into(), fixture_config("bundle"), Emit::default())
+            .unwrap();
+        let flattened = Compiler::compile_with_pass(
+            "main".into(),
+            fixture_config("bundle"),
+            Emit::default(),
+            &mut |ast| {
+                *ast = bundle(ast, "main")?;
+                Ok(())
+            },
+        ).unwrap();
+
+        assert_eq!(run(&original).unwrap(), vec![9, 40, 14]);
+        assert_eq!(run(&flattened).unwrap(), run(&original).unwrap());
+    }
+}
@@ -0,0 +1,167 @@
+use ast::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A function identified by the id of its module and its local id
+pub type FuncRef = (String, String);
+
+/// The graph of all calls between the functions of an AST
+#[derive(Debug, Clone, Default)]
+pub struct CallGraph {
+    edges: BTreeMap<FuncRef, BTreeSet<FuncRef>>,
+}
+
+impl CallGraph {
+    pub fn new(ast: &Ast) -> CallGraph {
+        let mut edges = BTreeMap::new();
+
+        for module in ast.modules.values() {
+            for func in &module.funcs {
+                let mut callees = BTreeSet::new();
+                CallGraph::collect_calls(ast, module, &func.expr, &mut callees);
+
+                edges.insert((module.id.clone(), func.id.clone()), callees);
+            }
+        }
+
+        CallGraph { edges }
+    }
+
+    fn collect_calls(
+        ast: &Ast,
+        module: &Module,
+        exprs: &[Located],
+        callees: &mut BTreeSet<FuncRef>,
+    ) {
+        for Located { expr, .. } in exprs {
+            match expr {
+                Expr::Call(func_id) => {
+                    if let Some(callee) = ast.resolve_call(module, func_id) {
+                        callees.insert(callee);
+                    }
+                }
+                Expr::While(whl) => CallGraph::collect_calls(ast, module, &whl.exprs, callees),
+                Expr::If(whether) => {
+                    CallGraph::collect_calls(ast, module, &whether.exprs, callees);
+
+                    if let Some(ref else_exprs) = whether.else_exprs {
+                        CallGraph::collect_calls(ast, module, else_exprs, callees);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the functions directly called by the given function
+    pub fn callees(&self, func: &FuncRef) -> Option<&BTreeSet<FuncRef>> {
+        self.edges.get(func)
+    }
+
+    /// Returns whether the given function is able to (indirectly) call itself
+    pub fn is_recursive(&self, func: &FuncRef) -> bool {
+        let mut visited = BTreeSet::new();
+        let mut stack: Vec<&FuncRef> = self.callees(func).into_iter().flatten().collect();
+
+        while let Some(current) = stack.pop() {
+            if current == func {
+                return true;
+            }
+
+            if visited.insert(current) {
+                stack.extend(self.callees(current).into_iter().flatten());
+            }
+        }
+
+        false
+    }
+
+    /// Renders the graph in the DOT language of Graphviz, naming each function `module:$func`
+    pub fn to_dot(&self) -> String {
+        let name = |func: &FuncRef| format!("\"{}:{}\"", func.0, func.1);
+
+        let mut dot = String::from("digraph calls {\n");
+        for (caller, callees) in &self.edges {
+            dot.push_str(&format!("    {};\n", name(caller)));
+
+            for callee in callees {
+                dot.push_str(&format!("    {} -> {};\n", name(caller), name(callee)));
+            }
+        }
+        dot.push_str("}\n");
+
+        dot
+    }
+
+    /// Orders the given functions of the module so the functions of the module each of them calls
+    /// follow it, unless they are placed already. The functions no other function of the module
+    /// calls come first, those only called from cycles follow, both in name order.
+    pub fn order(&self, module_id: &str, func_ids: &[&str]) -> Vec<String> {
+        let mut names = func_ids.to_vec();
+        names.sort();
+
+        let local_callees = |func_id: &str| -> Vec<&str> {
+            self.callees(&(module_id.into(), func_id.into()))
+                .into_iter()
+                .flatten()
+                .filter(|callee| callee.0 == module_id && names.contains(&callee.1.as_str()))
+                .map(|callee| callee.1.as_str())
+                .collect()
+        };
+
+        let called: BTreeSet<_> = names
+            .iter()
+            .flat_map(|func_id| local_callees(func_id))
+            .collect();
+
+        let roots = names.iter().filter(|func_id| !called.contains(*func_id));
+        let mut order: Vec<String> = Vec::new();
+
+        for start in roots.chain(names.iter()) {
+            let mut stack = vec![*start];
+
+            while let Some(func_id) = stack.pop() {
+                if order.iter().any(|placed| placed == func_id) {
+                    continue;
+                }
+
+                order.push(func_id.into());
+
+                // Reversed, so the callees are placed in name order
+                stack.extend(local_callees(func_id).into_iter().rev());
+            }
+        }
+
+        order
+    }
+
+    /// Returns every function that can be reached from the given function, including itself
+    pub fn reachable_from(&self, entry: &FuncRef) -> BTreeSet<FuncRef> {
+        let mut reachable = BTreeSet::new();
+        let mut stack = vec![entry.clone()];
+
+        while let Some(current) = stack.pop() {
+            if let Some(callees) = self.callees(&current) {
+                stack.extend(
+                    callees
+                        .iter()
+                        .filter(|callee| !reachable.contains(*callee))
+                        .cloned(),
+                );
+            }
+
+            reachable.insert(current);
+        }
+
+        reachable
+    }
+}
+
+/// The functions reachable from an entry function or from the exported functions
+#[derive(Debug, Clone, Default)]
+pub struct Reachability {
+    pub reachable: BTreeSet<FuncRef>,
+    /// The private functions that are never reached, grouped by module. Exported functions are
+    /// part of the API of their module and are therefore never counted as unreachable, nor are the
+    /// functions they call.
+    pub unreachable: BTreeMap<String, Vec<String>>,
+}
@@ -1,8 +1,11 @@
 use ast::*;
 use ast_gen::AstGen;
+use call_graph::{CallGraph, FuncRef};
 use config::Config;
 use defaults;
-use melon::{typedef::*, Instruction, Program};
+use lowering;
+use melon::{typedef::*, Instruction, IntegerType, Program};
+use stack_effect;
 use std::collections::BTreeMap;
 
 const PRIVATE_PREFIX: &str = "PRIVATE__";
@@ -11,45 +14,163 @@ const PRIVATE_PREFIX: &str = "PRIVATE__";
 enum MetaInstr {
     ActualInstr(Instruction),
     Call { func_id: String, module_id: String },
+    /// A jump out of the loop with the given label, or the innermost loop, resolved once the loop
+    /// is compiled
+    Break(Option<String>),
+    /// A jump back to the condition of the loop with the given label, or the innermost loop
+    Continue(Option<String>),
+}
+
+/// Selects the intermediate results printed while compiling
+#[derive(StructOpt, Debug, Default, Clone, Copy)]
+pub struct Emit {
+    #[structopt(
+        long = "emit-func-map",
+        help = "emits the corresponding function-map for the current build"
+    )]
+    pub func_map: bool,
+    #[structopt(long = "emit-ast", help = "emits the corresponding AST for the current build")]
+    pub ast: bool,
+    #[structopt(
+        long = "emit-public-api",
+        help = "emits the exported functions and constants of every module of the current build"
+    )]
+    pub public_api: bool,
+    #[structopt(
+        long = "emit-unreachable",
+        help = "emits the private functions that can't be reached from the entry point"
+    )]
+    pub unreachable: bool,
+    #[structopt(
+        long = "emit-timings",
+        help = "emits the time spent in each phase of generating the AST"
+    )]
+    pub timings: bool,
+    #[structopt(
+        long = "emit-listing",
+        help = "emits the expressions of every function with their source lines"
+    )]
+    pub listing: bool,
 }
 
 pub struct Compiler {
     ast: Ast,
     config: Config,
+    call_graph: CallGraph,
+    /// The function being compiled
+    func: FuncRef,
+    /// The number of iteration counters of capped loops, which are kept at the start of the heap
+    loop_counters: u16,
 }
 
 impl Compiler {
     fn new(config: Config, ast: Ast) -> Compiler {
-        Compiler { ast, config }
+        Compiler {
+            ast,
+            config,
+            call_graph: CallGraph::default(),
+            func: FuncRef::default(),
+            loop_counters: 0,
+        }
     }
 
-    pub fn compile(
+    pub fn compile(root_module: String, config: Config, emit: Emit) -> Result<Program> {
+        Compiler::compile_with_pass(root_module, config, emit, &mut |_| Ok(()))
+    }
+
+    /// Compiles the program like `compile`, running the given pass over the AST before it is
+    /// compiled
+    pub fn compile_with_pass(
         root_module: String,
         config: Config,
-        emit_func_map: bool,
-        emit_ast: bool,
+        emit: Emit,
+        pass: &mut dyn FnMut(&mut Ast) -> Result<()>,
     ) -> Result<Program> {
-        let ast = AstGen::gen(root_module.clone(), config.clone())?;
+        let mut ast = if emit.timings {
+            let (ast, report) = AstGen::gen_profiled(root_module.clone(), config.clone())?;
+            println!("{:#?}", report);
+
+            ast
+        } else {
+            AstGen::gen(root_module.clone(), config.clone())?
+        };
+
+        pass(&mut ast)?;
+
+        let warnings = ast.warnings();
+        for warning in &warnings {
+            eprintln!("warning: {}", warning);
+        }
+
+        let deny_warnings = config
+            .compilation
+            .deny_warnings
+            .unwrap_or(defaults::DENY_WARNINGS);
+
+        ensure!(
+            !deny_warnings || warnings.is_empty(),
+            "compilation failed because warnings are denied ({} warning{})",
+            warnings.len(),
+            if warnings.len() == 1 { "" } else { "s" }
+        );
 
-        if emit_ast {
+        if emit.ast {
             println!("{:#?}", ast);
         }
 
+        if emit.listing {
+            print!("{}", ast.listing());
+        }
+
+        if emit.public_api {
+            println!("{:#?}", ast.public_api());
+        }
+
+        if emit.unreachable {
+            let entry = (root_module.clone(), config.entry_func());
+            let reachability = ast.reachability(&entry);
+
+            println!(
+                "{} functions are reachable from {} or an exported function",
+                reachability.reachable.len(),
+                entry.1
+            );
+            println!("{:#?}", reachability.unreachable);
+        }
+
         let mut compiler = Compiler::new(config, ast);
-        let program = compiler.build(root_module, emit_func_map)?;
+        let program = compiler.build(root_module, emit.func_map)?;
 
         Ok(program)
     }
 
     fn build(&mut self, root_module: String, emit_func_map: bool) -> Result<Program> {
+        let debug_assertions = self.config
+            .compilation
+            .debug_assertions
+            .unwrap_or(defaults::DEBUG_ASSERTIONS);
+
+        if !debug_assertions {
+            for module in self.ast.modules.values_mut() {
+                for func in &mut module.funcs {
+                    func.expr = Compiler::strip_debug_exprs(func.expr.clone());
+                }
+            }
+        }
+
+        let entry_func = self.config.entry_func();
+
+        self.call_graph = CallGraph::new(&self.ast);
+
         let modules = self.ast.modules.clone();
 
         let mut meta_module_map = BTreeMap::new();
 
         for (module_name, module) in modules {
-            let mut meta_func_map = BTreeMap::new();
+            let mut meta_func_map = Vec::new();
 
             for func in &module.funcs {
+                self.func = (module_name.clone(), func.id.clone());
                 let mut meta_instr = self.to_meta_instr(func.expr.clone(), &module)?;
 
                 let exported_func = module
@@ -63,19 +184,38 @@ impl Compiler {
                     format!("{}{}", PRIVATE_PREFIX, func.id)
                 };
 
-                if module_name == root_module && func.id == defaults::ENTRY_POINT_FUNC {
-                    func_id = defaults::ENTRY_POINT_FUNC.into();
+                if module_name == root_module && func.id == entry_func {
+                    stack_effect::check_entry(func, &self.config.signals)?;
+
+                    func_id = entry_func.clone();
                     meta_instr.push(MetaInstr::ActualInstr(Instruction::SysCall(0)));
                 } else {
                     meta_instr.push(MetaInstr::ActualInstr(Instruction::Ret));
                 }
 
-                meta_func_map.insert(func_id, meta_instr);
+                meta_func_map.push((func_id, (func.align, meta_instr)));
+            }
+
+            // The functions stay in the order of the AST if it was chosen explicitly
+            if self.config.compilation.function_order.is_none() {
+                meta_func_map.sort_by(|a, b| a.0.cmp(&b.0));
             }
 
             meta_module_map.insert(module_name, meta_func_map);
         }
 
+        // Reserves the iteration counters before anything else is allocated
+        if self.loop_counters > 0 {
+            let entry = meta_module_map
+                .get_mut(&root_module)
+                .and_then(|funcs| funcs.iter_mut().find(|(func_id, _)| *func_id == entry_func));
+
+            if let Some((_, (_, meta_instr))) = entry {
+                let counters = Instruction::Alloc(self.loop_counters * 2);
+                meta_instr.insert(0, MetaInstr::ActualInstr(counters));
+            }
+        }
+
         let mut meta_instr_vec = Vec::new();
         let mut module_map = BTreeMap::new();
 
@@ -83,7 +223,14 @@ impl Compiler {
             let final_func_map = {
                 let mut func_map = BTreeMap::new();
 
-                for (meta_func_id, mut meta_func) in outer_func_map {
+                for (meta_func_id, (align, mut meta_func)) in outer_func_map {
+                    // The padding follows a return, so it is never executed
+                    if let Some(align) = align {
+                        while meta_instr_vec.len() % usize::from(align) != 0 {
+                            meta_instr_vec.push(MetaInstr::ActualInstr(Compiler::nop()));
+                        }
+                    }
+
                     let offset = meta_instr_vec.len();
 
                     func_map.insert(meta_func_id, offset);
@@ -123,24 +270,21 @@ impl Compiler {
 
                     Instruction::Call(*func_addr as u16)
                 }
+                MetaInstr::Break(_) | MetaInstr::Continue(_) => {
+                    bail!("unable to find the loop of a break or continue")
+                }
             };
 
             final_instructions.push(instr);
         }
 
         let entry_func_map = module_map
-            .get(defaults::BIN_ENTRY_POINT_MODULE)
-            .ok_or(format_err!(
-                "unable to find entry module {:?}",
-                defaults::BIN_ENTRY_POINT_MODULE
-            ))?;
+            .get(&root_module)
+            .ok_or(format_err!("unable to find entry module {:?}", root_module))?;
 
         let entry_func_addr = entry_func_map
-            .get(defaults::ENTRY_POINT_FUNC)
-            .ok_or(format_err!(
-                "unable to find entry function {:?}",
-                defaults::ENTRY_POINT_FUNC
-            ))?;
+            .get(&entry_func)
+            .ok_or(format_err!("unable to find entry function {:?}", entry_func))?;
 
         Ok(Program {
             target_version: self.config.program.target_version.clone(),
@@ -151,24 +295,151 @@ impl Compiler {
         })
     }
 
-    fn to_meta_instr(&mut self, instrs: Vec<Expr>, module: &Module) -> Result<Vec<MetaInstr>> {
+    fn to_meta_instr(&mut self, instrs: Vec<Located>, module: &Module) -> Result<Vec<MetaInstr>> {
         let mut meta_vec = Vec::new();
 
-        for instr in instrs {
+        for Located { expr: instr, .. } in instrs {
             match instr.clone() {
+                Expr::While(While {
+                    label,
+                    cond,
+                    type_t,
+                    immediate: Some(immediate),
+                    max,
+                    exprs,
+                }) => {
+                    // Compares the pushed immediate like any other value and drops both values
+                    // whenever the comparison is done
+                    let push = Compiler::push_immediate(&type_t, immediate)?;
+                    let drop = Expr::ActualInstr(Instruction::Drop(type_t.clone()));
+
+                    // Jumps leave the value to compare on the stack like the end of the body
+                    let mut body = vec![drop.clone().into(), drop.clone().into()];
+                    body.extend(Compiler::push_before_jumps(exprs, &label, &push, false));
+                    body.push(push.clone().into());
+
+                    let desugared = vec![
+                        push.into(),
+                        Expr::While(While {
+                            label,
+                            cond,
+                            type_t,
+                            immediate: None,
+                            max,
+                            exprs: body,
+                        }).into(),
+                        drop.clone().into(),
+                        drop.into(),
+                    ];
+
+                    meta_vec.append(&mut self.to_meta_instr(desugared, module)?);
+                    continue;
+                }
+                Expr::If(If {
+                    cond,
+                    type_t,
+                    immediate: Some(immediate),
+                    exprs,
+                    else_exprs,
+                }) => {
+                    let push = Compiler::push_immediate(&type_t, immediate)?;
+                    let drop = Expr::ActualInstr(Instruction::Drop(type_t.clone()));
+
+                    let mut if_exprs = vec![drop.clone().into(), drop.clone().into()];
+                    if_exprs.extend(exprs);
+
+                    let mut else_branch = vec![drop.clone().into(), drop.into()];
+                    else_branch.extend(else_exprs.unwrap_or_default());
+
+                    let desugared = vec![
+                        push.into(),
+                        Expr::If(If {
+                            cond,
+                            type_t,
+                            immediate: None,
+                            exprs: if_exprs,
+                            else_exprs: Some(else_branch),
+                        }).into(),
+                    ];
+
+                    meta_vec.append(&mut self.to_meta_instr(desugared, module)?);
+                    continue;
+                }
                 Expr::While(whl) => {
                     let While {
+                        label,
                         cond,
                         type_t,
+                        max,
                         exprs,
+                        ..
                     } = whl.clone();
 
+                    // Every capped loop has a counter of its own at a fixed address, which a
+                    // recursive call would reset while the loop is running
+                    let counter = match max {
+                        Some(_) => {
+                            ensure!(
+                                !self.call_graph.is_recursive(&self.func),
+                                "unable to cap the iterations of a while loop in {} of module \
+                                 {:?}, because it calls itself",
+                                self.func.1,
+                                self.func.0
+                            );
+
+                            let counter = self.loop_counters * 2;
+                            self.loop_counters += 1;
+
+                            for instr in lowering::loop_counter_start(counter) {
+                                meta_vec.push(MetaInstr::ActualInstr(instr));
+                            }
+
+                            Some(counter)
+                        }
+                        None => None,
+                    };
+
                     meta_vec.push(MetaInstr::ActualInstr(Instruction::Cmp(type_t)));
 
                     let mut meta_instrs = self.to_meta_instr(exprs, module)?;
 
+                    if let (Some(counter), Some(max)) = (counter, max) {
+                        let max = match max {
+                            Argument::Constant(id) => {
+                                Compiler::find_const(&module.constants, id)? as u16
+                            }
+                            Argument::Literal(lit) => lit,
+                        };
+
+                        let body_len = meta_instrs.len() as u16;
+                        let check = lowering::loop_counter_check(counter, max, body_len);
+                        for (index, instr) in check.into_iter().enumerate() {
+                            meta_instrs.insert(index, MetaInstr::ActualInstr(instr));
+                        }
+                    }
+
                     let meta_len = meta_instrs.len() as u16;
 
+                    // The body starts two instructions after the comparison and the loop is left
+                    // after the jump back to it
+                    for (index, meta_instr) in meta_instrs.iter_mut().enumerate() {
+                        let index = index as u16;
+
+                        let jump = match meta_instr {
+                            MetaInstr::Break(ref target) if Compiler::targets(target, &label) => {
+                                Instruction::Jmp(true, meta_len - index + 1)
+                            }
+                            MetaInstr::Continue(ref target)
+                                if Compiler::targets(target, &label) =>
+                            {
+                                Instruction::Jmp(false, index + 2)
+                            }
+                            _ => continue,
+                        };
+
+                        *meta_instr = MetaInstr::ActualInstr(jump);
+                    }
+
                     meta_vec.push(MetaInstr::ActualInstr(match cond {
                         Condition::Greater => Instruction::JltEq(true, meta_len + 2),
                         Condition::Less => Instruction::JgtEq(true, meta_len + 2),
@@ -191,6 +462,7 @@ impl Compiler {
                         type_t,
                         exprs,
                         else_exprs,
+                        ..
                     } = whether.clone();
 
                     meta_vec.push(MetaInstr::ActualInstr(Instruction::Cmp(type_t)));
@@ -225,6 +497,98 @@ impl Compiler {
                     meta_vec.append(&mut else_meta_instrs);
                     continue;
                 }
+                Expr::Assert(cond, type_t) => {
+                    meta_vec.push(MetaInstr::ActualInstr(Instruction::Cmp(type_t)));
+
+                    // Skip the trap if the condition holds
+                    meta_vec.push(MetaInstr::ActualInstr(match cond {
+                        Condition::Greater => Instruction::Jgt(true, 2),
+                        Condition::Less => Instruction::Jlt(true, 2),
+                        Condition::Equal => Instruction::Jeq(true, 2),
+                        Condition::NotEqual => Instruction::Jneq(true, 2),
+                        Condition::GreaterOrEqual => Instruction::JgtEq(true, 2),
+                        Condition::LessOrEqual => Instruction::JltEq(true, 2),
+                    }));
+
+                    meta_vec.push(MetaInstr::ActualInstr(Compiler::trap()));
+                    continue;
+                }
+                Expr::Trap => {
+                    meta_vec.push(MetaInstr::ActualInstr(Compiler::trap()));
+                    continue;
+                }
+                Expr::Break(label) => {
+                    meta_vec.push(MetaInstr::Break(label));
+                    continue;
+                }
+                Expr::Continue(label) => {
+                    meta_vec.push(MetaInstr::Continue(label));
+                    continue;
+                }
+                Expr::Tee(type_t, arg) => {
+                    let exprs = vec![
+                        Expr::ActualInstr(Instruction::Dup(type_t.clone())).into(),
+                        Expr::Store(type_t, arg).into(),
+                    ];
+
+                    meta_vec.append(&mut self.to_meta_instr(exprs, module)?);
+                    continue;
+                }
+                Expr::TeeIndirect(type_t) => {
+                    for instr in lowering::tee_indirect(&type_t) {
+                        meta_vec.push(MetaInstr::ActualInstr(instr));
+                    }
+                    continue;
+                }
+                Expr::WideMul(type_t) => {
+                    for instr in lowering::wide_mul(&type_t)? {
+                        meta_vec.push(MetaInstr::ActualInstr(instr));
+                    }
+                    continue;
+                }
+                Expr::Untyped(op) => bail!("cannot infer type for {}", op.name()),
+                Expr::Saturating(op, type_t) => {
+                    for instr in lowering::saturating(&op, &type_t)? {
+                        meta_vec.push(MetaInstr::ActualInstr(instr));
+                    }
+                    continue;
+                }
+                Expr::CountBits(count, type_t) => {
+                    for instr in lowering::count_bits(&count, &type_t) {
+                        meta_vec.push(MetaInstr::ActualInstr(instr));
+                    }
+                    continue;
+                }
+                Expr::MemCopy => {
+                    for instr in lowering::mem_copy() {
+                        meta_vec.push(MetaInstr::ActualInstr(instr));
+                    }
+                    continue;
+                }
+                Expr::MemSet => {
+                    for instr in lowering::mem_set() {
+                        meta_vec.push(MetaInstr::ActualInstr(instr));
+                    }
+                    continue;
+                }
+                Expr::Clamp(type_t, min, max) => {
+                    let bound = |arg| match arg {
+                        Argument::Constant(id) => Compiler::find_const(&module.constants, id),
+                        Argument::Literal(lit) => Ok(lit),
+                    };
+                    let (min, max) = (bound(min)?, bound(max)?);
+
+                    for instr in lowering::clamp(&type_t, min, max)? {
+                        meta_vec.push(MetaInstr::ActualInstr(instr));
+                    }
+                    continue;
+                }
+                Expr::Call(func_id) => {
+                    if let Some(mut inlined) = self.inline_call(&func_id, module)? {
+                        meta_vec.append(&mut inlined);
+                        continue;
+                    }
+                }
                 _ => {}
             }
 
@@ -265,26 +629,28 @@ impl Compiler {
                         MetaInstr::ActualInstr(Instruction::PushConstI16(lit))
                     }
                 },
-                Expr::Load(integer_type, arg) => match arg {
-                    Argument::Constant(id) => {
-                        let value = Compiler::find_const(&module.constants, id)?;
+                Expr::Load(integer_type, arg) => {
+                    let address = match arg {
+                        Argument::Constant(id) => {
+                            Compiler::find_const(&module.constants, id)? as u16
+                        }
+                        Argument::Literal(lit) => lit,
+                    };
+                    self.check_access(&integer_type, address)?;
 
-                        MetaInstr::ActualInstr(Instruction::Load(integer_type, value as u16))
-                    }
-                    Argument::Literal(lit) => {
-                        MetaInstr::ActualInstr(Instruction::Load(integer_type, lit))
-                    }
-                },
-                Expr::Store(integer_type, arg) => match arg {
-                    Argument::Constant(id) => {
-                        let value = Compiler::find_const(&module.constants, id)?;
+                    MetaInstr::ActualInstr(Instruction::Load(integer_type, address))
+                }
+                Expr::Store(integer_type, arg) => {
+                    let address = match arg {
+                        Argument::Constant(id) => {
+                            Compiler::find_const(&module.constants, id)? as u16
+                        }
+                        Argument::Literal(lit) => lit,
+                    };
+                    self.check_access(&integer_type, address)?;
 
-                        MetaInstr::ActualInstr(Instruction::Store(integer_type, value as u16))
-                    }
-                    Argument::Literal(lit) => {
-                        MetaInstr::ActualInstr(Instruction::Store(integer_type, lit))
-                    }
-                },
+                    MetaInstr::ActualInstr(Instruction::Store(integer_type, address))
+                }
                 Expr::Sys(mut signal) => {
                     signal.remove(0);
 
@@ -293,20 +659,21 @@ impl Compiler {
                     } else {
                         ensure!(self.config.signals.len() > 0, "no signals were given");
 
-                        *self.config.signals.get(&signal).ok_or(format_err!(
-                            "undefined signal {:?}. Available signals are {:?}",
-                            signal,
-                            self.config.signals.keys().cloned().collect::<Vec<_>>()
-                        ))?
+                        self.config
+                            .signals
+                            .get(&signal)
+                            .ok_or(format_err!(
+                                "undefined signal {:?}. Available signals are {:?}",
+                                signal,
+                                self.config.signals.keys().cloned().collect::<Vec<_>>()
+                            ))?
+                            .id()
                     };
 
                     MetaInstr::ActualInstr(Instruction::SysCall(real_signal))
                 }
                 Expr::Call(func_id) => {
-                    let opt_import = module
-                        .imports
-                        .iter()
-                        .find(|import| import.func_alias_id == func_id);
+                    let opt_import = module.import_of(&func_id);
                     if let Some(ref import) = opt_import {
                         MetaInstr::Call {
                             func_id: import.func_origin_id.clone(),
@@ -341,7 +708,7 @@ impl Compiler {
                         }
                     }
                 }
-                Expr::Alloc(arg) => match arg {
+                Expr::Alloc(arg, _) => match arg {
                     Argument::Constant(id) => {
                         let value = Compiler::find_const(&module.constants, id)?;
 
@@ -349,6 +716,7 @@ impl Compiler {
                     }
                     Argument::Literal(lit) => MetaInstr::ActualInstr(Instruction::Alloc(lit)),
                 },
+                Expr::Free(_) => MetaInstr::ActualInstr(Instruction::Free),
                 Expr::ActualInstr(instr) => MetaInstr::ActualInstr(instr),
                 _ => unreachable!(),
             };
@@ -359,6 +727,195 @@ impl Compiler {
         Ok(meta_vec)
     }
 
+    /// Returns the body of the called function if the call is eligible for inlining
+    fn inline_call(&mut self, func_id: &str, module: &Module) -> Result<Option<Vec<MetaInstr>>> {
+        let opt_level = self.config
+            .compilation
+            .opt_level
+            .unwrap_or(defaults::OPT_LEVEL);
+
+        if opt_level < 1 {
+            return Ok(None);
+        }
+
+        let callee = match self.ast.resolve_call(module, func_id) {
+            Some(callee) => callee,
+            None => return Ok(None),
+        };
+
+        let callee_module = self.ast.modules[&callee.0].clone();
+        let callee_func = callee_module
+            .funcs
+            .iter()
+            .find(|func| func.id == callee.1)
+            .cloned()
+            .ok_or(format_err!("unable to find function {:?}", callee.1))?;
+
+        // An explicit return inside of the inlined body would return from the caller instead
+        if !callee_func.is_inline
+            || self.call_graph.is_recursive(&callee)
+            || Compiler::contains_ret(&callee_func.expr)
+        {
+            return Ok(None);
+        }
+
+        let meta_instrs = self.to_meta_instr(callee_func.expr, &callee_module)?;
+
+        Ok(Some(meta_instrs))
+    }
+
+    /// Returns whether a jump with the given target leaves or continues the innermost loop, which
+    /// has the given label
+    fn targets(target: &Option<String>, label: &Option<String>) -> bool {
+        target.is_none() || target == label
+    }
+
+    /// Puts the given push in front of every `break` and `continue` that refers to the loop with
+    /// the given label, whose body the expressions are. `nested` is set inside of loops within the
+    /// body.
+    fn push_before_jumps(
+        exprs: Vec<Located>,
+        label: &Option<String>,
+        push: &Expr,
+        nested: bool,
+    ) -> Vec<Located> {
+        let mut pushed = Vec::new();
+
+        for Located { expr, line } in exprs {
+            match expr {
+                Expr::Break(ref target) | Expr::Continue(ref target)
+                    if (!nested && target.is_none()) || (target.is_some() && target == label) =>
+                {
+                    pushed.push(push.clone().into());
+                }
+                _ => {}
+            }
+
+            let expr = match expr {
+                Expr::While(mut whl) => {
+                    whl.exprs = Compiler::push_before_jumps(whl.exprs, label, push, true);
+
+                    Expr::While(whl)
+                }
+                Expr::If(mut whether) => {
+                    whether.exprs = Compiler::push_before_jumps(whether.exprs, label, push, nested);
+                    whether.else_exprs = whether
+                        .else_exprs
+                        .map(|exprs| Compiler::push_before_jumps(exprs, label, push, nested));
+
+                    Expr::If(whether)
+                }
+                expr => expr,
+            };
+
+            pushed.push(Located { expr, line });
+        }
+
+        pushed
+    }
+
+    fn contains_ret(exprs: &[Located]) -> bool {
+        exprs.iter().any(|located| match &located.expr {
+            Expr::ActualInstr(Instruction::Ret) => true,
+            Expr::While(whl) => Compiler::contains_ret(&whl.exprs),
+            Expr::If(whether) => {
+                Compiler::contains_ret(&whether.exprs)
+                    || whether
+                        .else_exprs
+                        .as_ref()
+                        .is_some_and(|exprs| Compiler::contains_ret(exprs))
+            }
+            _ => false,
+        })
+    }
+
+    /// Removes all assertions and traps from the given expressions, including the ones in nested
+    /// blocks
+    fn strip_debug_exprs(exprs: Vec<Located>) -> Vec<Located> {
+        exprs
+            .into_iter()
+            .filter_map(|Located { expr, line }| {
+                let expr = match expr {
+                    Expr::Assert(..) | Expr::Trap => return None,
+                    Expr::While(mut whl) => {
+                        whl.exprs = Compiler::strip_debug_exprs(whl.exprs);
+
+                        Expr::While(whl)
+                    }
+                    Expr::If(mut whether) => {
+                        whether.exprs = Compiler::strip_debug_exprs(whether.exprs);
+                        whether.else_exprs = whether.else_exprs.map(Compiler::strip_debug_exprs);
+
+                        Expr::If(whether)
+                    }
+                    expr => expr,
+                };
+
+                Some(Located { expr, line })
+            })
+            .collect()
+    }
+
+    /// melon has no dedicated no-op, so a jump to the next instruction is used instead
+    fn nop() -> Instruction {
+        Instruction::Jmp(true, 1)
+    }
+
+    /// melon has no dedicated trap instruction, so a zero-distance jump is used instead. The VM
+    /// rejects it as an unwanted hang, which aborts the execution of the program.
+    fn trap() -> Instruction {
+        Instruction::Jmp(true, 0)
+    }
+
+    /// Checks that a value of the given type at the address lies inside of the memory of the
+    /// program
+    fn check_access(&self, type_t: &IntegerType, address: u16) -> Result<()> {
+        let mem_size = self.ast.memory.size;
+
+        let end = u32::from(address) + u32::from(stack_effect::type_size(type_t));
+
+        ensure!(
+            end <= mem_size,
+            "the {:?} value at {:#06x} ends at {:#06x}, past the end of the memory at {:#06x}",
+            type_t,
+            address,
+            end,
+            mem_size
+        );
+
+        Ok(())
+    }
+
+    /// Returns the expression pushing the immediate of a condition with the given type
+    fn push_immediate(type_t: &IntegerType, immediate: Argument<i32>) -> Result<Expr> {
+        let value = match immediate {
+            Argument::Literal(value) => value,
+            Argument::Constant(id) => {
+                return Ok(match type_t {
+                    IntegerType::U8 => Expr::PushConstU8(Argument::Constant(id)),
+                    IntegerType::U16 => Expr::PushConstU16(Argument::Constant(id)),
+                    IntegerType::I8 => Expr::PushConstI8(Argument::Constant(id)),
+                    IntegerType::I16 => Expr::PushConstI16(Argument::Constant(id)),
+                })
+            }
+        };
+
+        let (lowest, highest) = lowering::bounds(type_t);
+        ensure!(
+            lowest <= value && value <= highest,
+            "the immediate {} of the condition doesn't fit into {:?}",
+            value,
+            type_t
+        );
+
+        Ok(match type_t {
+            IntegerType::U8 => Expr::PushConstU8(Argument::Literal(value as u8)),
+            IntegerType::U16 => Expr::PushConstU16(Argument::Literal(value as u16)),
+            IntegerType::I8 => Expr::PushConstI8(Argument::Literal(value as i8)),
+            IntegerType::I16 => Expr::PushConstI16(Argument::Literal(value as i16)),
+        })
+    }
+
     fn find_const(consts: &Vec<Const>, id: String) -> Result<i32> {
         let cons = consts
             .iter()
@@ -368,3 +925,539 @@ impl Compiler {
         Ok(cons.value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{FunctionOrder, Signal};
+    use test_utils::*;
+
+    /// Returns the instructions of the entry function up to and including the final `SysCall`
+    fn entry_instructions(program: &Program) -> &[Instruction] {
+        let instrs = &program.instructions[program.entry_point as usize..];
+        let end = instrs
+            .iter()
+            .position(|instr| matches!(instr, Instruction::SysCall(0)))
+            .unwrap();
+
+        &instrs[..=end]
+    }
+
+    #[test]
+    fn inline_functions_are_inlined_when_optimizing() {
+        let mut config = fixture_config("inline");
+        config.compilation.opt_level = Some(1);
+
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+
+        let main_instrs = entry_instructions(&program);
+        assert!(
+            !main_instrs
+                .iter()
+                .any(|instr| matches!(instr, Instruction::Call(_)))
+        );
+        assert_eq!(main_instrs.len(), 5);
+        assert!(run(&program).is_ok());
+    }
+
+    #[test]
+    fn inline_functions_are_called_without_optimizations() {
+        let mut config = fixture_config("inline");
+        config.compilation.opt_level = Some(0);
+
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+
+        let main_instrs = entry_instructions(&program);
+        assert_eq!(main_instrs.len(), 4);
+        assert!(run(&program).is_ok());
+    }
+
+    #[test]
+    fn grouped_signals_are_called_by_path() {
+        let mut config = Config::from_file("test/fixtures/signal_groups/Beast.toml").unwrap();
+        config.program.target_version = ::melon::VERSION.into();
+        config.compilation.include_dirs = vec!["test/fixtures/signal_groups".into()];
+
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![42]);
+    }
+
+    #[test]
+    fn module_paths_are_relative_to_the_importing_module() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("relative_imports"), Emit::default())
+                .unwrap();
+        assert_eq!(run(&program).unwrap(), vec![1]);
+
+        let mut config = fixture_config("relative_imports");
+        config.compilation.absolute_module_paths = Some(true);
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+        assert_eq!(run(&program).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn signals_are_named_or_numeric() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("numeric_signals"), Emit::default())
+                .unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![5, 6, 7]);
+
+        let mut config = fixture_config("numeric_signals");
+        config.signals.remove("gurgle");
+        let err = AstGen::gen("main".into(), config).unwrap_err();
+        assert!(err.to_string().contains(
+            "undefined signal \"gurgle\". Available signals are [\"fire\", \"nuke\"]"
+        ));
+    }
+
+    #[test]
+    fn stack_effect_continues_past_declared_signals() {
+        let mut config = Config::from_file("test/fixtures/sys_contract/Beast.toml").unwrap();
+        config.compilation.include_dirs = vec!["test/fixtures/sys_contract".into()];
+
+        let err = Compiler::compile("main".into(), config.clone(), Emit::default())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("stack underflow"));
+
+        config.signals.insert("write".into(), Signal::Id(4));
+        assert!(Compiler::compile("main".into(), config, Emit::default()).is_ok());
+    }
+
+    #[test]
+    fn assertions_trap_in_debug_builds() {
+        let mut config = fixture_config("assert");
+        config.compilation.debug_assertions = Some(true);
+
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+
+        assert_eq!(program.instructions.len(), 6);
+        assert!(run(&program).is_err());
+    }
+
+    #[test]
+    fn assertions_are_stripped_in_release_builds() {
+        let mut config = fixture_config("assert");
+        config.compilation.debug_assertions = Some(false);
+
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+
+        assert_eq!(program.instructions.len(), 3);
+        assert!(run(&program).is_ok());
+    }
+
+    #[test]
+    fn traps_abort_debug_builds_only() {
+        let mut config = fixture_config("trap");
+        config.compilation.debug_assertions = Some(true);
+
+        let program = Compiler::compile("main".into(), config.clone(), Emit::default()).unwrap();
+        assert!(run(&program).is_err());
+
+        config.compilation.debug_assertions = Some(false);
+
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+        assert_eq!(run(&program).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn aligned_functions_start_at_multiples_of_the_alignment() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("align"), Emit::default()).unwrap();
+
+        let calls: Vec<_> = program
+            .instructions
+            .iter()
+            .filter_map(|instr| match instr {
+                Instruction::Call(addr) => Some(*addr),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0] % 8, 0);
+        assert!(calls[0] > 0);
+
+        assert_eq!(run(&program).unwrap(), vec![42]);
+    }
+
+    #[test]
+    fn saturating_arithmetic_is_lowered() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("saturating"), Emit::default())
+                .unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![255, 0, 0x80]);
+    }
+
+    #[test]
+    fn untyped_arithmetic_uses_the_inferred_type() {
+        let program = Compiler::compile(
+            "main".into(),
+            fixture_config("untyped_arithmetic"),
+            Emit::default(),
+        ).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![250, 3]);
+    }
+
+    #[test]
+    fn break_and_continue_jump_to_their_loops() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("loop_labels"), Emit::default())
+                .unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![1, 2, 3, 0, 1, 3, 4]);
+    }
+
+    #[test]
+    fn glob_imported_constants_are_resolved() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("const_glob"), Emit::default())
+                .unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![0xe0, 9, 16]);
+    }
+
+    #[test]
+    fn functions_are_laid_out_in_the_configured_order() {
+        let entry_point = |order| {
+            let mut config = fixture_config("function_order");
+            config.compilation.function_order = order;
+
+            Compiler::compile("main".into(), config, Emit::default())
+                .unwrap()
+                .entry_point
+        };
+
+        assert_eq!(entry_point(None), 0);
+        assert_eq!(entry_point(Some(FunctionOrder::Source)), 3);
+        assert_eq!(entry_point(Some(FunctionOrder::Callgraph)), 0);
+    }
+
+    #[test]
+    fn bit_counts_are_lowered() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("count_bits"), Emit::default())
+                .unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![3, 8, 8]);
+    }
+
+    #[test]
+    fn constant_group_members_are_resolved() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("constant_group"), Emit::default())
+                .unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn targets_select_settings() {
+        let mut config = Config::from_file("test/fixtures/targets/Beast.toml").unwrap();
+        config.compilation.include_dirs = vec!["test/fixtures/targets".into()];
+
+        let program =
+            Compiler::compile("main".into(), config.with_target("small").unwrap(), Emit::default())
+                .unwrap();
+
+        assert_eq!(program.mem_pages, Some(4));
+        assert!(matches!(entry_instructions(&program)[0], Instruction::SysCall(7)));
+    }
+
+    #[test]
+    fn tee_stores_and_keeps_the_value() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("tee"), Emit::default()).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![7, 7, 9, 9]);
+    }
+
+    #[test]
+    fn clamp_constrains_the_value() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("clamp"), Emit::default()).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![100, 42, -3i8 as u8]);
+
+        let config = fixture_config("clamp_inverted");
+        let err = Compiler::compile("main".into(), config, Emit::default()).unwrap_err();
+        assert_eq!(err.to_string(), "the clamp minimum 100 is greater than the maximum 0");
+    }
+
+    #[test]
+    fn memory_is_copied_and_set() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("mem_copy"), Emit::default()).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![5, 6, 9]);
+
+        let config = fixture_config("mem_copy_underflow");
+        let err = Compiler::compile("main".into(), config, Emit::default()).unwrap_err();
+        assert!(err.to_string().contains("stack underflow"));
+    }
+
+    #[test]
+    fn denied_warnings_fail_the_compilation() {
+        let mut config = fixture_config("max_func_size");
+        config.compilation.max_func_instructions = Some(5);
+        assert!(Compiler::compile("main".into(), config.clone(), Emit::default()).is_ok());
+
+        config.compilation.deny_warnings = Some(true);
+        let err = Compiler::compile("main".into(), config.clone(), Emit::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "compilation failed because warnings are denied (1 warning)"
+        );
+
+        config.compilation.max_func_instructions = Some(1);
+        let err = Compiler::compile("main".into(), config, Emit::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "compilation failed because warnings are denied (2 warnings)"
+        );
+    }
+
+    #[test]
+    fn entry_parameters_count_towards_the_stack() {
+        // Without the parameters, dropping them would underflow the stack
+        let config = fixture_config("entry_params");
+        assert!(Compiler::compile("main".into(), config, Emit::default()).is_ok());
+    }
+
+    #[test]
+    fn memory_accesses_stay_inside_the_memory() {
+        let mut config = fixture_config("memory_bounds");
+        assert!(Compiler::compile("main".into(), config.clone(), Emit::default()).is_ok());
+
+        // One page ends right after the u16 at 0x03fe
+        config.program.mem_pages = Some(1);
+        let err = Compiler::compile("main".into(), config, Emit::default()).unwrap_err();
+        assert_eq!(
+            err.cause().to_string(),
+            "the U16 value at 0x0400 ends at 0x0402, past the end of the memory at 0x0400"
+        );
+    }
+
+    #[test]
+    fn entry_names_are_configurable() {
+        let mut config = fixture_config("entry_func");
+        config.compilation.entry_func = Some("$start".into());
+
+        let program = Compiler::compile("start".into(), config, Emit::default()).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![5]);
+    }
+
+    #[test]
+    fn conditions_compare_with_immediates() {
+        let config = fixture_config("immediate_conditions");
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![1, 3, 2, 1]);
+    }
+
+    #[test]
+    fn quoted_module_paths_are_unescaped() {
+        let config = fixture_config("quoted_import");
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![42]);
+    }
+
+    #[test]
+    fn lazily_discovered_programs_run() {
+        let mut config = fixture_config("lazy_modules");
+        config.compilation.lazy_modules = Some(true);
+
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+        assert_eq!(run(&program).unwrap(), vec![42]);
+    }
+
+    #[test]
+    fn capped_loops_stop_after_the_maximum() {
+        let config = fixture_config("loop_max");
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![7, 7, 7, 8, 8, 9]);
+    }
+
+    #[test]
+    fn capped_loops_keep_their_counter_around_nested_capped_loops() {
+        let config = fixture_config("loop_max_nested");
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![1, 2, 2, 1, 2, 2, 3]);
+    }
+
+    #[test]
+    fn capped_loops_allow_allocations_and_calls() {
+        let config = fixture_config("loop_max_alloc");
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![4, 4, 4]);
+    }
+
+    #[test]
+    fn capped_loops_reject_recursive_functions() {
+        let config = fixture_config("loop_max_recursive");
+        let err = Compiler::compile("main".into(), config, Emit::default()).unwrap_err();
+
+        assert!(err.to_string().ends_with("because it calls itself"));
+    }
+
+    #[test]
+    fn inlined_functions_resolve_constants_of_their_module() {
+        for opt_level in 0..2 {
+            let mut config = fixture_config("private_constants");
+            config.compilation.opt_level = Some(opt_level);
+
+            let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+
+            let inlined = !entry_instructions(&program)
+                .iter()
+                .any(|instr| matches!(instr, Instruction::Call(_)));
+            assert_eq!(inlined, opt_level > 0);
+            assert_eq!(run(&program).unwrap(), vec![7]);
+        }
+    }
+
+    #[test]
+    fn builds_are_reproducible() {
+        // The modules are generated by concurrent workers, so they arrive in any order
+        for fixture in &["module_alias", "private_constants", "else_if"] {
+            let build = || {
+                let ast = AstGen::gen("main".into(), fixture_config(fixture)).unwrap();
+                let program =
+                    Compiler::compile("main".into(), fixture_config(fixture), Emit::default())
+                        .unwrap();
+
+                (format!("{:?}", ast), format!("{:?}", program.instructions))
+            };
+
+            assert_eq!(build(), build(), "{}", fixture);
+        }
+    }
+
+    #[test]
+    fn module_aliases_resolve_calls() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("module_alias"), Emit::default())
+                .unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![9]);
+    }
+
+    #[test]
+    fn module_aliases_accept_quoted_paths() {
+        let ast = AstGen::gen("main".into(), fixture_config("module_alias_quoted")).unwrap();
+
+        let aliases = &ast.modules["main"].module_aliases;
+        assert_eq!(aliases["m"], "math.ops");
+        assert_eq!(aliases["d"], "double");
+        assert_eq!(
+            ast.modules["main"].warnings,
+            vec!["the extension of \"double.beast\" is unnecessary, import it as \"double\""]
+        );
+
+        let config = fixture_config("module_alias_quoted");
+        let program = Compiler::compile("main".into(), config, Emit::default()).unwrap();
+        assert_eq!(run(&program).unwrap(), vec![18]);
+    }
+
+    #[test]
+    fn passes_can_instrument_functions() {
+        let mut report_entry = |ast: &mut Ast| {
+            for module in ast.modules.values_mut() {
+                for func in &mut module.funcs {
+                    func.expr.insert(0, Expr::PushConstU8(Argument::Literal(42)).into());
+                    func.expr.insert(1, Expr::Sys(":gurgle".into()).into());
+                }
+            }
+
+            Ok(())
+        };
+
+        let program = Compiler::compile_with_pass(
+            "main".into(),
+            fixture_config("module_alias"),
+            Emit::default(),
+            &mut report_entry,
+        ).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![42, 42, 9]);
+    }
+
+    #[test]
+    fn else_if_chains_take_the_first_matching_branch() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("else_if"), Emit::default()).unwrap();
+
+        assert_eq!(run(&program).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn multi_value_pushes_push_every_value() {
+        let program =
+            Compiler::compile("main".into(), fixture_config("multi_push"), Emit::default())
+                .unwrap();
+
+        let pushes = entry_instructions(&program)
+            .iter()
+            .filter(|instr| matches!(instr, Instruction::PushConstU8(_)))
+            .count();
+        assert_eq!(pushes, 3);
+        assert_eq!(run(&program).unwrap(), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn large_module_trees_are_compiled_by_few_workers() {
+        use std::{env, fs};
+
+        // A binary tree of 255 modules, each counting the modules of its subtree
+        let dir = env::temp_dir().join(format!("beast_module_tree_{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Module ids can't contain digits, so the modules are named by their path from the root
+        let name = |node: usize| {
+            let path = format!("{:b}", node)[1..].replace('0', "l").replace('1', "r");
+            if path.is_empty() {
+                "main".into()
+            } else {
+                format!("node_{}", path)
+            }
+        };
+        for node in 1..256 {
+            let mut source = String::new();
+            if node < 128 {
+                source.push_str(&format!(
+                    "(import $count as $left from {})\n(import $count as $right from {})\n\
+                     (func $count (push u8 1) (call $left) (add u8) (call $right) (add u8))\n",
+                    name(node * 2),
+                    name(node * 2 + 1)
+                ));
+            } else {
+                source.push_str("(func $count (push u8 1))\n");
+            }
+
+            if node == 1 {
+                source.push_str("(func $main (call $count) (sys :gurgle))\n");
+            } else {
+                source.push_str("(export $count)\n");
+            }
+
+            fs::write(dir.join(format!("{}.beast", name(node))), source).unwrap();
+        }
+
+        let mut config = test_config();
+        config.compilation.include_dirs = vec![dir.to_string_lossy().into_owned()];
+        config.compilation.jobs = Some(2);
+
+        let result = Compiler::compile("main".into(), config, Emit::default());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(run(&result.unwrap()).unwrap(), vec![255]);
+    }
+}
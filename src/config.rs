@@ -1,5 +1,15 @@
-use melon::typedef::*;
-use std::{collections::BTreeMap, fs::File, io::Read, path::Path};
+use defaults;
+use failure::ResultExt;
+use melon::{typedef::*, MEM_PAGE};
+use serde::{Deserialize, Deserializer};
+use stack_effect::StackEffect;
+use std::{collections::BTreeMap,
+          fmt::Display,
+          fs::File,
+          io::{self, Read},
+          path::Path,
+          str::FromStr,
+          thread};
 use toml;
 
 #[derive(Deserialize, Debug, Clone)]
@@ -9,17 +19,162 @@ pub struct Program {
     pub target_version: String,
     pub system_id: String,
     pub mem_pages: Option<u8>,
+    #[serde(default)]
+    pub memory: Memory,
+}
+
+/// The division of the memory of the program, declared as `[program.memory]`
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Memory {
+    /// The number of bytes at the end of the memory reserved for the stack
+    pub stack_size: Option<u32>,
+    /// The address of the first byte of the heap, which ends where the stack starts
+    pub heap_start: Option<u32>,
+}
+
+/// The validated memory layout of the program, with the heap below the stack
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLayout {
+    /// The number of bytes of the memory
+    pub size: u32,
+    /// The address of the first byte of the heap
+    pub heap_start: u32,
+    /// The address of the first byte of the stack, which ends with the memory
+    pub stack_start: u32,
 }
 
 #[derive(Deserialize, Debug, Default, Clone)]
 pub struct Compilation {
+    /// The module the program starts in
     pub entry_point: Option<String>,
+    /// The function of the entry module the program starts with
+    pub entry_func: Option<String>,
     /// The paths to look for libraries
     #[serde(default, rename = "lib")]
     pub lib_dirs: Vec<String>,
     /// The paths to look for files to include
     #[serde(default, rename = "include")]
     pub include_dirs: Vec<String>,
+    /// Whether to keep `assert` instructions in the compiled program
+    pub debug_assertions: Option<bool>,
+    /// The level of optimizations applied to the compiled program
+    pub opt_level: Option<u8>,
+    /// Whether only `.beast` files are accepted as modules, rejecting the short `.bst`
+    pub strict_extensions: Option<bool>,
+    /// Whether a module found in more than one search path is an error instead of the first one
+    /// being used
+    pub unique_modules: Option<bool>,
+    /// Whether only the modules providing functions reachable from the entry function are parsed
+    /// instead of every imported module
+    pub lazy_modules: Option<bool>,
+    /// The number of expressions, including those of loops and conditionals, above which a
+    /// function is reported
+    pub max_func_instructions: Option<usize>,
+    /// Whether warnings fail the compilation
+    pub deny_warnings: Option<bool>,
+    /// The number of loops and conditionals that may be nested inside of each other
+    pub max_nesting: Option<usize>,
+    /// The order the functions of each module are laid out in. Without it they are laid out in
+    /// the order of the names they are compiled to.
+    pub function_order: Option<FunctionOrder>,
+    /// Whether imported modules are only looked up at the search paths instead of next to the
+    /// importing module first
+    pub absolute_module_paths: Option<bool>,
+    /// Whether a constant may be defined again with the same value
+    pub allow_identical_constants: Option<bool>,
+    /// The number of modules parsed at the same time, the number of CPUs by default
+    pub jobs: Option<usize>,
+    /// Whether every module is parsed and checked even after errors, reporting all of them at
+    /// once. Errors reading a module still stop the compilation right away.
+    pub collect_errors: Option<bool>,
+}
+
+/// The order the functions of each module are laid out in
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FunctionOrder {
+    /// The order they are defined in
+    Source,
+    /// Sorted by their ids
+    Name,
+    /// Each function followed by the functions it calls, see `CallGraph::order`
+    Callgraph,
+}
+
+/// A system call signal, optionally declaring how many bytes it takes off the stack and puts back
+/// onto it
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Signal {
+    Id(u16),
+    Contract { id: u16, pops: u16, pushes: u16 },
+}
+
+impl Signal {
+    pub fn id(&self) -> u16 {
+        match self {
+            Signal::Id(id) | Signal::Contract { id, .. } => *id,
+        }
+    }
+
+    pub fn stack_effect(&self) -> Option<StackEffect> {
+        match self {
+            Signal::Id(_) => None,
+            Signal::Contract { pops, pushes, .. } => Some(StackEffect {
+                pops: *pops,
+                pushes: *pushes,
+            }),
+        }
+    }
+}
+
+/// An entry of the signals table, either a signal or a group of further entries
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SignalEntry {
+    Signal(Signal),
+    Group(BTreeMap<String, SignalEntry>),
+}
+
+/// Reads a signals table, naming the signals of groups like `[signals.io]` as `io.write`
+fn deserialize_signals<'de, D>(
+    deserializer: D,
+) -> ::std::result::Result<BTreeMap<String, Signal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    fn flatten(
+        prefix: &str,
+        entries: BTreeMap<String, SignalEntry>,
+        signals: &mut BTreeMap<String, Signal>,
+    ) {
+        for (name, entry) in entries {
+            let name = format!("{}{}", prefix, name);
+
+            match entry {
+                SignalEntry::Signal(signal) => {
+                    signals.insert(name, signal);
+                }
+                SignalEntry::Group(group) => flatten(&format!("{}.", name), group, signals),
+            }
+        }
+    }
+
+    let mut signals = BTreeMap::new();
+    flatten("", BTreeMap::deserialize(deserializer)?, &mut signals);
+
+    Ok(signals)
+}
+
+/// Settings replacing those of the program when building for a named target
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Target {
+    pub target_version: Option<String>,
+    pub system_id: Option<String>,
+    pub mem_pages: Option<u8>,
+    /// The signals added to or replacing the signals of the program
+    #[serde(default, deserialize_with = "deserialize_signals")]
+    pub signals: BTreeMap<String, Signal>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -27,23 +182,183 @@ pub struct Config {
     pub program: Program,
     #[serde(default)]
     pub compilation: Compilation,
-    #[serde(default)]
-    pub signals: BTreeMap<String, u16>,
+    #[serde(default, deserialize_with = "deserialize_signals")]
+    pub signals: BTreeMap<String, Signal>,
+    #[serde(default, rename = "target")]
+    pub targets: BTreeMap<String, Target>,
 }
 
 impl Config {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
-        let mut file = File::open(path)?;
+        let path = path.as_ref();
+
+        let mut file = File::open(path).with_context(|e: &io::Error| {
+            let hint = if e.kind() == io::ErrorKind::NotFound {
+                "\nrun `beast new <path>` to create a new project"
+            } else {
+                ""
+            };
+
+            format!("could not open the manifest at {}: {}{}", path.display(), e, hint)
+        })?;
 
         let mut buf = String::new();
         file.read_to_string(&mut buf)?;
 
-        let config = toml::from_str(&buf)?;
+        Config::parse(&buf, &path.display())
+    }
+
+    /// Parses and validates the manifest, naming it by the given origin in errors
+    fn parse(source: &str, origin: &dyn Display) -> Result<Config> {
+        let config: Config = toml::from_str(source).with_context(|e: &toml::de::Error| {
+            let location = if let Some((line, col)) = e.line_col() {
+                format!(" (line {}, column {})", line + 1, col + 1)
+            } else {
+                String::new()
+            };
+
+            format!("failed to parse {}{}\n{}", origin, location, e)
+        })?;
+
+        config
+            .validate()
+            .with_context(|e| format!("invalid setting in {}: {}", origin, e))?;
+        config
+            .memory_layout()
+            .with_context(|e| format!("invalid memory layout in {}: {}", origin, e))?;
+
+        Ok(config)
+    }
+
+    /// Checks that the settings of the program and every target are in the range the target can
+    /// handle
+    pub fn validate(&self) -> Result<()> {
+        check_mem_pages("program.mem_pages", self.program.mem_pages)?;
+
+        for (name, target) in &self.targets {
+            check_mem_pages(&format!("target.{}.mem_pages", name), target.mem_pages)?;
+        }
+
+        ensure!(
+            self.compilation.jobs != Some(0),
+            "compilation.jobs is 0, but at least one module has to be parsed at a time"
+        );
+
+        Ok(())
+    }
+
+    /// Returns the configured entry function or the default one
+    pub fn entry_func(&self) -> String {
+        self.compilation
+            .entry_func
+            .clone()
+            .unwrap_or_else(|| defaults::ENTRY_POINT_FUNC.into())
+    }
+
+    /// Returns the configured number of jobs or the number of CPUs
+    pub fn jobs(&self) -> usize {
+        self.compilation.jobs.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|jobs| jobs.get())
+                .unwrap_or(1)
+        })
+    }
+
+    /// Resolves the memory layout of the program, making sure the stack fits into the memory and
+    /// doesn't overlap the heap
+    pub fn memory_layout(&self) -> Result<MemoryLayout> {
+        let mem_pages = self.program.mem_pages.unwrap_or(defaults::MEM_PAGES);
+        let size = u32::from(mem_pages) * MEM_PAGE as u32;
+
+        let memory = &self.program.memory;
+        let stack_size = memory.stack_size.unwrap_or(defaults::STACK_SIZE);
+        let heap_start = memory.heap_start.unwrap_or(defaults::HEAP_START);
+
+        ensure!(
+            stack_size <= size,
+            "the stack of {} bytes doesn't fit into the {} bytes of {} memory pages",
+            stack_size,
+            size,
+            mem_pages
+        );
+
+        let stack_start = size - stack_size;
+
+        ensure!(
+            heap_start <= stack_start,
+            "the heap starting at {:#06x} overlaps the stack at {:#06x} to {:#06x}",
+            heap_start,
+            stack_start,
+            size
+        );
+
+        Ok(MemoryLayout {
+            size,
+            heap_start,
+            stack_start,
+        })
+    }
+
+    /// Returns the configuration with the settings of the given target applied
+    pub fn with_target(&self, name: &str) -> Result<Config> {
+        let target = match self.targets.get(name) {
+            Some(target) => target.clone(),
+            None => bail!(
+                "unknown target {:?}, expected one of {:?}",
+                name,
+                self.targets.keys().collect::<Vec<_>>()
+            ),
+        };
+
+        let mut config = self.clone();
+
+        if let Some(target_version) = target.target_version {
+            config.program.target_version = target_version;
+        }
+        if let Some(system_id) = target.system_id {
+            config.program.system_id = system_id;
+        }
+        if let Some(mem_pages) = target.mem_pages {
+            config.program.mem_pages = Some(mem_pages);
+        }
+        config.signals.extend(target.signals);
+
+        config
+            .memory_layout()
+            .with_context(|e| format!("invalid memory layout for target {:?}: {}", name, e))?;
 
         Ok(config)
     }
 }
 
+fn check_mem_pages(field: &str, mem_pages: Option<u8>) -> Result<()> {
+    if let Some(mem_pages) = mem_pages {
+        ensure!(
+            mem_pages > 0,
+            "{} is 0, but the program needs at least one memory page",
+            field
+        );
+        ensure!(
+            mem_pages <= defaults::MAX_MEM_PAGES,
+            "{} is {}, more than the {} memory pages the target can address",
+            field,
+            mem_pages,
+            defaults::MAX_MEM_PAGES
+        );
+    }
+
+    Ok(())
+}
+
+impl FromStr for Config {
+    type Err = ::failure::Error;
+
+    /// Parses the manifest from its TOML source, e.g. to build a configuration in memory
+    fn from_str(source: &str) -> Result<Config> {
+        Config::parse(source, &"the manifest")
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -56,4 +371,187 @@ mod tests {
 
         println!("{:#?}", config);
     }
+
+    #[test]
+    fn config_from_str() {
+        let source = r#"
+            [program]
+            name = "in_memory"
+            target_version = "0.13.0"
+            system_id = "__TEST_SYSTEM__"
+            mem_pages = 2
+
+            [compilation]
+            entry_func = "$start"
+
+            [signals]
+            gurgle = 1
+        "#;
+
+        let config: Config = source.parse().unwrap();
+        assert_eq!(config.program.name, "in_memory");
+        assert_eq!(config.program.mem_pages, Some(2));
+        assert_eq!(config.entry_func(), "$start");
+        assert_eq!(config.signals["gurgle"].id(), 1);
+
+        let file_config = Config::from_file("test/Beast.toml").unwrap();
+        let source = ::std::fs::read_to_string("test/Beast.toml").unwrap();
+        let str_config = Config::from_str(&source).unwrap();
+        assert_eq!(str_config.program.name, file_config.program.name);
+        assert_eq!(
+            str_config.signals.keys().collect::<Vec<_>>(),
+            file_config.signals.keys().collect::<Vec<_>>()
+        );
+
+        let err = Config::from_str("[program]\nname = 1").unwrap_err();
+        assert!(err.to_string().starts_with("failed to parse the manifest\n"));
+    }
+
+    #[test]
+    fn mem_pages_are_validated() {
+        let manifest = |mem_pages| {
+            format!(
+                "[program]\nname = \"pages\"\ntarget_version = \"0.13.0\"\n\
+                 system_id = \"__TEST_SYSTEM__\"\nmem_pages = {}\n\
+                 [target.small]\nmem_pages = 1",
+                mem_pages
+            )
+        };
+
+        assert!(manifest(64).parse::<Config>().is_ok());
+        assert_eq!(
+            manifest(0).parse::<Config>().unwrap_err().to_string(),
+            "invalid setting in the manifest: program.mem_pages is 0, but the program needs at \
+             least one memory page"
+        );
+        assert_eq!(
+            manifest(65).parse::<Config>().unwrap_err().to_string(),
+            "invalid setting in the manifest: program.mem_pages is 65, more than the 64 memory \
+             pages the target can address"
+        );
+
+        let mut config = manifest(4).parse::<Config>().unwrap();
+        config.targets.get_mut("small").unwrap().mem_pages = Some(0);
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "target.small.mem_pages is 0, but the program needs at least one memory page"
+        );
+    }
+
+    #[test]
+    fn jobs_are_at_least_one() {
+        let mut config = Config::from_file("test/Beast.toml").unwrap();
+        assert!(config.jobs() >= 1);
+
+        config.compilation.jobs = Some(3);
+        assert_eq!(config.jobs(), 3);
+
+        config.compilation.jobs = Some(0);
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "compilation.jobs is 0, but at least one module has to be parsed at a time"
+        );
+    }
+
+    #[test]
+    fn broken_config_reports_path() {
+        const FILE_NAME: &str = "test/fixtures/broken_config/Beast.toml";
+
+        let err = Config::from_file(FILE_NAME).unwrap_err();
+        let message = err.to_string();
+
+        assert!(message.contains(FILE_NAME));
+        assert!(message.contains("line 3"));
+    }
+
+    #[test]
+    fn missing_config_suggests_creating_a_project() {
+        const FILE_NAME: &str = "test/fixtures/missing/Beast.toml";
+
+        let message = Config::from_file(FILE_NAME).unwrap_err().to_string();
+
+        assert!(message.starts_with(&format!("could not open the manifest at {}", FILE_NAME)));
+        assert!(message.ends_with("\nrun `beast new <path>` to create a new project"));
+    }
+
+    #[test]
+    fn signal_contracts() {
+        const FILE_NAME: &str = "test/fixtures/sys_contract/Beast.toml";
+
+        let config = Config::from_file(FILE_NAME).unwrap();
+
+        assert_eq!(config.signals["halt_all"].id(), 1);
+        assert_eq!(config.signals["halt_all"].stack_effect(), None);
+        assert_eq!(config.signals["write"].id(), 4);
+        assert_eq!(
+            config.signals["write"].stack_effect(),
+            Some(StackEffect { pops: 2, pushes: 0 })
+        );
+    }
+
+    #[test]
+    fn targets_override_program_settings() {
+        const FILE_NAME: &str = "test/fixtures/targets/Beast.toml";
+
+        let config = Config::from_file(FILE_NAME).unwrap();
+        assert_eq!(config.program.mem_pages, Some(34));
+
+        let small = config.with_target("small").unwrap();
+        assert_eq!(small.program.mem_pages, Some(4));
+        assert_eq!(small.program.system_id, "__TEST_SYSTEM__");
+        assert_eq!(small.signals["gurgle"].id(), 7);
+        assert_eq!(small.signals["nuke"].id(), 2);
+
+        let large = config.with_target("large").unwrap();
+        assert_eq!(large.program.mem_pages, Some(64));
+        assert_eq!(large.signals["gurgle"].id(), 1);
+
+        assert!(config.with_target("huge").is_err());
+    }
+
+    #[test]
+    fn memory_layouts_are_validated() {
+        const FILE_NAME: &str = "test/fixtures/memory_layout/Beast.toml";
+
+        let config = Config::from_file(FILE_NAME).unwrap();
+        assert_eq!(
+            config.memory_layout().unwrap(),
+            MemoryLayout {
+                size: 2048,
+                heap_start: 0x300,
+                stack_start: 1536,
+            }
+        );
+
+        assert_eq!(
+            config.with_target("tiny").unwrap_err().to_string(),
+            "invalid memory layout for target \"tiny\": the heap starting at 0x0300 overlaps the \
+             stack at 0x0200 to 0x0400"
+        );
+
+        let err = Config::from_file("test/fixtures/oversized_stack/Beast.toml").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid memory layout in test/fixtures/oversized_stack/Beast.toml: the stack of 2048 \
+             bytes doesn't fit into the 1024 bytes of 1 memory pages"
+        );
+    }
+
+    #[test]
+    fn signal_groups_are_named_by_path() {
+        const FILE_NAME: &str = "test/fixtures/signal_groups/Beast.toml";
+
+        let config = Config::from_file(FILE_NAME).unwrap();
+
+        assert_eq!(
+            config.signals.keys().collect::<Vec<_>>(),
+            vec!["gfx.screen.clear", "io.report", "io.write", "nuke"]
+        );
+        assert_eq!(config.signals["io.report"].id(), 1);
+        assert_eq!(
+            config.signals["io.write"].stack_effect(),
+            Some(StackEffect { pops: 2, pushes: 0 })
+        );
+        assert_eq!(config.signals["gfx.screen.clear"].id(), 5);
+    }
 }
@@ -0,0 +1,130 @@
+use melon::typedef::*;
+use parser::{BeastParser, Rule};
+use pest::Parser;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Whitespace,
+    Comment,
+    OpeningBrace,
+    ClosingBrace,
+    /// Anything between braces, whitespace and comments, e.g. keywords, ids and literals
+    Word,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+}
+
+/// The tokens of a source file including whitespace and comments, so printing the stream
+/// reproduces the source byte for byte
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenStream {
+    pub tokens: Vec<Token>,
+}
+
+impl TokenStream {
+    pub fn parse(source: &str) -> Result<TokenStream> {
+        let mut pairs = BeastParser::parse(Rule::token_stream, source)
+            .map_err(|e| format_err!("{}", e))?;
+
+        let tokens = pairs
+            .next()
+            .unwrap()
+            .into_inner()
+            .map(|pair| {
+                let kind = match pair.as_rule() {
+                    Rule::space_token => TokenKind::Whitespace,
+                    Rule::comment_token => TokenKind::Comment,
+                    Rule::opening_brace_token => TokenKind::OpeningBrace,
+                    Rule::closing_brace_token => TokenKind::ClosingBrace,
+                    Rule::word_token => TokenKind::Word,
+                    _ => unreachable!(),
+                };
+
+                Token {
+                    kind,
+                    text: pair.as_str().into(),
+                }
+            })
+            .collect();
+
+        Ok(TokenStream { tokens })
+    }
+}
+
+impl fmt::Display for TokenStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for token in &self.tokens {
+            f.write_str(&token.text)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Collects the source files below the given directory
+    fn sources(dir: &str, found: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+
+            if path.is_dir() {
+                sources(path.to_str().unwrap(), found);
+            } else if matches!(path.extension(), Some(ext) if ext == "beast" || ext == "bst") {
+                found.push(fs::read_to_string(path).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn token_streams_round_trip() {
+        let mut found = vec![include_str!("templates/main.bst").to_string()];
+        sources("test", &mut found);
+        assert!(found.len() > 10);
+
+        for source in found {
+            let stream = TokenStream::parse(&source).unwrap();
+
+            assert_eq!(stream.to_string(), source);
+        }
+    }
+
+    #[test]
+    fn token_streams_keep_comments() {
+        let source = "(func $main ;; entry\n  (; nothing ;) (ret))";
+
+        let kinds: Vec<_> = TokenStream::parse(source)
+            .unwrap()
+            .tokens
+            .into_iter()
+            .map(|token| token.kind)
+            .collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::OpeningBrace,
+                TokenKind::Word,
+                TokenKind::Whitespace,
+                TokenKind::Word,
+                TokenKind::Whitespace,
+                TokenKind::Comment,
+                TokenKind::Whitespace,
+                TokenKind::Comment,
+                TokenKind::Whitespace,
+                TokenKind::OpeningBrace,
+                TokenKind::Word,
+                TokenKind::ClosingBrace,
+                TokenKind::ClosingBrace,
+            ]
+        );
+    }
+}
@@ -2,3 +2,18 @@ pub const LIB_PATH: &str = "lib";
 pub const INCLUDE_PATH: &str = "src";
 pub const BIN_ENTRY_POINT_MODULE: &str = "main";
 pub const ENTRY_POINT_FUNC: &str = "$main";
+pub const SYSTEM_ID: &str = "__DEBUG__";
+pub const DEBUG_ASSERTIONS: bool = true;
+pub const OPT_LEVEL: u8 = 0;
+pub const STRICT_EXTENSIONS: bool = false;
+pub const UNIQUE_MODULES: bool = false;
+pub const LAZY_MODULES: bool = false;
+pub const ABSOLUTE_MODULE_PATHS: bool = false;
+pub const ALLOW_IDENTICAL_CONSTANTS: bool = false;
+pub const DENY_WARNINGS: bool = false;
+pub const COLLECT_ERRORS: bool = false;
+pub const MAX_NESTING: usize = 256;
+pub const MEM_PAGES: u8 = 32;
+pub const MAX_MEM_PAGES: u8 = 64;
+pub const STACK_SIZE: u32 = 0;
+pub const HEAP_START: u32 = 0;
@@ -0,0 +1,188 @@
+//! Structured warnings and errors for tools like editors and CI annotations.
+//!
+//! `diagnostics_to_json` renders diagnostics as a JSON array. Every diagnostic has the same
+//! fields, which are `null` when unknown:
+//!
+//! ```json
+//! [
+//!   {
+//!     "level": "error",
+//!     "message": "unknown function attribute: \"fast\"",
+//!     "module": "main",
+//!     "line": 3,
+//!     "col": 1,
+//!     "span": { "start": 24, "end": 33 },
+//!     "expected": ["closing_brace", "instr"]
+//!   }
+//! ]
+//! ```
+//!
+//! Lines and columns start at 1, the span holds the byte offsets of the start and the end in the
+//! source of the module. `expected` lists the grammar rules a parse error would have accepted at
+//! its position, so editors can offer completions, and is empty for every other diagnostic.
+
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Warning,
+    Error,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Warning => "warning",
+            Level::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    /// The module the diagnostic is about
+    pub module: Option<String>,
+    /// The line and the column in the source of the module
+    pub location: Option<(usize, usize)>,
+    /// The byte offsets of the start and the end in the source of the module
+    pub span: Option<(usize, usize)>,
+    /// The grammar rules that would have been accepted at the location
+    pub expected: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(level: Level, message: String) -> Diagnostic {
+        Diagnostic {
+            level,
+            message,
+            module: None,
+            location: None,
+            span: None,
+            expected: Vec::new(),
+        }
+    }
+
+    pub fn warning(message: String) -> Diagnostic {
+        Diagnostic::new(Level::Warning, message)
+    }
+
+    pub fn error(message: String) -> Diagnostic {
+        Diagnostic::new(Level::Error, message)
+    }
+
+    pub fn in_module(mut self, module: String) -> Diagnostic {
+        self.module = Some(module);
+        self
+    }
+
+    pub fn at(mut self, line: usize, col: usize) -> Diagnostic {
+        self.location = Some((line, col));
+        self
+    }
+
+    pub fn with_span(mut self, start: usize, end: usize) -> Diagnostic {
+        self.span = Some((start, end));
+        self
+    }
+
+    pub fn expecting(mut self, expected: Vec<String>) -> Diagnostic {
+        self.expected = expected;
+        self
+    }
+}
+
+/// Renders the diagnostics as a JSON array as documented in the module
+pub fn diagnostics_to_json(diagnostics: &[Diagnostic]) -> String {
+    let objects: Vec<_> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let module = match diagnostic.module {
+                Some(ref module) => json_string(module),
+                None => "null".into(),
+            };
+            let (line, col) = match diagnostic.location {
+                Some((line, col)) => (line.to_string(), col.to_string()),
+                None => ("null".into(), "null".into()),
+            };
+            let span = match diagnostic.span {
+                Some((start, end)) => format!("{{\"start\":{},\"end\":{}}}", start, end),
+                None => "null".into(),
+            };
+            let expected: Vec<_> = diagnostic
+                .expected
+                .iter()
+                .map(|rule| json_string(rule))
+                .collect();
+
+            format!(
+                concat!(
+                    "{{\"level\":\"{}\",\"message\":{},\"module\":{},",
+                    "\"line\":{},\"col\":{},\"span\":{},\"expected\":[{}]}}"
+                ),
+                diagnostic.level.name(),
+                json_string(&diagnostic.message),
+                module,
+                line,
+                col,
+                span,
+                expected.join(",")
+            )
+        })
+        .collect();
+
+    format!("[{}]", objects.join(","))
+}
+
+/// Quotes the string and escapes the characters JSON doesn't allow in strings
+pub(crate) fn json_string(raw: &str) -> String {
+    let mut quoted = String::from("\"");
+
+    for c in raw.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostics_are_serialized_with_every_field() {
+        let diagnostics = vec![
+            Diagnostic::warning("in $f: buf allocated but never freed".into())
+                .in_module("main".into()),
+            Diagnostic::error("unexpected \"token\"\n\tat the end".into())
+                .in_module("util".into())
+                .at(3, 7)
+                .with_span(24, 33)
+                .expecting(vec!["instr".into(), "closing_brace".into()]),
+        ];
+
+        assert_eq!(
+            diagnostics_to_json(&diagnostics),
+            concat!(
+                "[",
+                r#"{"level":"warning","message":"in $f: buf allocated but never freed","#,
+                r#""module":"main","line":null,"col":null,"span":null,"expected":[]},"#,
+                r#"{"level":"error","message":"unexpected \"token\"\n\tat the end","#,
+                r#""module":"util","line":3,"col":7,"span":{"start":24,"end":33},"#,
+                r#""expected":["instr","closing_brace"]}"#,
+                "]"
+            )
+        );
+        assert_eq!(diagnostics_to_json(&[]), "[]");
+    }
+}
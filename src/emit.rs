@@ -0,0 +1,164 @@
+//! Renders the artifacts of a build besides the program, selected by an `EmitKind`.
+//!
+//! The kinds are named like the `--emit` option of compilers, e.g. `"ast-json".parse()` selects
+//! `EmitKind::AstJson`.
+
+use ast::*;
+use call_graph::CallGraph;
+use flate2::{write::GzEncoder, Compression};
+use melon::typedef::*;
+use rmps;
+use serde_json;
+use std::{io::Write, str::FromStr};
+
+const KIND_NAMES: &[(&str, EmitKind)] = &[
+    ("ast-json", EmitKind::AstJson),
+    ("ast-binary", EmitKind::AstBinary),
+    ("listing", EmitKind::Listing),
+    ("dot", EmitKind::Dot),
+    ("depfile", EmitKind::Depfile),
+    ("lib", EmitKind::Lib),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmitKind {
+    /// The whole AST as JSON
+    AstJson,
+    /// The whole AST as gzip compressed MessagePack
+    AstBinary,
+    /// The expressions of every function with their source lines
+    Listing,
+    /// The call graph in the DOT language of Graphviz
+    Dot,
+    /// A Makefile rule for every module read from a file, depending on the files of the modules
+    /// it imports
+    Depfile,
+    /// A precompiled library
+    Lib,
+}
+
+impl FromStr for EmitKind {
+    type Err = ::failure::Error;
+
+    fn from_str(name: &str) -> Result<EmitKind> {
+        match KIND_NAMES.iter().find(|(kind_name, _)| *kind_name == name) {
+            Some((_, kind)) => Ok(*kind),
+            None => {
+                let names: Vec<_> = KIND_NAMES.iter().map(|(kind_name, _)| *kind_name).collect();
+                bail!("unknown emit kind {:?}, expected one of {}", name, names.join(", "))
+            }
+        }
+    }
+}
+
+/// Writes the artifact of the given kind for the AST
+pub fn emit<W: Write>(ast: &Ast, kind: EmitKind, writer: &mut W) -> Result<()> {
+    match kind {
+        EmitKind::AstJson => serde_json::to_writer(writer, ast)?,
+        EmitKind::AstBinary => {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            rmps::encode::write(&mut encoder, ast)?;
+            encoder.finish()?;
+        }
+        EmitKind::Listing => writer.write_all(ast.listing().as_bytes())?,
+        EmitKind::Dot => writer.write_all(CallGraph::new(ast).to_dot().as_bytes())?,
+        EmitKind::Depfile => writer.write_all(depfile(ast).as_bytes())?,
+        EmitKind::Lib => bail!("unable to emit a library, the entry module isn't a library"),
+    }
+
+    Ok(())
+}
+
+fn depfile(ast: &Ast) -> String {
+    let mut depfile = String::new();
+
+    for module in ast.modules.values() {
+        let path = match module.path {
+            Some(ref path) => path,
+            None => continue,
+        };
+
+        let dependencies: Vec<_> = module
+            .dependencies()
+            .iter()
+            .filter_map(|id| ast.modules.get(id)?.path.as_ref())
+            .map(|path| path.to_string_lossy().replace(' ', "\\ "))
+            .collect();
+
+        depfile.push_str(&format!(
+            "{}: {}\n",
+            path.to_string_lossy().replace(' ', "\\ "),
+            dependencies.join(" ")
+        ));
+    }
+
+    depfile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast_gen::AstGen;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use test_utils::*;
+
+    fn emitted(ast: &Ast, kind: EmitKind) -> Vec<u8> {
+        let mut out = Vec::new();
+        emit(ast, kind, &mut out).unwrap();
+
+        out
+    }
+
+    #[test]
+    fn every_kind_is_emitted() {
+        let ast = AstGen::gen("main".into(), fixture_config("emit")).unwrap();
+
+        let json = String::from_utf8(emitted(&ast, "ast-json".parse().unwrap())).unwrap();
+        assert!(json.starts_with(r#"{"modules":{"main":{"id":"main","imports":[{"#));
+        assert!(json.contains(
+            r#"{"func_origin_id":"$square","func_alias_id":"$square","module_id":"util"}"#
+        ));
+        assert!(json.contains(r#""constants":[{"id":"%SIDE","value":7}],"regions":[]"#));
+        assert!(json.contains(r#"{"expr":{"PushConstU8":{"Constant":"%SIDE"}},"line":6}"#));
+        assert!(json.contains(r#""is_inline":false,"is_pure":false"#));
+        assert!(json.contains(r#""const_exports":[],"static_asserts":[]"#));
+        assert!(json.contains(r#""path":"test/fixtures/emit/util.beast"}},"memory":{"#));
+
+        let binary = emitted(&ast, EmitKind::AstBinary);
+        let mut decoded = Vec::new();
+        GzDecoder::new(&binary[..]).read_to_end(&mut decoded).unwrap();
+        assert!(decoded.windows(7).any(|window| window == b"$square"));
+
+        let listing = String::from_utf8(emitted(&ast, EmitKind::Listing)).unwrap();
+        assert_eq!(listing, ast.listing());
+
+        let dot = String::from_utf8(emitted(&ast, EmitKind::Dot)).unwrap();
+        assert!(dot.starts_with("digraph calls {\n"));
+        assert!(dot.contains("    \"main:$main\" -> \"util:$square\";\n"));
+
+        let depfile = String::from_utf8(emitted(&ast, EmitKind::Depfile)).unwrap();
+        assert_eq!(
+            depfile,
+            "test/fixtures/emit/main.beast: test/fixtures/emit/util.beast\n\
+             test/fixtures/emit/util.beast: \n"
+        );
+
+        let mut out = Vec::new();
+        let err = emit(&ast, EmitKind::Lib, &mut out).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unable to emit a library, the entry module isn't a library"
+        );
+    }
+
+    #[test]
+    fn kinds_are_parsed_from_their_names() {
+        assert_eq!("dot".parse::<EmitKind>().unwrap(), EmitKind::Dot);
+        assert_eq!(
+            "svg".parse::<EmitKind>().unwrap_err().to_string(),
+            "unknown emit kind \"svg\", expected one of ast-json, ast-binary, listing, dot, \
+             depfile, lib"
+        );
+    }
+}
@@ -0,0 +1,37 @@
+#[macro_use]
+extern crate failure;
+extern crate melon;
+extern crate pest;
+#[macro_use]
+extern crate pest_derive;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate flate2;
+extern crate rmp_serde as rmps;
+extern crate toml;
+#[macro_use]
+extern crate structopt;
+
+mod alloc_balance;
+pub mod ast;
+pub mod ast_gen;
+pub mod bundle;
+pub mod call_graph;
+pub mod compiler;
+pub mod config;
+pub mod cst;
+pub mod defaults;
+pub mod diagnostic;
+pub mod emit;
+mod literal;
+mod loop_labels;
+mod lowering;
+mod parser;
+pub mod profile;
+pub mod source_provider;
+pub mod stack_effect;
+#[cfg(test)]
+mod test_utils;
+mod type_check;
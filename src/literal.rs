@@ -0,0 +1,215 @@
+use melon::typedef::*;
+
+/// Parses an integer literal into the widest integer type available.
+///
+/// Literals may carry a sign, a radix prefix (`0x`, `0b` or `0o`) and `_` separators between
+/// digits.
+pub fn parse_int(raw: &str) -> Result<i64> {
+    let (negative, unsigned) = if let Some(unsigned) = raw.strip_prefix('-') {
+        (true, unsigned)
+    } else {
+        (false, raw.strip_prefix('+').unwrap_or(raw))
+    };
+
+    let (radix, digits) = if let Some(digits) = unsigned.strip_prefix("0x") {
+        (16, digits)
+    } else if let Some(digits) = unsigned.strip_prefix("0b") {
+        (2, digits)
+    } else if let Some(digits) = unsigned.strip_prefix("0o") {
+        (8, digits)
+    } else {
+        (10, unsigned)
+    };
+
+    ensure!(!digits.is_empty(), "missing digits in literal {:?}", raw);
+    ensure!(
+        !digits.starts_with('_') && !digits.ends_with('_') && !digits.contains("__"),
+        "misplaced digit separator in literal {:?}, `_` may only separate two digits",
+        raw
+    );
+
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
+
+    let magnitude = i64::from_str_radix(&digits, radix)
+        .map_err(|e| format_err!("invalid literal {:?}: {}", raw, e))?;
+
+    Ok(if negative { -magnitude } else { magnitude })
+}
+
+/// Parses an integer literal that has to fit into an `i32`
+pub fn parse_i32(raw: &str) -> Result<i32> {
+    Ok(parse_ranged(raw, i32::MIN.into(), i32::MAX.into(), "i32")? as i32)
+}
+
+/// Parses an integer literal that has to fit into a `u8`
+pub fn parse_u8(raw: &str) -> Result<u8> {
+    Ok(parse_ranged(raw, u8::MIN.into(), u8::MAX.into(), "u8")? as u8)
+}
+
+/// Parses an integer literal that has to fit into a `u16`
+pub fn parse_u16(raw: &str) -> Result<u16> {
+    Ok(parse_ranged(raw, u16::MIN.into(), u16::MAX.into(), "u16")? as u16)
+}
+
+/// Parses an integer literal that has to fit into an `i8`. Unsigned literals with a radix prefix
+/// are the bit pattern of the value, e.g. `0xFF` is -1.
+pub fn parse_i8(raw: &str) -> Result<i8> {
+    if is_bit_pattern(raw) {
+        Ok(parse_ranged(raw, u8::MIN.into(), u8::MAX.into(), "i8")? as u8 as i8)
+    } else {
+        Ok(parse_ranged(raw, i8::MIN.into(), i8::MAX.into(), "i8")? as i8)
+    }
+}
+
+/// Parses an integer literal that has to fit into an `i16`. Unsigned literals with a radix prefix
+/// are the bit pattern of the value, e.g. `0xFFFF` is -1.
+pub fn parse_i16(raw: &str) -> Result<i16> {
+    if is_bit_pattern(raw) {
+        Ok(parse_ranged(raw, u16::MIN.into(), u16::MAX.into(), "i16")? as u16 as i16)
+    } else {
+        Ok(parse_ranged(raw, i16::MIN.into(), i16::MAX.into(), "i16")? as i16)
+    }
+}
+
+/// Returns whether the literal is written in binary, octal or hex without a sign
+fn is_bit_pattern(raw: &str) -> bool {
+    ["0x", "0b", "0o"].iter().any(|prefix| raw.starts_with(prefix))
+}
+
+fn parse_ranged(raw: &str, min: i64, max: i64, type_name: &str) -> Result<i64> {
+    let value = parse_int(raw)?;
+
+    ensure!(
+        value >= min && value <= max,
+        "literal {:?} does not fit into {}",
+        raw,
+        type_name
+    );
+
+    Ok(value)
+}
+
+/// Parses a quoted string literal, resolving the escape sequences `\"`, `\'`, `\\`, `\t`, `\n`,
+/// `\r`, `\u{...}` and `\` followed by two hex digits
+pub fn parse_string(raw: &str) -> Result<String> {
+    ensure!(
+        raw.len() >= 2 && raw.starts_with('"') && raw.ends_with('"'),
+        "unterminated string {}",
+        raw
+    );
+
+    let mut chars = raw[1..raw.len() - 1].chars();
+    let mut parsed = String::new();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            parsed.push(c);
+            continue;
+        }
+
+        let escaped = match chars.next() {
+            Some('"') => '"',
+            Some('\'') => '\'',
+            Some('\\') => '\\',
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('u') => {
+                let rest = chars.as_str();
+                let end = rest.find('}');
+                ensure!(
+                    rest.starts_with('{') && end.is_some(),
+                    "malformed unicode escape in string {}",
+                    raw
+                );
+                let end = end.unwrap();
+
+                let code = u32::from_str_radix(&rest[1..end], 16)?;
+                chars = rest[end + 1..].chars();
+
+                ::std::char::from_u32(code).ok_or_else(|| {
+                    format_err!("invalid unicode escape {:#x} in string {}", code, raw)
+                })?
+            }
+            Some(high) if high.is_ascii_hexdigit() => {
+                let low = chars
+                    .next()
+                    .filter(|low| low.is_ascii_hexdigit())
+                    .ok_or_else(|| format_err!("malformed hex escape in string {}", raw))?;
+
+                char::from(u8::from_str_radix(&format!("{}{}", high, low), 16)?)
+            }
+            Some(other) => bail!("unknown escape sequence \\{} in string {}", other, raw),
+            None => bail!("unterminated escape sequence in string {}", raw),
+        };
+
+        parsed.push(escaped);
+    }
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_radix_literals() {
+        assert_eq!(parse_int("42").unwrap(), 42);
+        assert_eq!(parse_int("-42").unwrap(), -42);
+        assert_eq!(parse_int("0xFF").unwrap(), 0xFF);
+        assert_eq!(parse_int("0b1111_0000").unwrap(), 0b1111_0000);
+        assert_eq!(parse_int("0o755").unwrap(), 0o755);
+        assert_eq!(parse_int("65_535").unwrap(), 65_535);
+
+        assert!(parse_int("0b102").is_err());
+        assert!(parse_int("_1").is_err());
+        assert!(parse_int("1_").is_err());
+        assert_eq!(
+            parse_int("0x_1").unwrap_err().to_string(),
+            "misplaced digit separator in literal \"0x_1\", `_` may only separate two digits"
+        );
+        assert!(parse_i32("0x1_0000_0000").is_err());
+        assert_eq!(parse_u16("0b1_0000_0000").unwrap(), 256);
+        assert!(parse_u16("-1").is_err());
+
+        assert_eq!(parse_u8("0b1010").unwrap(), 10);
+        assert_eq!(parse_i16("0o755").unwrap(), 0o755);
+        assert_eq!(
+            parse_u8("0b1_0000_0000").unwrap_err().to_string(),
+            "literal \"0b1_0000_0000\" does not fit into u8"
+        );
+        assert!(parse_i8("0o400").is_err());
+    }
+
+    #[test]
+    fn parse_signed_bit_patterns() {
+        assert_eq!(parse_i8("0xFF").unwrap(), -1);
+        assert_eq!(parse_i8("0x80").unwrap(), -128);
+        assert_eq!(parse_i8("0b0111_1111").unwrap(), 127);
+        assert_eq!(parse_i16("0xFFFF").unwrap(), -1);
+        assert_eq!(parse_i16("0o100000").unwrap(), -32768);
+        assert_eq!(parse_i8("-0x7F").unwrap(), -127);
+
+        assert!(parse_i8("0x100").is_err());
+        assert!(parse_i8("-0xFF").is_err());
+        assert_eq!(
+            parse_i8("200").unwrap_err().to_string(),
+            "literal \"200\" does not fit into i8"
+        );
+    }
+
+    #[test]
+    fn parse_escaped_strings() {
+        assert_eq!(parse_string(r#""my module""#).unwrap(), "my module");
+        assert_eq!(parse_string(r#""a\"b""#).unwrap(), "a\"b");
+        assert_eq!(parse_string(r#""a\\b""#).unwrap(), "a\\b");
+        assert_eq!(parse_string(r#""\t\u{e9}\41""#).unwrap(), "\té\x41");
+
+        assert!(parse_string(r#""open"#).is_err());
+        assert!(parse_string(r#""\q""#).is_err());
+        assert!(parse_string(r#""trailing\""#).is_err());
+        assert!(parse_string(r#""\u{110000}""#).is_err());
+        assert!(parse_string(r#""\4""#).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use ast::*;
+use melon::typedef::*;
+
+/// Checks that every `break` and `continue` of the given function is inside of a loop and that
+/// their labels refer to a loop around them. Labels may not shadow the label of a loop around
+/// them.
+pub fn check(func: &Func) -> Result<()> {
+    check_block(&func.expr, &mut Vec::new()).map_err(|err| format_err!("in {}: {}", func.id, err))
+}
+
+/// Checks the expressions inside of the loops with the given labels, the innermost one last
+fn check_block<'a>(exprs: &'a [Located], loops: &mut Vec<&'a Option<String>>) -> Result<()> {
+    for Located { expr, .. } in exprs {
+        match expr {
+            Expr::Break(label) => check_jump("break", label, loops)?,
+            Expr::Continue(label) => check_jump("continue", label, loops)?,
+            Expr::While(whl) => {
+                if let Some(ref label) = whl.label {
+                    ensure!(
+                        !loops.contains(&&whl.label),
+                        "the label {} is already used by a loop around it",
+                        label
+                    );
+                }
+
+                loops.push(&whl.label);
+                check_block(&whl.exprs, loops)?;
+                loops.pop();
+            }
+            Expr::If(whether) => {
+                check_block(&whether.exprs, loops)?;
+
+                if let Some(ref else_exprs) = whether.else_exprs {
+                    check_block(else_exprs, loops)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn check_jump(keyword: &str, label: &Option<String>, loops: &[&Option<String>]) -> Result<()> {
+    match label {
+        Some(label) => ensure!(
+            loops.iter().any(|&loop_label| loop_label.as_ref() == Some(label)),
+            "{} {} doesn't refer to a loop around it",
+            keyword,
+            label
+        ),
+        None => ensure!(!loops.is_empty(), "{} outside of a loop", keyword),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,885 @@
+//! Instruction sequences for operations the VM doesn't provide natively.
+//!
+//! melon's arithmetic is checked, so every sequence is built to never overflow regardless of
+//! its input.
+
+use ast::{BitCount, Saturating};
+use melon::{typedef::*, Instruction, IntegerType, Register};
+use stack_effect::type_size;
+
+/// Returns the unsigned type with the same width as the given type
+fn unsigned(type_t: &IntegerType) -> IntegerType {
+    match type_t {
+        IntegerType::U8 | IntegerType::I8 => IntegerType::U8,
+        IntegerType::U16 | IntegerType::I16 => IntegerType::U16,
+    }
+}
+
+/// Pushes a constant of the unsigned type with the same width as the given type
+fn push_unsigned(type_t: &IntegerType, value: u16) -> Instruction {
+    match unsigned(type_t) {
+        IntegerType::U8 => Instruction::PushConstU8(value as u8),
+        _ => Instruction::PushConstU16(value),
+    }
+}
+
+/// Replaces the top value with itself combined with itself shifted by `amount`
+fn fold_shifted(type_t: &IntegerType, shift: Instruction, amount: u8) -> Vec<Instruction> {
+    let t = || unsigned(type_t);
+
+    vec![
+        Instruction::Dup(t()),
+        push_unsigned(type_t, amount as u16),
+        shift,
+        Instruction::Or(t()),
+    ]
+}
+
+/// Pops a value of the given type and pushes the number of set bits as `u8`
+fn popcount(type_t: &IntegerType) -> Vec<Instruction> {
+    let t = || unsigned(type_t);
+    let (m1, m2, m4) = match t() {
+        IntegerType::U8 => (0x55, 0x33, 0x0F),
+        _ => (0x5555, 0x3333, 0x0F0F),
+    };
+
+    let mut instrs = vec![
+        // Count the bits of each pair: x - ((x >> 1) & 0x55)
+        Instruction::Dup(t()),
+        push_unsigned(type_t, 1),
+        Instruction::Shr(t()),
+        push_unsigned(type_t, m1),
+        Instruction::And(t()),
+        Instruction::Sub(t()),
+        // Sum neighbouring pairs: x - 3 * ((x >> 2) & 0x33)
+        Instruction::Dup(t()),
+        push_unsigned(type_t, 2),
+        Instruction::Shr(t()),
+        push_unsigned(type_t, m2),
+        Instruction::And(t()),
+        push_unsigned(type_t, 3),
+        Instruction::Mul(t()),
+        Instruction::Sub(t()),
+        // Sum neighbouring nibbles: (x + (x >> 4)) & 0x0F
+        Instruction::Dup(t()),
+        push_unsigned(type_t, 4),
+        Instruction::Shr(t()),
+        Instruction::Add(t()),
+        push_unsigned(type_t, m4),
+        Instruction::And(t()),
+    ];
+
+    if let IntegerType::U16 = t() {
+        // Add the high byte's count to the low byte's and drop the former
+        instrs.append(&mut vec![
+            Instruction::Dup(t()),
+            push_unsigned(type_t, 8),
+            Instruction::Shr(t()),
+            Instruction::Add(t()),
+            Instruction::U16Demote,
+        ]);
+    }
+
+    instrs
+}
+
+/// Pops a `u8` count of set bits and pushes the number of remaining bits of the given type
+fn remaining_bits(type_t: &IntegerType) -> Vec<Instruction> {
+    // width - count == (0xFF - count) - (0xFF - width), which can't underflow
+    vec![
+        Instruction::Not(IntegerType::U8),
+        Instruction::PushConstU8(0xFF - type_size(type_t) as u8 * 8),
+        Instruction::Sub(IntegerType::U8),
+    ]
+}
+
+/// Pops a value of the given type and pushes the number of bits counted as `u8`.
+///
+/// Leading and trailing zeros of `0` are counted as the bit width of the type.
+pub fn count_bits(count: &BitCount, type_t: &IntegerType) -> Vec<Instruction> {
+    let t = unsigned(type_t);
+    let shift = match count {
+        BitCount::Ones => return popcount(type_t),
+        BitCount::LeadingZeros => Instruction::Shr(t),
+        BitCount::TrailingZeros => Instruction::Shl(t),
+    };
+
+    // Set every bit below the highest (or above the lowest) set bit, so the zeros to count are
+    // the only ones left
+    let mut instrs = Vec::new();
+    let mut amount = 1;
+    while amount < type_size(type_t) as u8 * 8 {
+        instrs.append(&mut fold_shifted(type_t, shift.clone(), amount));
+        amount *= 2;
+    }
+
+    instrs.append(&mut popcount(type_t));
+    instrs.append(&mut remaining_bits(type_t));
+
+    instrs
+}
+
+/// Pushes a copy of the value of the given type that lies below the top `above` bytes
+fn copy_below(above: u16, type_t: &IntegerType) -> Vec<Instruction> {
+    // The stack pointer points at the top value, so the value below starts `above` bytes after it
+    vec![
+        Instruction::LoadReg(Register::StackPtr),
+        Instruction::PushConstU16(above),
+        Instruction::Add(IntegerType::U16),
+        Instruction::LoadIndirect(type_t.clone()),
+    ]
+}
+
+/// Removes the value of type `below` that lies under the top value of type `top`
+fn nip(top: &IntegerType, below: &IntegerType) -> Vec<Instruction> {
+    let mut instrs = vec![
+        // The top value ends up where the value below currently ends
+        Instruction::LoadReg(Register::StackPtr),
+        Instruction::PushConstU16(type_size(below)),
+        Instruction::Add(IntegerType::U16),
+    ];
+    instrs.append(&mut copy_below(2, top));
+    instrs.push(Instruction::StoreIndirect(top.clone()));
+    instrs.push(Instruction::Drop(below.clone()));
+
+    instrs
+}
+
+/// Pops a value and an address, stores the value at the address and pushes the value again
+pub fn tee_indirect(type_t: &IntegerType) -> Vec<Instruction> {
+    let mut instrs = copy_below(type_size(type_t), &IntegerType::U16);
+    instrs.append(&mut copy_below(2, type_t));
+    instrs.push(Instruction::StoreIndirect(type_t.clone()));
+
+    // Replace the original address and value by the stored value
+    instrs.push(Instruction::Drop(type_t.clone()));
+    instrs.push(Instruction::LoadIndirect(type_t.clone()));
+
+    instrs
+}
+
+/// Returns the type with twice the width of the given type, if there is one
+pub fn wider(type_t: &IntegerType) -> Option<IntegerType> {
+    match type_t {
+        IntegerType::U8 => Some(IntegerType::U16),
+        IntegerType::I8 => Some(IntegerType::I16),
+        IntegerType::U16 | IntegerType::I16 => None,
+    }
+}
+
+/// Pops two values of the given type and pushes their product with twice the width, so it can't
+/// overflow
+pub fn wide_mul(type_t: &IntegerType) -> Result<Vec<Instruction>> {
+    let wide = wider(type_t)
+        .ok_or_else(|| format_err!("there is no integer type wider than {:?}", type_t))?;
+
+    let promote = match type_t {
+        IntegerType::I8 => Instruction::I8Promote,
+        _ => Instruction::U8Promote,
+    };
+
+    let mut instrs = vec![promote.clone()];
+    instrs.append(&mut copy_below(type_size(&wide), type_t));
+    instrs.push(promote);
+    instrs.push(Instruction::Mul(wide.clone()));
+    instrs.append(&mut nip(&wide, type_t));
+
+    Ok(instrs)
+}
+
+/// Runs `then` if the jump, following a `Cmp`, is taken and `otherwise` if it isn't
+fn branch(
+    jump: fn(bool, u16) -> Instruction,
+    mut then: Vec<Instruction>,
+    mut otherwise: Vec<Instruction>,
+) -> Vec<Instruction> {
+    let mut instrs = vec![jump(true, otherwise.len() as u16 + 2)];
+    instrs.append(&mut otherwise);
+    instrs.push(Instruction::Jmp(true, then.len() as u16 + 1));
+    instrs.append(&mut then);
+
+    instrs
+}
+
+/// Drops the given number of values of the given type
+fn drops(type_t: &IntegerType, count: usize) -> Vec<Instruction> {
+    vec![Instruction::Drop(type_t.clone()); count]
+}
+
+/// Compares the top value with the given value and runs `then` if the jump is taken and
+/// `otherwise` if it isn't, dropping the compared value in both cases
+fn compare_top(
+    type_t: &IntegerType,
+    value: i32,
+    jump: fn(bool, u16) -> Instruction,
+    mut then: Vec<Instruction>,
+    mut otherwise: Vec<Instruction>,
+) -> Vec<Instruction> {
+    then.insert(0, Instruction::Drop(type_t.clone()));
+    otherwise.insert(0, Instruction::Drop(type_t.clone()));
+
+    let mut instrs = vec![push(type_t, value), Instruction::Cmp(type_t.clone())];
+    instrs.append(&mut branch(jump, then, otherwise));
+
+    instrs
+}
+
+/// Guards the operation on the two values `a` and `b` on top of the stack by computing the limit
+/// `base` combined with `b`, which mustn't overflow for the values of `b` reaching the guard. If
+/// comparing the limit with `a` takes the jump, both values are replaced by `saturated`.
+fn limit_guard(
+    type_t: &IntegerType,
+    base: i32,
+    combine: Instruction,
+    jump: fn(bool, u16) -> Instruction,
+    saturated: i32,
+    mut operation: Vec<Instruction>,
+) -> Vec<Instruction> {
+    let mut instrs = vec![push(type_t, base)];
+    instrs.append(&mut copy_below(2, type_t));
+    instrs.push(combine);
+    instrs.append(&mut copy_below(4, type_t));
+    instrs.push(Instruction::Cmp(type_t.clone()));
+
+    let mut then = drops(type_t, 4);
+    then.push(push(type_t, saturated));
+
+    let mut otherwise = drops(type_t, 2);
+    otherwise.append(&mut operation);
+
+    instrs.append(&mut branch(jump, then, otherwise));
+
+    instrs
+}
+
+/// Pops two values of a 16 bit type and pushes the result of the operation clamped to the bounds
+/// of the type
+fn saturating_wide(op: &Saturating, type_t: &IntegerType) -> Vec<Instruction> {
+    let t = || type_t.clone();
+    let (min, max) = bounds(type_t);
+    let signed = min < 0;
+
+    match op {
+        Saturating::Add if !signed => limit_guard(
+            type_t,
+            max,
+            Instruction::Sub(t()),
+            Instruction::Jlt,
+            max,
+            vec![Instruction::Add(t())],
+        ),
+        Saturating::Sub if !signed => {
+            let mut instrs = vec![Instruction::Cmp(t())];
+            instrs.append(&mut branch(
+                Instruction::Jlt,
+                vec![Instruction::Drop(t()), Instruction::Drop(t()), push(type_t, 0)],
+                vec![Instruction::Sub(t())],
+            ));
+
+            instrs
+        }
+        Saturating::Mul if !signed => {
+            let mut zero = drops(type_t, 2);
+            zero.push(push(type_t, 0));
+
+            let guarded = limit_guard(
+                type_t,
+                max,
+                Instruction::Div(t()),
+                Instruction::Jlt,
+                max,
+                vec![Instruction::Mul(t())],
+            );
+
+            compare_top(type_t, 0, Instruction::Jeq, zero, guarded)
+        }
+        // Depending on the sign of `b`, the result can only overflow in one direction
+        Saturating::Add => compare_top(
+            type_t,
+            0,
+            Instruction::JgtEq,
+            limit_guard(
+                type_t,
+                max,
+                Instruction::Sub(t()),
+                Instruction::Jlt,
+                max,
+                vec![Instruction::Add(t())],
+            ),
+            limit_guard(
+                type_t,
+                min,
+                Instruction::Sub(t()),
+                Instruction::Jgt,
+                min,
+                vec![Instruction::Add(t())],
+            ),
+        ),
+        Saturating::Sub => compare_top(
+            type_t,
+            0,
+            Instruction::JgtEq,
+            limit_guard(
+                type_t,
+                min,
+                Instruction::Add(t()),
+                Instruction::Jgt,
+                min,
+                vec![Instruction::Sub(t())],
+            ),
+            limit_guard(
+                type_t,
+                max,
+                Instruction::Add(t()),
+                Instruction::Jlt,
+                max,
+                vec![Instruction::Sub(t())],
+            ),
+        ),
+        Saturating::Mul => {
+            let mut zero = drops(type_t, 2);
+            zero.push(push(type_t, 0));
+
+            // Dividing the minimum by -1 overflows, so `b` being -1 negates `a` instead
+            let mut minus_one = vec![Instruction::Drop(t())];
+            minus_one.append(&mut compare_top(
+                type_t,
+                min,
+                Instruction::Jeq,
+                vec![Instruction::Drop(t()), push(type_t, max)],
+                vec![Instruction::Neg(t())],
+            ));
+
+            let positive = limit_guard(
+                type_t,
+                max,
+                Instruction::Div(t()),
+                Instruction::Jlt,
+                max,
+                limit_guard(
+                    type_t,
+                    min,
+                    Instruction::Div(t()),
+                    Instruction::Jgt,
+                    min,
+                    vec![Instruction::Mul(t())],
+                ),
+            );
+            let negative = limit_guard(
+                type_t,
+                min,
+                Instruction::Div(t()),
+                Instruction::Jlt,
+                min,
+                limit_guard(
+                    type_t,
+                    max,
+                    Instruction::Div(t()),
+                    Instruction::Jgt,
+                    max,
+                    vec![Instruction::Mul(t())],
+                ),
+            );
+
+            compare_top(
+                type_t,
+                0,
+                Instruction::Jeq,
+                zero,
+                compare_top(
+                    type_t,
+                    -1,
+                    Instruction::Jeq,
+                    minus_one,
+                    compare_top(type_t, 0, Instruction::Jgt, positive, negative),
+                ),
+            )
+        }
+    }
+}
+
+/// Pops two values and pushes the result of the operation clamped to the bounds of their type
+pub fn saturating(op: &Saturating, type_t: &IntegerType) -> Result<Vec<Instruction>> {
+    if wider(type_t).is_none() {
+        return Ok(saturating_wide(op, type_t));
+    }
+
+    // 8 bit operations can't overflow once widened, but unsigned subtractions can go below zero
+    let (promote, wide_op_type) = match (type_t, op) {
+        (IntegerType::I8, _) => (Instruction::I8Promote, IntegerType::I16),
+        (_, Saturating::Sub) => (Instruction::U8Promote, IntegerType::I16),
+        _ => (Instruction::U8Promote, IntegerType::U16),
+    };
+    let demote = match type_t {
+        IntegerType::I8 => Instruction::I16Demote,
+        _ => Instruction::U16Demote,
+    };
+    let (min, max) = bounds(type_t);
+
+    let mut instrs = copy_below(1, type_t);
+    instrs.push(promote.clone());
+    instrs.append(&mut copy_below(2, type_t));
+    instrs.push(promote);
+    instrs.push(match op {
+        Saturating::Add => Instruction::Add(wide_op_type.clone()),
+        Saturating::Sub => Instruction::Sub(wide_op_type.clone()),
+        Saturating::Mul => Instruction::Mul(wide_op_type.clone()),
+    });
+    instrs.append(&mut clamp(&wide_op_type, min, max)?);
+    instrs.push(demote);
+
+    // Remove the original operands below the result
+    instrs.append(&mut nip(type_t, type_t));
+    instrs.append(&mut nip(type_t, type_t));
+
+    Ok(instrs)
+}
+
+/// Repeats the given body, which mustn't change the stack, for every byte of the `u16` length on
+/// top of the stack, from the last byte to the first. The length is decremented before the body
+/// runs, so it is the offset of the current byte. Drops the length and the given operands below
+/// it afterwards.
+fn bytewise(mut body: Vec<Instruction>, operands: &[IntegerType]) -> Vec<Instruction> {
+    body.insert(0, Instruction::Drop(IntegerType::U16));
+    body.insert(1, Instruction::Dec(IntegerType::U16));
+    body.push(Instruction::PushConstU16(0));
+
+    let len = body.len() as u16;
+
+    let mut instrs = vec![
+        Instruction::PushConstU16(0),
+        Instruction::Cmp(IntegerType::U16),
+        Instruction::Jeq(true, len + 2),
+    ];
+    instrs.append(&mut body);
+    instrs.push(Instruction::Jmp(false, len + 2));
+
+    // The zero compared with and the length
+    instrs.push(Instruction::Drop(IntegerType::U16));
+    instrs.push(Instruction::Drop(IntegerType::U16));
+    for operand in operands.iter().rev() {
+        instrs.push(Instruction::Drop(operand.clone()));
+    }
+
+    instrs
+}
+
+/// Pops the `u16` length, the `u16` destination and the `u16` source address and copies the bytes
+/// from the source to the destination
+pub fn mem_copy() -> Vec<Instruction> {
+    // The destination of the current byte
+    let mut body = copy_below(2, &IntegerType::U16);
+    body.append(&mut copy_below(2, &IntegerType::U16));
+    body.push(Instruction::Add(IntegerType::U16));
+
+    // The source of the current byte
+    body.append(&mut copy_below(6, &IntegerType::U16));
+    body.append(&mut copy_below(4, &IntegerType::U16));
+    body.push(Instruction::Add(IntegerType::U16));
+
+    body.push(Instruction::LoadIndirect(IntegerType::U8));
+    body.push(Instruction::StoreIndirect(IntegerType::U8));
+
+    bytewise(body, &[IntegerType::U16, IntegerType::U16])
+}
+
+/// Pops the `u16` length, the `u8` value and the `u16` destination address and sets the bytes at
+/// the destination to the value
+pub fn mem_set() -> Vec<Instruction> {
+    // The destination of the current byte
+    let mut body = copy_below(3, &IntegerType::U16);
+    body.append(&mut copy_below(2, &IntegerType::U16));
+    body.push(Instruction::Add(IntegerType::U16));
+
+    body.append(&mut copy_below(4, &IntegerType::U8));
+    body.push(Instruction::StoreIndirect(IntegerType::U8));
+
+    bytewise(body, &[IntegerType::U16, IntegerType::U8])
+}
+
+/// Sets the `u16` iteration counter of a capped loop at the given address to zero
+pub fn loop_counter_start(counter: Address) -> Vec<Instruction> {
+    vec![
+        Instruction::PushConstU16(0),
+        Instruction::Store(IntegerType::U16, counter),
+    ]
+}
+
+/// Counts an iteration at the start of the body of a capped loop and leaves the loop once `max`
+/// iterations are done. The rest of the body and the jump back to the condition have to follow
+/// the `body_len` instructions after it.
+pub fn loop_counter_check(counter: Address, max: u16, body_len: u16) -> Vec<Instruction> {
+    let mut increment = vec![
+        Instruction::Drop(IntegerType::U16),
+        Instruction::Drop(IntegerType::U16),
+        Instruction::Load(IntegerType::U16, counter),
+        Instruction::Inc(IntegerType::U16),
+        Instruction::Store(IntegerType::U16, counter),
+        Instruction::Jmp(true, 4),
+    ];
+
+    let mut instrs = vec![Instruction::Load(IntegerType::U16, counter)];
+    instrs.push(Instruction::PushConstU16(max));
+    instrs.push(Instruction::Cmp(IntegerType::U16));
+    instrs.push(Instruction::JgtEq(true, increment.len() as u16 + 1));
+    instrs.append(&mut increment);
+
+    // Leaves the loop like a condition that doesn't hold anymore
+    instrs.push(Instruction::Drop(IntegerType::U16));
+    instrs.push(Instruction::Drop(IntegerType::U16));
+    instrs.push(Instruction::Jmp(true, body_len + 2));
+
+    instrs
+}
+
+/// Pushes a constant of the given type
+fn push(type_t: &IntegerType, value: i32) -> Instruction {
+    match type_t {
+        IntegerType::U8 => Instruction::PushConstU8(value as u8),
+        IntegerType::U16 => Instruction::PushConstU16(value as u16),
+        IntegerType::I8 => Instruction::PushConstI8(value as i8),
+        IntegerType::I16 => Instruction::PushConstI16(value as i16),
+    }
+}
+
+/// Returns the smallest and the largest value of the given type
+pub fn bounds(type_t: &IntegerType) -> (i32, i32) {
+    match type_t {
+        IntegerType::U8 => (0, i32::from(u8::MAX)),
+        IntegerType::U16 => (0, i32::from(u16::MAX)),
+        IntegerType::I8 => (i32::from(i8::MIN), i32::from(i8::MAX)),
+        IntegerType::I16 => (i32::from(i16::MIN), i32::from(i16::MAX)),
+    }
+}
+
+/// Pops a value of the given type and pushes it constrained to the inclusive range `min..=max`
+pub fn clamp(type_t: &IntegerType, min: i32, max: i32) -> Result<Vec<Instruction>> {
+    let (lowest, highest) = bounds(type_t);
+    for bound in &[min, max] {
+        ensure!(
+            lowest <= *bound && *bound <= highest,
+            "the clamp bound {} doesn't fit into {:?}",
+            bound,
+            type_t
+        );
+    }
+    ensure!(
+        min <= max,
+        "the clamp minimum {} is greater than the maximum {}",
+        min,
+        max
+    );
+
+    let t = || type_t.clone();
+    let mut instrs = Vec::new();
+
+    // Cmp keeps both values, so the bound is dropped when the value is kept and both are replaced
+    // by the bound otherwise
+    for (bound, keep) in [
+        (min, Instruction::JgtEq(true, 5)),
+        (max, Instruction::JltEq(true, 5)),
+    ] {
+        instrs.append(&mut vec![
+            push(type_t, bound),
+            Instruction::Cmp(t()),
+            keep,
+            Instruction::Drop(t()),
+            Instruction::Drop(t()),
+            push(type_t, bound),
+            Instruction::Jmp(true, 2),
+            Instruction::Drop(t()),
+        ]);
+    }
+
+    Ok(instrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::*;
+
+    /// Runs the lowered count on the given value and returns the result
+    fn run_count(count: &BitCount, push: Instruction, type_t: IntegerType) -> u8 {
+        let mut instructions = vec![push];
+        instructions.append(&mut count_bits(count, &type_t));
+        instructions.push(Instruction::SysCall(REPORT_SIGNAL));
+        instructions.push(Instruction::SysCall(0));
+
+        let reported = run_instructions(instructions).unwrap();
+        assert_eq!(reported.len(), 1);
+
+        reported[0]
+    }
+
+    #[test]
+    fn count_bits_8_bit() {
+        for value in 0..=u8::MAX {
+            for (count, expected) in &[
+                (BitCount::Ones, value.count_ones()),
+                (BitCount::LeadingZeros, value.leading_zeros()),
+                (BitCount::TrailingZeros, value.trailing_zeros()),
+            ] {
+                let unsigned = run_count(count, Instruction::PushConstU8(value), IntegerType::U8);
+                let signed = run_count(
+                    count,
+                    Instruction::PushConstI8(value as i8),
+                    IntegerType::I8,
+                );
+
+                assert_eq!(unsigned as u32, *expected, "{:?} of {:#x}", count, value);
+                assert_eq!(signed as u32, *expected, "{:?} of {:#x}", count, value);
+            }
+        }
+    }
+
+    #[test]
+    fn count_bits_16_bit() {
+        let values = (0..16)
+            .flat_map(|bit| vec![1u16 << bit, (1u16 << bit) - 1, !(1u16 << bit), 0x8000 >> bit])
+            .chain(vec![0, 0xFFFF, 0x1234, 0xA5A5, 0x0F00, 0x00F0, 0x8001]);
+
+        for value in values {
+            for (count, expected) in &[
+                (BitCount::Ones, value.count_ones()),
+                (BitCount::LeadingZeros, value.leading_zeros()),
+                (BitCount::TrailingZeros, value.trailing_zeros()),
+            ] {
+                let unsigned =
+                    run_count(count, Instruction::PushConstU16(value), IntegerType::U16);
+                let signed = run_count(
+                    count,
+                    Instruction::PushConstI16(value as i16),
+                    IntegerType::I16,
+                );
+
+                assert_eq!(unsigned as u32, *expected, "{:?} of {:#x}", count, value);
+                assert_eq!(signed as u32, *expected, "{:?} of {:#x}", count, value);
+            }
+        }
+    }
+
+    #[test]
+    fn tee_indirect_keeps_the_value() {
+        let mut instructions = vec![
+            Instruction::PushConstU16(10),
+            Instruction::PushConstU8(42),
+        ];
+        instructions.append(&mut tee_indirect(&IntegerType::U8));
+        instructions.append(&mut vec![
+            Instruction::SysCall(REPORT_SIGNAL),
+            Instruction::Load(IntegerType::U8, 10),
+            Instruction::SysCall(REPORT_SIGNAL),
+            Instruction::PushConstU16(20),
+            Instruction::PushConstI16(-300),
+        ]);
+        instructions.append(&mut tee_indirect(&IntegerType::I16));
+        instructions.append(&mut vec![
+            Instruction::PushConstI16(-300),
+            Instruction::Sub(IntegerType::I16),
+            Instruction::I16Demote,
+            Instruction::SysCall(REPORT_SIGNAL),
+            Instruction::Load(IntegerType::I16, 20),
+            Instruction::PushConstI16(-300),
+            Instruction::Sub(IntegerType::I16),
+            Instruction::I16Demote,
+            Instruction::SysCall(REPORT_SIGNAL),
+            Instruction::SysCall(0),
+        ]);
+
+        assert_eq!(run_instructions(instructions).unwrap(), vec![42, 42, 0, 0]);
+    }
+
+    #[test]
+    fn wide_mul_keeps_the_high_half() {
+        let values: Vec<u8> = vec![0, 1, 2, 3, 16, 100, 127, 128, 200, 255];
+
+        for &a in &values {
+            for &b in &values {
+                for type_t in &[IntegerType::U8, IntegerType::I8] {
+                    let (mut instructions, expected) = match type_t {
+                        IntegerType::U8 => (
+                            vec![Instruction::PushConstU8(a), Instruction::PushConstU8(b)],
+                            a as u16 * b as u16,
+                        ),
+                        _ => (
+                            vec![
+                                Instruction::PushConstI8(a as i8),
+                                Instruction::PushConstI8(b as i8),
+                            ],
+                            (a as i8 as i16 * b as i8 as i16) as u16,
+                        ),
+                    };
+                    instructions.append(&mut wide_mul(type_t).unwrap());
+                    instructions.append(&mut vec![
+                        Instruction::Dup(IntegerType::U16),
+                        Instruction::PushConstU16(8),
+                        Instruction::Shr(IntegerType::U16),
+                        Instruction::U16Demote,
+                        Instruction::SysCall(REPORT_SIGNAL),
+                        Instruction::U16Demote,
+                        Instruction::SysCall(REPORT_SIGNAL),
+                        Instruction::SysCall(0),
+                    ]);
+
+                    let reported = run_instructions(instructions).unwrap();
+                    let expected = vec![(expected >> 8) as u8, expected as u8];
+                    assert_eq!(reported, expected, "{:?} {} * {}", type_t, a, b);
+                }
+            }
+        }
+
+        assert!(wide_mul(&IntegerType::U16).is_err());
+        assert!(wide_mul(&IntegerType::I16).is_err());
+    }
+
+    #[test]
+    fn clamp_respects_signedness() {
+        let cases = vec![
+            (IntegerType::U8, 0, 100, vec![0, 42, 100, 101, 255]),
+            (IntegerType::I8, -10, 10, vec![-128, -11, -10, 0, 10, 11, 127]),
+            (IntegerType::U16, 0x10, 0x40, vec![0, 0x10, 0x20, 0x41, 0xFFFF]),
+            (IntegerType::I16, -300, 100, vec![-32768, -301, -300, 100, 101, 32767]),
+        ];
+
+        for (type_t, min, max, values) in cases {
+            for value in values {
+                let mut instructions = vec![push(&type_t, value)];
+                instructions.append(&mut clamp(&type_t, min, max).unwrap());
+                if type_size(&type_t) == 2 {
+                    instructions.push(Instruction::U16Demote);
+                }
+                instructions.push(Instruction::SysCall(REPORT_SIGNAL));
+                instructions.push(Instruction::SysCall(0));
+
+                let reported = run_instructions(instructions).unwrap();
+                let expected = value.max(min).min(max);
+
+                assert_eq!(reported, vec![expected as u8], "{:?} of {}", type_t, value);
+            }
+        }
+    }
+
+    #[test]
+    fn saturating_arithmetic_clamps_to_the_type() {
+        let types = vec![
+            (IntegerType::U8, vec![0, 1, 2, 15, 16, 100, 200, 254, 255]),
+            (IntegerType::I8, vec![-128, -127, -12, -2, -1, 0, 1, 2, 11, 64, 126, 127]),
+            (IntegerType::U16, vec![0, 1, 2, 255, 256, 300, 1000, 0x8000, 0xFFFE, 0xFFFF]),
+            (
+                IntegerType::I16,
+                vec![-32768, -32767, -300, -181, -2, -1, 0, 1, 2, 181, 300, 32766, 32767],
+            ),
+        ];
+        let ops = [Saturating::Add, Saturating::Sub, Saturating::Mul];
+
+        for (type_t, values) in types {
+            let (min, max) = bounds(&type_t);
+
+            for op in &ops {
+                for &a in &values {
+                    for &b in &values {
+                        // Reports the stack pointer before and after to check the operands are
+                        // removed, and every byte of the result, the most significant first
+                        let mut instructions = vec![
+                            Instruction::LoadReg(Register::StackPtr),
+                            Instruction::U16Demote,
+                            Instruction::SysCall(REPORT_SIGNAL),
+                            push(&type_t, a),
+                            push(&type_t, b),
+                        ];
+                        instructions.append(&mut saturating(op, &type_t).unwrap());
+                        for _ in 0..type_size(&type_t) {
+                            instructions.push(Instruction::SysCall(REPORT_SIGNAL));
+                        }
+                        instructions.push(Instruction::LoadReg(Register::StackPtr));
+                        instructions.push(Instruction::U16Demote);
+                        instructions.push(Instruction::SysCall(REPORT_SIGNAL));
+                        instructions.push(Instruction::SysCall(0));
+
+                        let reported = run_instructions(instructions).unwrap();
+                        let (a, b) = (i64::from(a), i64::from(b));
+                        let exact = match op {
+                            Saturating::Add => a + b,
+                            Saturating::Sub => a - b,
+                            Saturating::Mul => a * b,
+                        };
+                        let expected = exact
+                            .max(i64::from(min))
+                            .min(i64::from(max));
+
+                        let bytes = &reported[1..reported.len() - 1];
+                        let result = bytes
+                            .iter()
+                            .fold(0, |result, byte| result << 8 | i64::from(*byte));
+                        let mask = (1 << (8 * bytes.len())) - 1;
+
+                        let case = format!("{:?} {:?} {} {}", op, type_t, a, b);
+                        assert_eq!(result, expected & mask, "{}", case);
+                        assert_eq!(reported[0], reported[reported.len() - 1], "{}", case);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clamp_rejects_invalid_bounds() {
+        assert!(clamp(&IntegerType::U8, 10, 5).is_err());
+        assert!(clamp(&IntegerType::U8, 0, 256).is_err());
+        assert!(clamp(&IntegerType::I8, -129, 0).is_err());
+    }
+
+    #[test]
+    fn mem_copy_copies_every_byte() {
+        // Reports the stack pointer before and after copying to check every operand is dropped
+        let mut instructions = vec![
+            Instruction::LoadReg(Register::StackPtr),
+            Instruction::U16Demote,
+            Instruction::SysCall(REPORT_SIGNAL),
+        ];
+        for (offset, value) in (10..14).enumerate() {
+            instructions.push(Instruction::PushConstU8(value));
+            instructions.push(Instruction::Store(IntegerType::U8, 100 + offset as u16));
+        }
+
+        instructions.append(&mut vec![
+            Instruction::PushConstU16(100),
+            Instruction::PushConstU16(200),
+            Instruction::PushConstU16(3),
+        ]);
+        instructions.append(&mut mem_copy());
+
+        for address in 200..204 {
+            instructions.push(Instruction::Load(IntegerType::U8, address));
+            instructions.push(Instruction::SysCall(REPORT_SIGNAL));
+        }
+        instructions.push(Instruction::LoadReg(Register::StackPtr));
+        instructions.push(Instruction::U16Demote);
+        instructions.push(Instruction::SysCall(REPORT_SIGNAL));
+        instructions.push(Instruction::SysCall(0));
+
+        let reported = run_instructions(instructions).unwrap();
+
+        assert_eq!(&reported[1..5], &[10, 11, 12, 0]);
+        assert_eq!(reported[0], reported[5]);
+    }
+
+    #[test]
+    fn mem_set_sets_every_byte() {
+        let mut instructions = vec![
+            Instruction::PushConstU16(100),
+            Instruction::PushConstU8(7),
+            Instruction::PushConstU16(3),
+        ];
+        instructions.append(&mut mem_set());
+
+        for address in 99..104 {
+            instructions.push(Instruction::Load(IntegerType::U8, address));
+            instructions.push(Instruction::SysCall(REPORT_SIGNAL));
+        }
+        instructions.push(Instruction::SysCall(0));
+
+        assert_eq!(run_instructions(instructions).unwrap(), vec![0, 7, 7, 7, 0]);
+    }
+}
@@ -1,27 +1,12 @@
+extern crate beast;
 #[macro_use]
 extern crate failure;
 extern crate melon;
-extern crate pest;
-#[macro_use]
-extern crate pest_derive;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-extern crate flate2;
-extern crate rmp_serde as rmps;
-extern crate toml;
-#[macro_use]
 extern crate structopt;
 
-mod ast;
-mod ast_gen;
-mod compiler;
-mod config;
-mod defaults;
-mod parser;
-
-use compiler::Compiler;
-use config::Config;
+use beast::{compiler::{Compiler, Emit},
+            config::Config,
+            defaults};
 use melon::typedef::Result;
 use std::{fs::{self, File},
           io::Write,
@@ -41,13 +26,10 @@ enum Opt {
     },
     #[structopt(name = "build", about = "builds the current project")]
     Build {
-        #[structopt(
-            long = "emit-func-map",
-            help = "emits the corresponding function-map for the current build"
-        )]
-        emit_func_map: bool,
-        #[structopt(long = "emit-ast", help = "emits the corresponding AST for the current build")]
-        emit_ast: bool,
+        #[structopt(long = "target", help = "the target to build for as named in the config")]
+        target: Option<String>,
+        #[structopt(flatten)]
+        emit: Emit,
     },
 }
 
@@ -65,26 +47,27 @@ fn run() -> Result<()> {
     let opt = Opt::from_args();
 
     match opt {
-        Opt::Build {
-            emit_func_map,
-            emit_ast,
-        } => build(emit_func_map, emit_ast)?,
+        Opt::Build { target, emit } => build(target, emit)?,
         Opt::New { path } => new(&path)?,
     }
 
     Ok(())
 }
 
-fn build(emit_func_map: bool, emit_ast: bool) -> Result<()> {
+fn build(target: Option<String>, emit: Emit) -> Result<()> {
     let config_file = PathBuf::from(CONFIG_FILE_NAME);
 
     ensure!(
         config_file.exists(),
-        "unable to find {} in current directory",
+        "unable to find {} in the current directory\nrun `beast new <path>` to create a new project",
         CONFIG_FILE_NAME
     );
 
-    let config = Config::from_file(config_file)?;
+    let mut config = Config::from_file(config_file)?;
+
+    if let Some(target) = target {
+        config = config.with_target(&target)?;
+    }
 
     let compilation = config.compilation.clone();
 
@@ -96,7 +79,7 @@ fn build(emit_func_map: bool, emit_ast: bool) -> Result<()> {
 
     let now = Instant::now();
 
-    let program = Compiler::compile(entry_point, config, emit_func_map, emit_ast)?;
+    let program = Compiler::compile(entry_point, config, emit)?;
 
     println!(
         "Compilation finished. Took {} seconds",
@@ -129,7 +112,18 @@ system_id = "__DEBUG__"
 # [signals]
 # gurgle = 1
 # nuke = 2
-# fire = 3"#,
+# fire = 3
+# write = {{ id = 4, pops = 2, pushes = 0 }} # declares the bytes taken off and put onto the stack
+# io = {{ read = 5 }} # a group of signals, called like `(sys :io.read)`
+
+# [program.memory] # the stack takes the end of the memory, the heap the part below it
+# stack_size = 1024
+# heap_start = 0
+
+# [target.small] # selected with `beast build --target small`
+# mem_pages = 4
+# [target.small.signals]
+# gurgle = 7"#,
         melon::VERSION
     );
 
@@ -137,7 +131,7 @@ system_id = "__DEBUG__"
     config_file.write_all(&config_data.into_bytes())?;
 
     let main_file_data = include_bytes!("templates/main.bst");
-    let mut main_file_file = File::create(path.join(defaults::INCLUDE_PATH).join("main.bst"))?;
+    let mut main_file_file = File::create(path.join(defaults::INCLUDE_PATH).join("main.beast"))?;
     main_file_file.write_all(&main_file_data[..])?;
 
     let gitignore_data = include_bytes!("templates/.gitignore");
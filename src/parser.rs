@@ -15,4 +15,11 @@ mod tests {
 
         BeastParser::parse(Rule::file, input).unwrap();
     }
+
+    #[test]
+    fn import_export_whitespace() {
+        let input = "(  import\n\t$sqrt   as\n  $root\r\n from   math.sqrt  )\n(export\n  $root\n)";
+
+        BeastParser::parse(Rule::file, input).unwrap();
+    }
 }
@@ -0,0 +1,71 @@
+use std::{collections::BTreeMap,
+          sync::{Arc, Mutex},
+          time::{Duration, Instant}};
+
+/// The time spent in the phases of generating an AST
+#[derive(Debug, Clone, Default)]
+pub struct CompileReport {
+    /// The wall time of the whole generation
+    pub total: Duration,
+    /// The time spent finding module files, summed over all modules
+    pub discovery: Duration,
+    /// The time spent parsing modules, summed over all modules
+    pub parse: Duration,
+    /// The time spent scheduling the imports of parsed modules
+    pub resolution: Duration,
+    /// The parse time of every module
+    pub modules: BTreeMap<String, Duration>,
+}
+
+/// Accumulates timings from all workers into a `CompileReport`
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    report: Arc<Mutex<CompileReport>>,
+}
+
+impl Profiler {
+    /// Returns the report with the wall time since the given start as its total
+    pub fn finish(&self, start: Instant) -> CompileReport {
+        let mut report = self.report.lock().unwrap().clone();
+        report.total = start.elapsed();
+
+        report
+    }
+}
+
+/// Measures a single phase. Does nothing if profiling is disabled.
+pub struct Timer {
+    started: Option<(Profiler, Instant)>,
+}
+
+impl Timer {
+    pub fn start(profiler: &Option<Profiler>) -> Timer {
+        Timer {
+            started: profiler
+                .as_ref()
+                .map(|profiler| (profiler.clone(), Instant::now())),
+        }
+    }
+
+    pub fn discovery(self) {
+        if let Some((profiler, start)) = self.started {
+            profiler.report.lock().unwrap().discovery += start.elapsed();
+        }
+    }
+
+    pub fn parse(self, module_id: &str) {
+        if let Some((profiler, start)) = self.started {
+            let duration = start.elapsed();
+            let mut report = profiler.report.lock().unwrap();
+
+            report.parse += duration;
+            report.modules.insert(module_id.into(), duration);
+        }
+    }
+
+    pub fn resolution(self) {
+        if let Some((profiler, start)) = self.started {
+            profiler.report.lock().unwrap().resolution += start.elapsed();
+        }
+    }
+}
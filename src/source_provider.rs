@@ -0,0 +1,24 @@
+//! Sources of modules that aren't read from the search paths, e.g. the buffers of an editor or
+//! generated code.
+
+use melon::typedef::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Provides the source of modules by their id, e.g. `math.float`. Modules it doesn't know are
+/// looked up in the search paths as usual.
+pub trait SourceProvider: Send + Sync {
+    /// Returns the source of the module or `None` if the provider doesn't know it
+    fn load(&self, module: &str) -> Result<Option<String>>;
+}
+
+impl SourceProvider for HashMap<String, String> {
+    fn load(&self, module: &str) -> Result<Option<String>> {
+        Ok(self.get(module).cloned())
+    }
+}
+
+impl SourceProvider for BTreeMap<String, String> {
+    fn load(&self, module: &str) -> Result<Option<String>> {
+        Ok(self.get(module).cloned())
+    }
+}
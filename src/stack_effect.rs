@@ -0,0 +1,176 @@
+use ast::*;
+use config::Signal;
+use melon::{typedef::*, Instruction, IntegerType};
+use std::collections::BTreeMap;
+
+/// The number of bytes an instruction takes off the stack and puts back onto it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    pub pops: u16,
+    pub pushes: u16,
+}
+
+impl StackEffect {
+    fn new(pops: u16, pushes: u16) -> StackEffect {
+        StackEffect { pops, pushes }
+    }
+}
+
+pub fn type_size(type_t: &IntegerType) -> u16 {
+    match type_t {
+        IntegerType::U8 | IntegerType::I8 => 1,
+        IntegerType::U16 | IntegerType::I16 => 2,
+    }
+}
+
+/// Returns the stack effect of the given instruction or `None` if it can't be determined
+/// statically
+pub fn of_instr(instr: &Instruction) -> Option<StackEffect> {
+    let effect = match instr {
+        Instruction::Add(t)
+        | Instruction::Sub(t)
+        | Instruction::Mul(t)
+        | Instruction::Div(t)
+        | Instruction::Shr(t)
+        | Instruction::Shl(t)
+        | Instruction::And(t)
+        | Instruction::Or(t)
+        | Instruction::Xor(t) => StackEffect::new(type_size(t) * 2, type_size(t)),
+        Instruction::Not(t) | Instruction::Neg(t) | Instruction::Inc(t) | Instruction::Dec(t) => {
+            StackEffect::new(type_size(t), type_size(t))
+        }
+        Instruction::Cmp(t) => StackEffect::new(type_size(t) * 2, type_size(t) * 2),
+        Instruction::U8Promote | Instruction::I8Promote => StackEffect::new(1, 2),
+        Instruction::U16Demote | Instruction::I16Demote => StackEffect::new(2, 1),
+        Instruction::PushConstU8(_) | Instruction::PushConstI8(_) => StackEffect::new(0, 1),
+        Instruction::PushConstU16(_) | Instruction::PushConstI16(_) => StackEffect::new(0, 2),
+        Instruction::LoadReg(_) => StackEffect::new(0, 2),
+        Instruction::Load(t, _) => StackEffect::new(0, type_size(t)),
+        Instruction::LoadIndirect(t) => StackEffect::new(2, type_size(t)),
+        Instruction::Store(t, _) => StackEffect::new(type_size(t), 0),
+        Instruction::StoreIndirect(t) => StackEffect::new(type_size(t) + 2, 0),
+        Instruction::Dup(t) => StackEffect::new(type_size(t), type_size(t) * 2),
+        Instruction::Drop(t) => StackEffect::new(type_size(t), 0),
+        Instruction::Alloc(_) | Instruction::Free => StackEffect::new(0, 0),
+        _ => return None,
+    };
+
+    Some(effect)
+}
+
+/// Returns the bytes of the stack a condition compares and how many of them it keeps
+fn compared(type_t: &IntegerType, immediate: &Option<Argument<i32>>) -> (u16, u16) {
+    match immediate {
+        Some(_) => (type_size(type_t), 0),
+        None => (type_size(type_t) * 2, type_size(type_t) * 2),
+    }
+}
+
+/// Checks that the entry function never takes more values off the stack than were put onto it.
+///
+/// The analysis stops at the first expression whose effect is unknown, e.g. a call or a system
+/// call without a declared stack effect.
+pub fn check_entry(func: &Func, signals: &BTreeMap<String, Signal>) -> Result<()> {
+    // The parameters are on the stack when the program starts
+    let depth = func.params.iter().map(|param| type_size(&param.type_t)).sum();
+
+    Analysis { func, signals }.exprs(&func.expr, depth)?;
+
+    Ok(())
+}
+
+struct Analysis<'a> {
+    func: &'a Func,
+    signals: &'a BTreeMap<String, Signal>,
+}
+
+impl<'a> Analysis<'a> {
+    /// Returns the stack depth after the given expressions or `None` if it is unknown
+    fn exprs(&self, exprs: &[Located], mut depth: u16) -> Result<Option<u16>> {
+        for Located { expr, .. } in exprs {
+            depth = match self.expr(expr, depth)? {
+                Some(depth) => depth,
+                None => return Ok(None),
+            };
+        }
+
+        Ok(Some(depth))
+    }
+
+    fn expr(&self, expr: &Expr, depth: u16) -> Result<Option<u16>> {
+        let effect = match expr {
+            Expr::ActualInstr(instr) => of_instr(instr),
+            Expr::PushConstU8(_) | Expr::PushConstI8(_) => Some(StackEffect::new(0, 1)),
+            Expr::PushConstU16(_) | Expr::PushConstI16(_) => Some(StackEffect::new(0, 2)),
+            Expr::Load(t, _) => Some(StackEffect::new(0, type_size(t))),
+            Expr::Store(t, _) => Some(StackEffect::new(type_size(t), 0)),
+            Expr::Tee(t, _) => Some(StackEffect::new(type_size(t), type_size(t))),
+            Expr::TeeIndirect(t) => Some(StackEffect::new(type_size(t) + 2, type_size(t))),
+            Expr::Alloc(..) | Expr::Free(_) | Expr::Trap => Some(StackEffect::new(0, 0)),
+            Expr::Assert(_, t) => Some(StackEffect::new(type_size(t) * 2, type_size(t) * 2)),
+            Expr::CountBits(_, t) => Some(StackEffect::new(type_size(t), 1)),
+            Expr::Saturating(_, t) => Some(StackEffect::new(type_size(t) * 2, type_size(t))),
+            Expr::Untyped(_) => None,
+            Expr::WideMul(t) => Some(StackEffect::new(type_size(t) * 2, type_size(t) * 2)),
+            Expr::MemCopy => Some(StackEffect::new(6, 0)),
+            Expr::MemSet => Some(StackEffect::new(5, 0)),
+            Expr::Clamp(t, ..) => Some(StackEffect::new(type_size(t), type_size(t))),
+            Expr::Sys(signal) => self.signals
+                .get(&signal[1..])
+                .and_then(|signal| signal.stack_effect()),
+            Expr::Call(_) | Expr::Break(_) | Expr::Continue(_) => None,
+            Expr::While(whl) => {
+                let (compared, kept) = compared(&whl.type_t, &whl.immediate);
+                self.ensure_depth(expr, compared, depth)?;
+
+                // Only loops that put the compared values back can be followed
+                let body_depth = self.exprs(&whl.exprs, depth - compared + kept)?;
+
+                return Ok(if body_depth == Some(depth) {
+                    Some(depth - compared + kept)
+                } else {
+                    None
+                });
+            }
+            Expr::If(whether) => {
+                let (compared, kept) = compared(&whether.type_t, &whether.immediate);
+                self.ensure_depth(expr, compared, depth)?;
+
+                let branch_depth = depth - compared + kept;
+                let if_depth = self.exprs(&whether.exprs, branch_depth)?;
+                let else_depth = match whether.else_exprs {
+                    Some(ref else_exprs) => self.exprs(else_exprs, branch_depth)?,
+                    None => Some(branch_depth),
+                };
+
+                return Ok(if if_depth == else_depth {
+                    if_depth
+                } else {
+                    None
+                });
+            }
+        };
+
+        let effect = match effect {
+            Some(effect) => effect,
+            None => return Ok(None),
+        };
+
+        self.ensure_depth(expr, effect.pops, depth)?;
+
+        Ok(Some(depth - effect.pops + effect.pushes))
+    }
+
+    fn ensure_depth(&self, expr: &Expr, needed: u16, depth: u16) -> Result<()> {
+        ensure!(
+            needed <= depth,
+            "stack underflow in {:?}: {:?} takes {} bytes off the stack but only {} are available",
+            self.func.id,
+            expr,
+            needed,
+            depth
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use config::Config;
+use melon::{typedef::*, Instruction, Program, System, VM};
+
+/// The signal used by test programs to report the `u8` on top of the stack
+pub const REPORT_SIGNAL: u16 = 1;
+
+/// A system collecting every value reported by the program
+#[derive(Default)]
+pub struct TestSystem {
+    pub reported: Vec<u8>,
+}
+
+impl System for TestSystem {
+    const ID: &'static str = "__TEST_SYSTEM__";
+
+    const MEM_PAGES: u8 = 1;
+
+    fn system_call(&mut self, vm: &mut VM, signal: u16) -> Result<()> {
+        if signal == REPORT_SIGNAL {
+            let value = vm.pop_u8()?;
+            self.reported.push(value);
+        }
+
+        Ok(())
+    }
+}
+
+/// Loads the test configuration without any include path of its own
+pub fn test_config() -> Config {
+    let mut config = Config::from_file("test/Beast.toml").unwrap();
+    config.program.target_version = ::melon::VERSION.into();
+
+    config
+}
+
+/// Loads the test configuration with the given fixture directory as its include path
+pub fn fixture_config(name: &str) -> Config {
+    let mut config = test_config();
+    config.compilation.include_dirs = vec![format!("test/fixtures/{}", name)];
+
+    config
+}
+
+/// Runs the given program and returns the values it reported
+pub fn run(program: &Program) -> Result<Vec<u8>> {
+    let mut system = TestSystem::default();
+    VM::default().exec(program, &mut system)?;
+
+    Ok(system.reported)
+}
+
+/// Runs the given instructions as a program and returns the values it reported
+pub fn run_instructions(instructions: Vec<Instruction>) -> Result<Vec<u8>> {
+    let program = Program {
+        target_version: ::melon::VERSION.into(),
+        system_id: TestSystem::ID.into(),
+        instructions,
+        mem_pages: None,
+        entry_point: 0,
+    };
+
+    run(&program)
+}
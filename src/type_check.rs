@@ -0,0 +1,311 @@
+use ast::*;
+use failure::Error;
+use lowering;
+use melon::{Instruction, IntegerType};
+
+fn type_named(name: &str) -> IntegerType {
+    match name {
+        "u8" => IntegerType::U8,
+        "u16" => IntegerType::U16,
+        "i8" => IntegerType::I8,
+        _ => IntegerType::I16,
+    }
+}
+
+fn type_name(type_t: &IntegerType) -> &'static str {
+    match type_t {
+        IntegerType::U8 => "u8",
+        IntegerType::U16 => "u16",
+        IntegerType::I8 => "i8",
+        IntegerType::I16 => "i16",
+    }
+}
+
+/// The statically known types of the values on the stack. Values put onto the stack before the
+/// tracked code have an unknown type, unless the second field is set because the tracked values
+/// are all there is.
+#[derive(Clone, PartialEq)]
+struct TypeStack(Vec<&'static str>, bool);
+
+impl TypeStack {
+    fn push(&mut self, type_t: &IntegerType) {
+        self.0.push(type_name(type_t));
+    }
+
+    fn pop(&mut self) -> Option<&'static str> {
+        self.0.pop()
+    }
+
+    /// Replaces the top `pops` values by `pushes`
+    fn apply(&mut self, pops: usize, pushes: &[&IntegerType]) {
+        for _ in 0..pops {
+            self.pop();
+        }
+
+        for type_t in pushes {
+            self.push(type_t);
+        }
+    }
+}
+
+/// Warns about conditions whose declared type differs from the type of the values they compare,
+/// e.g. comparing two `u8` values as `i16`, and about indirect memory accesses without a `u16`
+/// address. Expressions are skipped if the types of their values can't be determined statically.
+///
+/// The stack of the entry function holds nothing but its parameters, so accesses without any
+/// address on its stack are reported as well.
+pub fn check(func: &Func, is_entry: bool) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    let mut stack = TypeStack(Vec::new(), is_entry);
+    for param in &func.params {
+        stack.push(&param.type_t);
+    }
+
+    check_block(&func.expr, stack, &mut warnings);
+
+    warnings
+        .into_iter()
+        .map(|warning| format!("in {}: {}", func.id, warning))
+        .collect()
+}
+
+/// Replaces the untyped arithmetic of the function by the instruction for the type of its
+/// operands. Both operands have to be known to have the same type.
+pub fn infer(func: &mut Func) -> Result<(), Error> {
+    let mut stack = TypeStack(Vec::new(), false);
+    for param in &func.params {
+        stack.push(&param.type_t);
+    }
+
+    infer_block(&mut func.expr, Some(stack))
+        .map_err(|err| format_err!("in {}: {}", func.id, err))?;
+
+    Ok(())
+}
+
+/// Infers the types in the given expressions and returns the types after them, or `None` if they
+/// can't be determined
+fn infer_block(
+    exprs: &mut [Located],
+    mut stack: Option<TypeStack>,
+) -> Result<Option<TypeStack>, Error> {
+    for Located { expr, .. } in exprs.iter_mut() {
+        match expr {
+            Expr::Untyped(op) => {
+                let operands = stack
+                    .as_ref()
+                    .filter(|stack| stack.0.len() >= 2)
+                    .map(|stack| &stack.0[stack.0.len() - 2..]);
+
+                let type_t = match operands {
+                    Some(&[below, top]) if below == top => type_named(top),
+                    Some(&[below, top]) => bail!(
+                        "cannot infer type for {}, the operands are {} and {}",
+                        op.name(),
+                        below,
+                        top
+                    ),
+                    _ => bail!(
+                        "cannot infer type for {}, the types of the operands are unknown",
+                        op.name()
+                    ),
+                };
+
+                *expr = Expr::ActualInstr(op.instruction(type_t));
+            }
+            Expr::If(whether) => {
+                let mut branch_stack = stack.clone();
+                if whether.immediate.is_some() {
+                    if let Some(ref mut branch_stack) = branch_stack {
+                        branch_stack.pop();
+                    }
+                }
+
+                infer_block(&mut whether.exprs, branch_stack.clone())?;
+                if let Some(ref mut else_exprs) = whether.else_exprs {
+                    infer_block(else_exprs, branch_stack)?;
+                }
+            }
+            Expr::While(whl) => {
+                let mut body_stack = stack.clone();
+                if whl.immediate.is_some() {
+                    if let Some(ref mut body_stack) = body_stack {
+                        body_stack.pop();
+                    }
+                }
+
+                infer_block(&mut whl.exprs, body_stack)?;
+            }
+            _ => {}
+        }
+
+        stack = stack.and_then(|stack| check_expr(expr, stack, &mut Vec::new()));
+    }
+
+    Ok(stack)
+}
+
+/// Returns the types after the given expressions or `None` if they can't be determined
+fn check_block(
+    exprs: &[Located],
+    mut stack: TypeStack,
+    warnings: &mut Vec<String>,
+) -> Option<TypeStack> {
+    for Located { expr, .. } in exprs {
+        stack = check_expr(expr, stack, warnings)?;
+    }
+
+    Some(stack)
+}
+
+fn check_condition(
+    type_t: &IntegerType,
+    compared: usize,
+    stack: &TypeStack,
+    warnings: &mut Vec<String>,
+) {
+    let len = stack.0.len();
+    if len < compared {
+        return;
+    }
+
+    let declared = type_name(type_t);
+
+    for actual in &stack.0[len - compared..] {
+        if *actual != declared {
+            warnings.push(format!(
+                "condition compares the values as {} but a {} value is on the stack",
+                declared, actual
+            ));
+            return;
+        }
+    }
+}
+
+/// Checks the address of an indirect memory access, which is below the given number of values
+fn check_address(access: &str, above: usize, stack: &TypeStack, warnings: &mut Vec<String>) {
+    let len = stack.0.len();
+
+    if len > above {
+        let address = stack.0[len - above - 1];
+        if address == "u8" || address == "i8" {
+            warnings.push(format!(
+                "{} expects a u16 address but finds a {} value",
+                access, address
+            ));
+        }
+    } else if stack.1 {
+        warnings.push(format!(
+            "{} expects a u16 address but there is none on the stack",
+            access
+        ));
+    }
+}
+
+fn check_expr(expr: &Expr, mut stack: TypeStack, warnings: &mut Vec<String>) -> Option<TypeStack> {
+    match expr {
+        Expr::PushConstU8(_) => stack.push(&IntegerType::U8),
+        Expr::PushConstU16(_) => stack.push(&IntegerType::U16),
+        Expr::PushConstI8(_) => stack.push(&IntegerType::I8),
+        Expr::PushConstI16(_) => stack.push(&IntegerType::I16),
+        Expr::Load(t, _) => stack.push(t),
+        Expr::Store(..) => stack.apply(1, &[]),
+        Expr::Tee(..) | Expr::Alloc(..) | Expr::Free(_) | Expr::Trap => {}
+        Expr::TeeIndirect(t) => {
+            check_address("indirect tee", 1, &stack, warnings);
+            stack.apply(2, &[t])
+        }
+        Expr::CountBits(..) => stack.apply(1, &[&IntegerType::U8]),
+        Expr::Saturating(_, t) => stack.apply(2, &[t]),
+        Expr::WideMul(t) => stack.apply(2, &[&lowering::wider(t)?]),
+        Expr::MemCopy | Expr::MemSet => stack.apply(3, &[]),
+        Expr::Clamp(t, ..) => stack.apply(1, &[t]),
+        Expr::Assert(_, t) => check_condition(t, 2, &stack, warnings),
+        Expr::ActualInstr(instr) => {
+            match instr {
+                Instruction::LoadIndirect(_) => check_address("indirect load", 0, &stack, warnings),
+                Instruction::StoreIndirect(_) => {
+                    check_address("indirect store", 1, &stack, warnings)
+                }
+                _ => {}
+            }
+
+            apply_instr(instr, &mut stack)?
+        }
+        Expr::If(whether) => {
+            let compared = if whether.immediate.is_some() { 1 } else { 2 };
+            check_condition(&whether.type_t, compared, &stack, warnings);
+
+            // Immediates take the compared value off the stack
+            if whether.immediate.is_some() {
+                stack.pop();
+            }
+
+            let if_stack = check_block(&whether.exprs, stack.clone(), warnings);
+            let else_stack = match whether.else_exprs {
+                Some(ref else_exprs) => check_block(else_exprs, stack, warnings),
+                None => Some(stack),
+            };
+
+            // Branches that leave different types behind make the types unknown
+            return if if_stack == else_stack {
+                if_stack
+            } else {
+                None
+            };
+        }
+        Expr::While(whl) => {
+            let compared = if whl.immediate.is_some() { 1 } else { 2 };
+            check_condition(&whl.type_t, compared, &stack, warnings);
+
+            let mut body_start = stack.clone();
+            if whl.immediate.is_some() {
+                body_start.pop();
+            }
+
+            let body_stack = check_block(&whl.exprs, body_start.clone(), warnings);
+
+            return if body_stack.as_ref() == Some(&stack) {
+                Some(body_start)
+            } else {
+                None
+            };
+        }
+        Expr::Sys(_) | Expr::Call(_) | Expr::Untyped(_) => return None,
+        // The code after them is only reached by jumps
+        Expr::Break(_) | Expr::Continue(_) => return None,
+    }
+
+    Some(stack)
+}
+
+fn apply_instr(instr: &Instruction, stack: &mut TypeStack) -> Option<()> {
+    match instr {
+        Instruction::Add(t)
+        | Instruction::Sub(t)
+        | Instruction::Mul(t)
+        | Instruction::Div(t)
+        | Instruction::Shr(t)
+        | Instruction::Shl(t)
+        | Instruction::And(t)
+        | Instruction::Or(t)
+        | Instruction::Xor(t) => stack.apply(2, &[t]),
+        Instruction::Not(t) | Instruction::Neg(t) | Instruction::Inc(t) | Instruction::Dec(t) => {
+            stack.apply(1, &[t])
+        }
+        Instruction::Cmp(_) | Instruction::Alloc(_) | Instruction::Free => {}
+        Instruction::U8Promote => stack.apply(1, &[&IntegerType::U16]),
+        Instruction::U16Demote => stack.apply(1, &[&IntegerType::U8]),
+        Instruction::I8Promote => stack.apply(1, &[&IntegerType::I16]),
+        Instruction::I16Demote => stack.apply(1, &[&IntegerType::I8]),
+        Instruction::LoadReg(_) => stack.push(&IntegerType::U16),
+        Instruction::LoadIndirect(t) => stack.apply(1, &[t]),
+        Instruction::StoreIndirect(_) => stack.apply(2, &[]),
+        Instruction::Dup(t) => stack.apply(1, &[t, t]),
+        Instruction::Drop(_) => stack.apply(1, &[]),
+        _ => return None,
+    }
+
+    Some(())
+}